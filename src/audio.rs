@@ -1,5 +1,49 @@
 pub trait AudioInterface {
     fn set_clock_rate(&mut self, emulated_clock_rate: u64);
     fn needs_sample(&self, current_cycle: u64) -> bool;
-    fn add_sample(&mut self, value: f32);
+    /// The first cycle at which `needs_sample` will return true
+    fn next_sample_cycle(&self) -> u64;
+    /// Takes the sample due at `current_cycle`
+    fn add_sample(&mut self, value: f32, current_cycle: u64);
+    /// Restarts sampling from `current_cycle`, such as after loading a save state
+    fn reset_clock(&mut self, current_cycle: u64);
+}
+
+/// Works out which emulated clock cycles fall on samples at a given sample rate
+pub struct SampleClock {
+    sample_rate: u32,
+    clock_of_last_sample: f64,
+    clocks_between_samples: f64,
+}
+
+impl SampleClock {
+    pub fn new(sample_rate: u32) -> Self {
+        Self {
+            sample_rate,
+            clock_of_last_sample: 0.0,
+            clocks_between_samples: 0.0,
+        }
+    }
+
+    pub fn set_clock_rate(&mut self, emulated_clock_rate: u64) {
+        self.clocks_between_samples = emulated_clock_rate as f64 / self.sample_rate as f64;
+    }
+
+    pub fn needs_sample(&self, current_cycle: u64) -> bool {
+        let next_sample_cycle = self.clock_of_last_sample + self.clocks_between_samples;
+        next_sample_cycle <= current_cycle as f64
+    }
+
+    pub fn next_sample_cycle(&self) -> u64 {
+        (self.clock_of_last_sample + self.clocks_between_samples).ceil() as u64
+    }
+
+    /// Moves on to the next sample
+    pub fn advance(&mut self) {
+        self.clock_of_last_sample += self.clocks_between_samples;
+    }
+
+    pub fn reset(&mut self, current_cycle: u64) {
+        self.clock_of_last_sample = current_cycle as f64;
+    }
 }
@@ -1,5 +1,46 @@
+use std::fmt::Display;
+
 pub trait GpioInterface {
-    fn get_updates(&self) -> Option<GpioButtonState>;
+    /// Returns the new button state, if it changed. `current_cycle` is the CPU cycle
+    /// count at which the state will be applied.
+    fn get_updates(&self, current_cycle: u64) -> Option<GpioButtonState>;
+
+    /// The first cycle after `current_cycle` at which the external clock pin,
+    /// PB7, rises, or `None` if it will not
+    fn next_external_clock_edge(&self, _current_cycle: u64) -> Option<u64> {
+        None
+    }
+
+    /// The level of the external clock pin at `current_cycle`. Nothing drives
+    /// it by default, so it is pulled high.
+    fn external_clock_level(&self, _current_cycle: u64) -> bool {
+        true
+    }
+
+    /// Called whenever an output pin changes level, such as when a timer toggles it
+    fn output_changed(&self, _pin: GpioPin, _high: bool, _current_cycle: u64) {}
+}
+
+/// A port whose pins can be driven by the microcontroller
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub enum GpioPort {
+    C,
+    D,
+    E,
+    F,
+}
+
+/// One pin of a port, such as PC4
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub struct GpioPin {
+    pub port: GpioPort,
+    pub bit: u8,
+}
+
+impl Display for GpioPin {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "P{:?}{}", self.port, self.bit)
+    }
 }
 
 #[derive(PartialEq, Copy, Clone, Debug)]
@@ -20,6 +61,49 @@ pub enum GpioButton {
     Mute,
 }
 
+impl GpioButton {
+    pub const ALL: [GpioButton; 14] = [
+        GpioButton::Up,
+        GpioButton::Down,
+        GpioButton::Left,
+        GpioButton::Right,
+        GpioButton::Power,
+        GpioButton::Menu,
+        GpioButton::UpsideUp,
+        GpioButton::UpsideDown,
+        GpioButton::ScreenTopLeft,
+        GpioButton::ScreenTopRight,
+        GpioButton::ScreenBottomLeft,
+        GpioButton::ScreenBottomRight,
+        GpioButton::Action,
+        GpioButton::Mute,
+    ];
+
+    /// Name used for the button in text files such as input scripts
+    pub fn name(&self) -> &'static str {
+        match self {
+            GpioButton::Up => "up",
+            GpioButton::Down => "down",
+            GpioButton::Left => "left",
+            GpioButton::Right => "right",
+            GpioButton::Power => "power",
+            GpioButton::Menu => "menu",
+            GpioButton::UpsideUp => "upside_up",
+            GpioButton::UpsideDown => "upside_down",
+            GpioButton::ScreenTopLeft => "screen_top_left",
+            GpioButton::ScreenTopRight => "screen_top_right",
+            GpioButton::ScreenBottomLeft => "screen_bottom_left",
+            GpioButton::ScreenBottomRight => "screen_bottom_right",
+            GpioButton::Action => "action",
+            GpioButton::Mute => "mute",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|button| button.name() == name)
+    }
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub struct GpioButtonState {
     pub up: bool,
@@ -99,3 +183,39 @@ impl Default for GpioButtonState {
         }
     }
 }
+
+/// Drives the external clock pin with a square wave, which rises every
+/// `period` cycles and is high for the first half of each period. Everything
+/// else is passed through to `io`.
+pub struct ExternalClock {
+    io: Box<dyn GpioInterface>,
+    period: u64,
+}
+
+impl ExternalClock {
+    pub fn new(io: Box<dyn GpioInterface>, period: u64) -> Self {
+        assert!(
+            period >= 2,
+            "The external clock must be high and low for a cycle each"
+        );
+        Self { io, period }
+    }
+}
+
+impl GpioInterface for ExternalClock {
+    fn get_updates(&self, current_cycle: u64) -> Option<GpioButtonState> {
+        self.io.get_updates(current_cycle)
+    }
+
+    fn next_external_clock_edge(&self, current_cycle: u64) -> Option<u64> {
+        Some((current_cycle / self.period + 1) * self.period)
+    }
+
+    fn external_clock_level(&self, current_cycle: u64) -> bool {
+        current_cycle % self.period < self.period / 2
+    }
+
+    fn output_changed(&self, pin: GpioPin, high: bool, current_cycle: u64) {
+        self.io.output_changed(pin, high, current_cycle);
+    }
+}
@@ -0,0 +1,238 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::fmt::Display;
+use std::io::Write;
+
+use crate::gpio::{GpioButton, GpioButtonState, GpioInterface, GpioPin};
+
+/// Button states to apply at exact CPU cycle counts.
+///
+/// Each line of a script is a cycle count followed by the buttons held from
+/// that cycle on, such as `8000000 action up`. A cycle count on its own
+/// releases every button. Blank lines and lines starting with `#` are ignored.
+#[derive(Default)]
+pub struct InputScript {
+    events: VecDeque<(u64, GpioButtonState)>,
+}
+
+impl InputScript {
+    pub fn parse(text: &str) -> Result<Self, InputScriptError> {
+        let mut events = VecDeque::<(u64, GpioButtonState)>::new();
+
+        for (index, line) in text.lines().enumerate() {
+            let line_number = index + 1;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let mut words = line.split_whitespace();
+            let cycle = words
+                .next()
+                .and_then(|word| word.parse::<u64>().ok())
+                .ok_or(InputScriptError::InvalidCycle(line_number))?;
+
+            if events.back().is_some_and(|(last, _)| *last > cycle) {
+                return Err(InputScriptError::OutOfOrder(line_number));
+            }
+
+            let mut state = GpioButtonState::default();
+            for word in words {
+                let button = GpioButton::from_name(word)
+                    .ok_or_else(|| InputScriptError::UnknownButton(line_number, word.to_owned()))?;
+                state.set(button, true);
+            }
+
+            events.push_back((cycle, state));
+        }
+
+        Ok(Self { events })
+    }
+
+    /// The cycle at which the next button state is due, if there is one
+    pub fn next_cycle(&self) -> Option<u64> {
+        self.events.front().map(|(cycle, _)| *cycle)
+    }
+
+    /// Removes and returns the next button state if it is due by `current_cycle`
+    pub fn take_due(&mut self, current_cycle: u64) -> Option<GpioButtonState> {
+        if self.next_cycle()? > current_cycle {
+            return None;
+        }
+        self.events.pop_front().map(|(_, state)| state)
+    }
+}
+
+/// Formats a line of an input script which holds `state` from `cycle` on
+fn format_line(cycle: u64, state: &GpioButtonState) -> String {
+    let mut line = cycle.to_string();
+    for button in GpioButton::ALL {
+        if state.get(button) {
+            line.push(' ');
+            line.push_str(button.name());
+        }
+    }
+    line
+}
+
+/// Plays back an input script, in place of any other input
+pub struct ScriptedGpio {
+    script: RefCell<InputScript>,
+}
+
+impl ScriptedGpio {
+    pub fn new(script: InputScript) -> Self {
+        Self {
+            script: RefCell::new(script),
+        }
+    }
+}
+
+impl GpioInterface for ScriptedGpio {
+    fn get_updates(&self, current_cycle: u64) -> Option<GpioButtonState> {
+        self.script.borrow_mut().take_due(current_cycle)
+    }
+}
+
+/// Passes input through while writing every change to an input script
+pub struct InputRecorder<W: Write> {
+    io: Box<dyn GpioInterface>,
+    output: RefCell<W>,
+}
+
+impl<W: Write> InputRecorder<W> {
+    pub fn new(io: Box<dyn GpioInterface>, mut output: W) -> std::io::Result<Self> {
+        writeln!(output, "# emiu2 input recording")?;
+        Ok(Self {
+            io,
+            output: RefCell::new(output),
+        })
+    }
+}
+
+impl<W: Write> GpioInterface for InputRecorder<W> {
+    fn get_updates(&self, current_cycle: u64) -> Option<GpioButtonState> {
+        let state = self.io.get_updates(current_cycle)?;
+
+        let line = format_line(current_cycle, &state);
+        if let Err(why) = writeln!(self.output.borrow_mut(), "{line}") {
+            eprintln!("Failed to record input: {why}");
+        }
+
+        Some(state)
+    }
+
+    fn next_external_clock_edge(&self, current_cycle: u64) -> Option<u64> {
+        self.io.next_external_clock_edge(current_cycle)
+    }
+
+    fn external_clock_level(&self, current_cycle: u64) -> bool {
+        self.io.external_clock_level(current_cycle)
+    }
+
+    fn output_changed(&self, pin: GpioPin, high: bool, current_cycle: u64) {
+        self.io.output_changed(pin, high, current_cycle);
+    }
+}
+
+#[derive(Debug)]
+pub enum InputScriptError {
+    InvalidCycle(usize),
+    OutOfOrder(usize),
+    UnknownButton(usize, String),
+}
+
+impl Display for InputScriptError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&match &self {
+            InputScriptError::InvalidCycle(line) => {
+                format!("Line {line} does not start with a cycle count")
+            }
+            InputScriptError::OutOfOrder(line) => {
+                format!("Line {line} is earlier than the line before it")
+            }
+            InputScriptError::UnknownButton(line, name) => {
+                format!("Line {line} has an unknown button \"{name}\"")
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Polls `io` every 1024 cycles up to `end`, collecting each update with its cycle
+    fn poll(io: &dyn GpioInterface, end: u64) -> Vec<(u64, GpioButtonState)> {
+        (0..end)
+            .step_by(1024)
+            .filter_map(|cycle| io.get_updates(cycle).map(|state| (cycle, state)))
+            .collect()
+    }
+
+    #[test]
+    fn parses_buttons_and_releases() {
+        let mut script =
+            InputScript::parse("# comment\n\n100 action up\n  200  \n200 mute\n").unwrap();
+
+        assert_eq!(script.next_cycle(), Some(100));
+        assert_eq!(script.take_due(99), None);
+
+        let state = script.take_due(100).unwrap();
+        assert!(state.action && state.up && !state.down);
+
+        assert_eq!(script.take_due(500), Some(GpioButtonState::default()));
+
+        let state = script.take_due(500).unwrap();
+        assert!(state.mute);
+        assert_eq!(script.next_cycle(), None);
+    }
+
+    #[test]
+    fn rejects_a_line_without_a_cycle() {
+        assert!(matches!(
+            InputScript::parse("100 up\naction 200\n"),
+            Err(InputScriptError::InvalidCycle(2))
+        ));
+        assert!(matches!(
+            InputScript::parse("-5 up\n"),
+            Err(InputScriptError::InvalidCycle(1))
+        ));
+    }
+
+    #[test]
+    fn rejects_an_unknown_button() {
+        match InputScript::parse("100 up\n200 jump\n") {
+            Err(InputScriptError::UnknownButton(2, name)) => assert_eq!(name, "jump"),
+            other => panic!("Unexpected result {:?}", other.err()),
+        }
+    }
+
+    #[test]
+    fn rejects_cycles_going_backwards() {
+        assert!(matches!(
+            InputScript::parse("100 up\n# ignored\n50 down\n"),
+            Err(InputScriptError::OutOfOrder(3))
+        ));
+    }
+
+    #[test]
+    fn recording_replays_identically() {
+        let source =
+            InputScript::parse("0 power\n5000 power action\n5000 action\n20000 up left\n40000\n")
+                .unwrap();
+
+        let mut recording = Vec::<u8>::new();
+        let recorded = {
+            let recorder =
+                InputRecorder::new(Box::new(ScriptedGpio::new(source)), &mut recording).unwrap();
+            poll(&recorder, 100_000)
+        };
+        assert_eq!(recorded.len(), 5);
+
+        let replay = InputScript::parse(std::str::from_utf8(&recording).unwrap()).unwrap();
+        let replayed = poll(&ScriptedGpio::new(replay), 100_000);
+
+        assert_eq!(recorded, replayed);
+    }
+}
@@ -1,14 +1,52 @@
 mod audio;
 mod gpio;
+mod input_script;
 pub mod memory;
 mod miuchiz;
+mod pin_recorder;
 mod platform;
+mod resampler;
+mod savestate;
 mod screen;
+mod screenshot;
+mod serial;
+mod usb;
+mod video;
+mod wav;
 
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::channel;
+use std::time::{Duration, Instant};
 
 use clap::Parser;
 use cpal::traits::StreamTrait;
+use gpio::GpioInterface;
+use input_script::{InputRecorder, InputScript, ScriptedGpio};
+use pin_recorder::PinRecorder;
+use platform::cpal_audio::AudioPacer;
+use platform::headless::{ChannelGpio, NullAudio};
+use platform::minifb_screen_gpio::Hotkey;
+use platform::rtc_source::RtcSource;
+use platform::serial::SerialBackend;
+use screenshot::{CapturingScreen, FrameCapture};
+
+/// Frame rate of the deterministic loop
+const FRAMES_PER_SECOND: u64 = 60;
+
+/// Sample rate of recorded audio when there is no audio output to match
+const FALLBACK_SAMPLE_RATE: u32 = 44100;
+
+/// Longest to wait for the audio output before checking the window again
+const AUDIO_SYNC_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// What keeps the emulator running at the device's speed
+#[derive(Clone, Copy, PartialEq, clap::ValueEnum)]
+enum SyncMode {
+    /// Run as many cycles as the host's clock says have passed
+    Clock,
+    /// Run as many cycles as it takes to keep the audio output fed
+    Audio,
+}
 
 #[derive(Parser)]
 struct Args {
@@ -22,96 +60,780 @@ struct Args {
     #[arg(long)]
     save_file: Option<PathBuf>,
 
+    /// Save state to load at startup, and to load with F9
+    #[arg(long)]
+    load_state: Option<PathBuf>,
+
+    /// File to save the state to on exit and with F5
+    #[arg(long)]
+    save_state: Option<PathBuf>,
+
     /// Pixel scale
     #[arg(long, default_value_t = 3)]
     scale: usize,
+
+    /// Run without a window, audio or input, for the given number of cycles or seconds
+    #[arg(long, requires = "run_length")]
+    headless: bool,
+
+    /// Number of CPU cycles to run for in headless mode
+    #[arg(long, group = "run_length", requires = "headless")]
+    cycles: Option<u64>,
+
+    /// Number of emulated seconds to run for in headless mode
+    #[arg(long, group = "run_length", requires = "headless")]
+    seconds: Option<f64>,
+
+    /// Run a fixed number of cycles per frame and only apply input between frames
+    #[arg(long)]
+    deterministic: bool,
+
+    /// Audio output device to play through, or "list" to show the available ones
+    #[arg(long)]
+    audio_device: Option<String>,
+
+    /// How emulation is kept to real time
+    #[arg(long, value_enum, default_value_t = SyncMode::Clock, conflicts_with_all = ["deterministic", "headless"])]
+    sync: SyncMode,
+
+    /// Emulated time to skip ahead by at startup, such as 90m or 2h
+    #[arg(long, value_parser = parse_duration)]
+    warp: Option<Duration>,
+
+    /// Emulated time to skip ahead by with F7
+    #[arg(long, value_parser = parse_duration, default_value = "1h")]
+    warp_step: Duration,
+
+    /// How to set the real-time clock at startup: host, fixed:<YYYY-MM-DDTHH:MM[:SS]>,
+    /// or flash to keep an offset from the host's time next to the flash image.
+    /// Defaults to host, or to fixed:2000-01-01T00:00 with --deterministic or --headless.
+    #[arg(long)]
+    rtc: Option<RtcSource>,
+
+    /// Show frames from the ST2205U's built-in LCD controller in place of the ST7626's,
+    /// on a panel of <WIDTH>x<HEIGHT> pixels, for firmware which uses it
+    #[arg(long, value_parser = parse_panel_size)]
+    internal_lcd: Option<(usize, usize)>,
+
+    /// Where to connect the UART: pty, tcp:<PORT>, file:<PATH> or stdio
+    #[arg(long)]
+    serial: Option<SerialBackend>,
+
+    /// Unix socket to expose the USB device on, for host programs to connect to
+    #[arg(long)]
+    usb_socket: Option<PathBuf>,
+
+    /// Image of a 25 series SPI flash to attach to the SPI bus
+    #[arg(long, group = "spi_memory")]
+    spi_flash: Option<PathBuf>,
+
+    /// Image of a 25 series SPI EEPROM to attach to the SPI bus
+    #[arg(long, group = "spi_memory")]
+    spi_eeprom: Option<PathBuf>,
+
+    /// File to save the SPI memory's image to on exit
+    #[arg(long, requires = "spi_memory")]
+    spi_save_file: Option<PathBuf>,
+
+    /// Button presses to play back in place of the window's input
+    #[arg(long)]
+    input_script: Option<PathBuf>,
+
+    /// File to record button presses to, as an input script
+    #[arg(long)]
+    record_input: Option<PathBuf>,
+
+    /// File to record each change of an output pin's level to, such as those driven by timers
+    #[arg(long)]
+    record_pins: Option<PathBuf>,
+
+    /// Drive the external clock pin (PB7) with a square wave of this many Hz,
+    /// for timers which count it
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..=miuchiz::CYCLES_PER_SECOND / 2))]
+    external_clock: Option<u64>,
+
+    /// PNG file to write the screen to on exit
+    #[arg(long)]
+    screenshot: Option<PathBuf>,
+
+    /// Also write each screenshot scaled up by this factor, as <NAME>@<SCALE>x.png
+    #[arg(long)]
+    screenshot_scale: Option<usize>,
+
+    /// GIF or APNG (.png) file to record the screen to, from startup until exit
+    #[arg(long)]
+    record_video: Option<PathBuf>,
+
+    /// Pixel scale of recorded video
+    #[arg(long, default_value_t = 1)]
+    video_scale: usize,
+
+    /// WAV file to record the audio mix to
+    #[arg(long)]
+    record_audio: Option<PathBuf>,
+
+    /// Sample rate of recorded audio, instead of the audio output's rate
+    #[arg(long, requires = "record_audio")]
+    record_audio_rate: Option<u32>,
 }
 
-fn main() {
-    let args = Args::parse();
+/// Parses a length of time such as "30s", "90m", "2h" or "1d"
+fn parse_duration(text: &str) -> Result<Duration, String> {
+    let number_length = text.trim_end_matches(char::is_alphabetic).len();
+    let (number, unit) = text.split_at(number_length);
+
+    let seconds_per_unit = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => {
+            return Err(format!(
+                "\"{unit}\" is not a unit of time; use s, m, h or d"
+            ))
+        }
+    };
+
+    let number: f64 = number
+        .parse()
+        .map_err(|_| format!("\"{number}\" is not a number"))?;
+
+    Duration::try_from_secs_f64(number * seconds_per_unit as f64).map_err(|why| why.to_string())
+}
+
+/// Parses the size of a panel, such as "160x120"
+fn parse_panel_size(text: &str) -> Result<(usize, usize), String> {
+    let (width, height) = text
+        .split_once('x')
+        .ok_or_else(|| format!("\"{text}\" is not a size such as 160x120"))?;
+    let parse = |number: &str| match number.parse::<usize>() {
+        Ok(number) if (1..=255).contains(&number) => Ok(number),
+        _ => Err(format!("\"{number}\" is not a length from 1 to 255")),
+    };
+    Ok((parse(width)?, parse(height)?))
+}
+
+/// The size of the screen frames are shown on, which is the ST7626's unless
+/// the internal LCD controller is shown instead
+fn screen_size(args: &Args) -> (usize, usize) {
+    args.internal_lcd
+        .unwrap_or((miuchiz::LCD_WIDTH, miuchiz::LCD_HEIGHT))
+}
+
+/// Where the real-time clock's time comes from, which must not depend on the
+/// host's clock when runs are meant to be repeatable
+fn rtc_source(args: &Args) -> RtcSource {
+    args.rtc.unwrap_or(if args.deterministic || args.headless {
+        // 2000-01-01 00:00
+        RtcSource::Fixed(0)
+    } else {
+        RtcSource::Host
+    })
+}
+
+/// Skips `duration` of emulated time ahead as fast as possible
+fn warp(handheld: &mut miuchiz::Handheld, duration: Duration) {
+    let cycles = duration.as_nanos() * handheld.mcu.core.cycles_per_second() as u128 / 1000000000;
+
+    let started = Instant::now();
+    handheld.warp(cycles as u64);
+    eprintln!(
+        "Warped forward by {:?} in {:.2?}",
+        duration,
+        started.elapsed()
+    );
+}
+
+fn save_state(handheld: &miuchiz::Handheld, path: &Path) {
+    match std::fs::write(path, handheld.save_state()) {
+        Ok(_) => {
+            eprintln!("Saved state to {path:?}");
+        }
+        Err(why) => {
+            eprintln!("Failed to save state: {why}");
+        }
+    }
+}
 
-    let otp_data = match std::fs::read(args.otp_file) {
+fn load_state(handheld: &mut miuchiz::Handheld, path: &Path) -> bool {
+    let data = match std::fs::read(path) {
         Ok(data) => data,
         Err(why) => {
-            eprintln!("Could not read OTP file: {why}");
-            return;
+            eprintln!("Could not read save state: {why}");
+            return false;
         }
     };
 
-    let flash_data = match std::fs::read(args.flash_file) {
-        Ok(data) => data,
+    match handheld.load_state(&data) {
+        Ok(_) => {
+            eprintln!("Loaded state from {path:?}");
+            true
+        }
         Err(why) => {
-            eprintln!("Could not read flash file: {why}");
-            return;
+            eprintln!("Could not load save state: {why}");
+            false
+        }
+    }
+}
+
+fn take_screenshot(capture: &FrameCapture, path: &Path, scale: Option<usize>) {
+    let mut outputs = vec![(path.to_path_buf(), 1)];
+    if let Some(scale) = scale.filter(|scale| *scale > 1) {
+        outputs.push((screenshot::scaled_path(path, scale), scale));
+    }
+
+    for (path, scale) in outputs {
+        match capture.save_png(&path, scale) {
+            Ok(_) => {
+                eprintln!("Saved screenshot to {path:?}");
+            }
+            Err(why) => {
+                eprintln!("Failed to save screenshot: {why}");
+            }
+        }
+    }
+}
+
+fn start_recording(
+    capture: &FrameCapture,
+    path: PathBuf,
+    handheld: &miuchiz::Handheld,
+    scale: usize,
+) -> bool {
+    let core = &handheld.mcu.core;
+    match capture.start_recording(path.clone(), scale, core.cycles, core.cycles_per_second()) {
+        Ok(_) => {
+            eprintln!("Recording video to {path:?}");
+            true
         }
+        Err(why) => {
+            eprintln!("Could not start recording: {why}");
+            false
+        }
+    }
+}
+
+fn stop_recording(capture: &FrameCapture, handheld: &miuchiz::Handheld) {
+    let Some(recording) = capture.stop_recording() else {
+        return;
     };
 
-    let scale = args.scale;
+    let path = recording.path().to_owned();
+    match recording.finish(handheld.mcu.core.cycles) {
+        Ok(frames) => {
+            eprintln!("Saved {frames} frames to {path:?}");
+        }
+        Err(why) => {
+            eprintln!("Failed to save video: {why}");
+        }
+    }
+}
 
-    let (mut screen, screen_rx, screen_tx) =
-        platform::minifb_screen_gpio::MiniFbScreen::open("emiu2", scale);
+/// Records the audio to a WAV file, if requested. Unless another rate is
+/// asked for, it is recorded at the rate of the audio output.
+fn record_audio(args: &Args, handheld: &mut miuchiz::Handheld, output_sample_rate: u32) -> bool {
+    let Some(path) = &args.record_audio else {
+        return true;
+    };
 
-    let minifb_gpio = platform::minifb_screen_gpio::MiniFbGpioInterface::new(screen_rx);
-    let minifb_screen = platform::minifb_screen_gpio::MiniFbScreenInterface::new(screen_tx);
+    let sample_rate = args.record_audio_rate.unwrap_or(output_sample_rate);
+    match wav::WavRecorder::new(path, sample_rate) {
+        Ok(recorder) => {
+            eprintln!("Recording audio to {path:?} at {sample_rate} Hz");
+            handheld.mcu.set_audio_recorder(Some(Box::new(recorder)));
+            true
+        }
+        Err(why) => {
+            eprintln!("Could not record audio: {why}");
+            false
+        }
+    }
+}
 
-    let (stream, sender) = match platform::cpal_audio::stream_setup_for() {
-        Ok((stream, sender)) => (stream, sender),
+fn read_input_script(path: &Path) -> Option<InputScript> {
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
         Err(why) => {
-            eprintln!("Could not setup audio stream: {why}");
-            return;
+            eprintln!("Could not read input script: {why}");
+            return None;
         }
     };
 
-    if let Err(why) = stream.play() {
-        eprintln!("Could not play audio stream: {why}");
-        return;
+    match InputScript::parse(&text) {
+        Ok(script) => Some(script),
+        Err(why) => {
+            eprintln!("Could not parse input script: {why}");
+            None
+        }
     }
+}
 
-    let mut handheld = match miuchiz::Handheld::new(
-        &otp_data,
-        &flash_data,
-        Box::new(minifb_screen),
-        Box::new(minifb_gpio),
-        Box::new(sender),
-    ) {
-        Ok(handheld) => handheld,
+/// Makes the SPI memory chosen by the arguments, if there is one
+fn open_spi_memory(args: &Args) -> Result<Option<miuchiz::SpiMemory>, String> {
+    let (kind, path) = match (&args.spi_flash, &args.spi_eeprom) {
+        (Some(path), _) => (miuchiz::SpiMemoryKind::Flash, path),
+        (None, Some(path)) => (miuchiz::SpiMemoryKind::Eeprom, path),
+        (None, None) => return Ok(None),
+    };
+
+    let data = std::fs::read(path).map_err(|why| format!("Could not read {path:?}: {why}"))?;
+    miuchiz::SpiMemory::new(kind, &data).map(Some)
+}
+
+/// Creates the handheld and loads the startup save state, if there is one.
+/// Frames drawn to `screen` are also kept for screenshots.
+fn make_handheld(
+    args: &Args,
+    otp_data: &[u8],
+    flash_data: &[u8],
+    screen: Box<dyn screen::Screen>,
+    mut gpio: Box<dyn gpio::GpioInterface>,
+    audio: Box<dyn audio::AudioInterface>,
+    audio_sample_rate: u32,
+) -> Option<(miuchiz::Handheld, FrameCapture)> {
+    let (width, height) = screen_size(args);
+    let (screen, capture) = CapturingScreen::new(screen, width, height);
+
+    if let Some(record_file) = &args.record_input {
+        let recorder = std::fs::File::create(record_file)
+            .and_then(|file| InputRecorder::new(gpio, std::io::BufWriter::new(file)));
+        gpio = match recorder {
+            Ok(recorder) => Box::new(recorder),
+            Err(why) => {
+                eprintln!("Could not record input: {why}");
+                return None;
+            }
+        };
+    }
+
+    if let Some(record_file) = &args.record_pins {
+        let recorder = std::fs::File::create(record_file)
+            .and_then(|file| PinRecorder::new(gpio, std::io::BufWriter::new(file)));
+        gpio = match recorder {
+            Ok(recorder) => Box::new(recorder),
+            Err(why) => {
+                eprintln!("Could not record pins: {why}");
+                return None;
+            }
+        };
+    }
+
+    if let Some(frequency) = args.external_clock {
+        let period = miuchiz::CYCLES_PER_SECOND / frequency;
+        gpio = Box::new(gpio::ExternalClock::new(gpio, period));
+    }
+
+    let mut handheld =
+        match miuchiz::Handheld::new(otp_data, flash_data, Box::new(screen), gpio, audio) {
+            Ok(handheld) => handheld,
+            Err(why) => {
+                eprintln!("Could not initialize the Miuchiz handheld device: {why}");
+                return None;
+            }
+        };
+
+    if !record_audio(args, &mut handheld, audio_sample_rate) {
+        return None;
+    }
+
+    if let Some((width, height)) = args.internal_lcd {
+        handheld.show_internal_lcd(width, height);
+    }
+
+    if let Some(backend) = &args.serial {
+        match platform::serial::open(backend) {
+            Ok(serial) => handheld.connect_serial(serial),
+            Err(why) => {
+                eprintln!("Could not open the serial port: {why}");
+                return None;
+            }
+        }
+    }
+
+    if let Some(path) = &args.usb_socket {
+        match platform::usb_socket::open(path) {
+            Ok(host) => handheld.connect_usb(host),
+            Err(why) => {
+                eprintln!("Could not open the USB socket: {why}");
+                return None;
+            }
+        }
+    }
+
+    match open_spi_memory(args) {
+        Ok(Some(memory)) => handheld.attach_spi_device(Box::new(memory)),
+        Ok(None) => {}
         Err(why) => {
-            eprintln!("Could not initialize the Miuchiz handheld device: {why}");
-            return;
+            eprintln!("Could not attach the SPI memory: {why}");
+            return None;
+        }
+    }
+
+    // A save state has its own clock time
+    if let Some(load_state_file) = &args.load_state {
+        if !load_state(&mut handheld, load_state_file) {
+            return None;
+        }
+    } else {
+        match platform::rtc_source::start_time(rtc_source(args), Path::new(&args.flash_file)) {
+            Ok(time) => handheld.set_rtc_time(time),
+            Err(why) => {
+                eprintln!("Could not set the real-time clock: {why}");
+                return None;
+            }
         }
+    }
+
+    if let Some(duration) = args.warp {
+        warp(&mut handheld, duration);
+    }
+
+    if let Some(video_file) = &args.record_video {
+        if !start_recording(&capture, video_file.clone(), &handheld, args.video_scale) {
+            return None;
+        }
+    }
+
+    Some((handheld, capture))
+}
+
+struct AudioOutput {
+    /// Plays for as long as it is kept
+    stream: Option<cpal::Stream>,
+    audio: Box<dyn audio::AudioInterface>,
+    sample_rate: u32,
+    pacer: Option<AudioPacer>,
+}
+
+/// Opens the audio output, along with what paces emulation by it. If there is no usable
+/// output, audio is thrown away instead.
+fn open_audio(args: &Args) -> Option<AudioOutput> {
+    let output = platform::cpal_audio::stream_setup_for(args.audio_device.as_deref()).and_then(
+        |(stream, sender)| {
+            stream.play()?;
+            Ok((stream, sender))
+        },
+    );
+
+    match output {
+        Ok((stream, sender)) => {
+            let sample_rate = sender.sample_rate();
+            let pacer = sender.pacer();
+            Some(AudioOutput {
+                stream: Some(stream),
+                audio: Box::new(sender),
+                sample_rate,
+                pacer: Some(pacer),
+            })
+        }
+        Err(why) => {
+            eprintln!("Could not open the audio output, continuing without sound: {why}");
+            if args.sync == SyncMode::Audio {
+                eprintln!("Pacing emulation by the clock instead of the audio output");
+            }
+            Some(AudioOutput {
+                stream: None,
+                audio: Box::new(NullAudio),
+                sample_rate: FALLBACK_SAMPLE_RATE,
+                pacer: None,
+            })
+        }
+    }
+}
+
+fn run_windowed(
+    args: &Args,
+    otp_data: &[u8],
+    flash_data: &[u8],
+    quick_state_file: &Path,
+    script: Option<InputScript>,
+) -> Option<(miuchiz::Handheld, FrameCapture)> {
+    let (width, height) = screen_size(args);
+    let (mut screen, screen_rx, screen_tx) =
+        platform::minifb_screen_gpio::MiniFbScreen::open("emiu2", args.scale, width, height);
+
+    let minifb_screen = platform::minifb_screen_gpio::MiniFbScreenInterface::new(screen_tx);
+
+    // In deterministic mode, window input is passed on between frames
+    let (input_tx, input_rx) = channel();
+    let mut window_input = None;
+    let gpio: Box<dyn GpioInterface> = if let Some(script) = script {
+        Box::new(ScriptedGpio::new(script))
+    } else if args.deterministic {
+        window_input = Some(screen_rx);
+        Box::new(ChannelGpio::new(input_rx))
+    } else {
+        Box::new(platform::minifb_screen_gpio::MiniFbGpioInterface::new(
+            screen_rx,
+        ))
     };
-    // std::thread::sleep(std::time::Duration::from_secs(3));
 
-    let beginning = std::time::Instant::now();
+    let AudioOutput {
+        stream: _stream,
+        audio,
+        sample_rate,
+        pacer,
+    } = open_audio(args)?;
+
+    let (mut handheld, capture) = make_handheld(
+        args,
+        otp_data,
+        flash_data,
+        Box::new(minifb_screen),
+        gpio,
+        audio,
+        sample_rate,
+    )?;
+
+    let frame_cycles = handheld.mcu.core.cycles_per_second() / FRAMES_PER_SECOND;
+    let mut frames = 0u64;
+
+    let mut beginning = Instant::now();
+    let mut beginning_cycles = handheld.mcu.core.cycles;
 
     while screen.is_open() {
-        let now = std::time::Instant::now();
-        let elapsed = now - beginning;
-        let nanoseconds = elapsed.as_nanos();
-        let cycles_required_so_far =
-            (nanoseconds * handheld.mcu.core.cycles_per_second() as u128) / 1000000000;
+        if args.deterministic {
+            if let Some(window_input) = &window_input {
+                while let Ok(state) = window_input.try_recv() {
+                    input_tx.send(state).ok();
+                }
+            }
+
+            frames += 1;
+            handheld.run_until(beginning_cycles + frames * frame_cycles);
+
+            // The host only decides when a frame runs, never how far it goes
+            let frame_due =
+                beginning + Duration::from_nanos(frames * 1_000_000_000 / FRAMES_PER_SECOND);
+            if let Some(wait) = frame_due.checked_duration_since(Instant::now()) {
+                std::thread::sleep(wait);
+            }
+        } else if let (SyncMode::Audio, Some(pacer)) = (args.sync, &pacer) {
+            // Sleeps until the output has played some audio, then makes that much more
+            let demand = pacer.wait_for_demand(AUDIO_SYNC_TIMEOUT);
+            let cycles =
+                demand.as_nanos() * handheld.mcu.core.cycles_per_second() as u128 / 1000000000;
+            handheld.run_until(handheld.mcu.core.cycles + cycles as u64);
+        } else {
+            let now = Instant::now();
+            let elapsed = now - beginning;
+            let nanoseconds = elapsed.as_nanos();
+            let cycles_required_so_far = beginning_cycles as u128
+                + (nanoseconds * handheld.mcu.core.cycles_per_second() as u128) / 1000000000;
 
-        while (handheld.mcu.core.cycles as u128) < cycles_required_so_far {
-            // let pc = handheld.mcu.core.registers.pc;
-            // let inst = handheld.mcu.core.decode_next_instruction();
-            // println!("{pc:04X}: {}", inst.instruction.to_string());
-            handheld.mcu.step();
+            while (handheld.mcu.core.cycles as u128) < cycles_required_so_far {
+                // let pc = handheld.mcu.core.registers.pc;
+                // let inst = handheld.mcu.core.decode_next_instruction();
+                // println!("{pc:04X}: {}", inst.instruction.to_string());
+                handheld.mcu.step();
+            }
         }
 
         screen.update_state();
-        std::thread::sleep(std::time::Duration::from_nanos(1));
+        while let Some(hotkey) = screen.next_hotkey() {
+            match hotkey {
+                Hotkey::SaveState => save_state(&handheld, quick_state_file),
+                Hotkey::LoadState => {
+                    // Emulated time is about to jump, which a video can't follow
+                    if capture.is_recording() {
+                        eprintln!("Stopping the recording to load a state");
+                        stop_recording(&capture, &handheld);
+                    }
+                    if load_state(&mut handheld, quick_state_file) {
+                        // Emulated time jumped, so pace from here
+                        beginning = Instant::now();
+                        beginning_cycles = handheld.mcu.core.cycles;
+                        frames = 0;
+                    }
+                }
+                Hotkey::Warp => {
+                    warp(&mut handheld, args.warp_step);
+                    // Emulated time jumped, so pace from here
+                    beginning = Instant::now();
+                    beginning_cycles = handheld.mcu.core.cycles;
+                    frames = 0;
+                }
+                Hotkey::Screenshot => {
+                    let path = format!("{}.{}.png", args.flash_file, handheld.mcu.core.cycles);
+                    take_screenshot(&capture, Path::new(&path), args.screenshot_scale);
+                }
+                Hotkey::ToggleRecording => {
+                    if capture.is_recording() {
+                        stop_recording(&capture, &handheld);
+                    } else {
+                        let path = format!("{}.{}.gif", args.flash_file, handheld.mcu.core.cycles);
+                        start_recording(&capture, PathBuf::from(path), &handheld, args.video_scale);
+                    }
+                }
+            }
+        }
+        std::thread::sleep(Duration::from_nanos(1));
     }
 
-    if let Some(save_file) = args.save_file {
-        match std::fs::write(&save_file, handheld.make_flash_dump()) {
+    Some((handheld, capture))
+}
+
+/// Runs as fast as possible for the requested length of emulated time
+fn run_headless(
+    args: &Args,
+    otp_data: &[u8],
+    flash_data: &[u8],
+    script: Option<InputScript>,
+) -> Option<(miuchiz::Handheld, FrameCapture)> {
+    let (mut handheld, capture) = make_handheld(
+        args,
+        otp_data,
+        flash_data,
+        Box::new(platform::headless::NullScreen),
+        Box::new(ScriptedGpio::new(script.unwrap_or_default())),
+        Box::new(NullAudio),
+        FALLBACK_SAMPLE_RATE,
+    )?;
+
+    let run_cycles = match (args.cycles, args.seconds) {
+        (Some(cycles), _) => cycles,
+        (None, Some(seconds)) => (seconds * handheld.mcu.core.cycles_per_second() as f64) as u64,
+        (None, None) => unreachable!("clap requires a run length in headless mode"),
+    };
+
+    // Counted from the loaded state, if any
+    let end_cycle = handheld.mcu.core.cycles.saturating_add(run_cycles);
+    handheld.run_until(end_cycle);
+
+    eprintln!("Ran for {run_cycles} cycles");
+
+    Some((handheld, capture))
+}
+
+fn main() {
+    let args = Args::parse();
+
+    if args.audio_device.as_deref() == Some("list") {
+        if let Err(why) = platform::cpal_audio::print_output_devices() {
+            eprintln!("Could not list audio devices: {why}");
+        }
+        return;
+    }
+
+    // Hotkeys use the save state file, or the load state file, or one next to the flash image
+    let quick_state_file = args
+        .save_state
+        .clone()
+        .or(args.load_state.clone())
+        .unwrap_or_else(|| PathBuf::from(format!("{}.state", args.flash_file)));
+
+    let otp_data = match std::fs::read(&args.otp_file) {
+        Ok(data) => data,
+        Err(why) => {
+            eprintln!("Could not read OTP file: {why}");
+            return;
+        }
+    };
+
+    let flash_data = match std::fs::read(&args.flash_file) {
+        Ok(data) => data,
+        Err(why) => {
+            eprintln!("Could not read flash file: {why}");
+            return;
+        }
+    };
+
+    let script = match &args.input_script {
+        Some(path) => match read_input_script(path) {
+            Some(script) => Some(script),
+            None => return,
+        },
+        None => None,
+    };
+
+    let handheld = if args.headless {
+        run_headless(&args, &otp_data, &flash_data, script)
+    } else {
+        run_windowed(&args, &otp_data, &flash_data, &quick_state_file, script)
+    };
+
+    let Some((mut handheld, capture)) = handheld else {
+        return;
+    };
+
+    if let Some(screenshot_file) = &args.screenshot {
+        take_screenshot(&capture, screenshot_file, args.screenshot_scale);
+    }
+
+    stop_recording(&capture, &handheld);
+
+    if let Some(save_state_file) = &args.save_state {
+        save_state(&handheld, save_state_file);
+    }
+
+    if let Some(save_file) = &args.save_file {
+        match std::fs::write(save_file, handheld.make_flash_dump()) {
             Ok(_) => {
-                println!("Saved flash to {save_file:?}");
+                eprintln!("Saved flash to {save_file:?}");
             }
             Err(why) => {
                 eprintln!("Failed to save flash: {why}");
             }
         }
+
+        if rtc_source(&args) == RtcSource::Flash {
+            // Kept next to the flash image, which is where it is read from at startup
+            let flash_file = Path::new(&args.flash_file);
+            match platform::rtc_source::save_offset(handheld.rtc_time(), flash_file) {
+                Ok(path) => {
+                    eprintln!("Saved clock offset to {path:?}");
+                }
+                Err(why) => {
+                    eprintln!("Failed to save clock offset: {why}");
+                }
+            }
+        }
+    }
+
+    if let Some(spi_save_file) = &args.spi_save_file {
+        let storage = handheld.spi_storage().unwrap_or_default();
+        match std::fs::write(spi_save_file, storage) {
+            Ok(_) => {
+                eprintln!("Saved SPI memory to {spi_save_file:?}");
+            }
+            Err(why) => {
+                eprintln!("Failed to save SPI memory: {why}");
+            }
+        }
     }
 
     // println!("{} cycles", handheld.mcu.core.cycles);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_each_unit() {
+        assert_eq!(parse_duration("30s"), Ok(Duration::from_secs(30)));
+        assert_eq!(parse_duration("90m"), Ok(Duration::from_secs(90 * 60)));
+        assert_eq!(parse_duration("2h"), Ok(Duration::from_secs(2 * 60 * 60)));
+        assert_eq!(parse_duration("1d"), Ok(Duration::from_secs(24 * 60 * 60)));
+    }
+
+    #[test]
+    fn parses_fractions() {
+        assert_eq!(parse_duration("1.5h"), Ok(Duration::from_secs(90 * 60)));
+        assert_eq!(parse_duration("0.25s"), Ok(Duration::from_millis(250)));
+    }
+
+    #[test]
+    fn rejects_a_missing_or_unknown_unit() {
+        assert!(parse_duration("10").is_err());
+        assert!(parse_duration("10x").is_err());
+        assert!(parse_duration("10 s").is_err());
+    }
+
+    #[test]
+    fn rejects_a_missing_or_negative_number() {
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("h").is_err());
+        assert!(parse_duration("-1s").is_err());
+    }
+}
@@ -1,9 +1,22 @@
 use super::{sst39vf1681, st2205u, st7626};
-use crate::{audio::AudioInterface, gpio::GpioInterface, memory::AddressSpace, screen::Screen};
+use crate::{
+    audio::AudioInterface,
+    gpio::GpioInterface,
+    memory::AddressSpace,
+    savestate::{SaveState, StateError, StateReader, StateWriter},
+    screen::{Pixel, Screen},
+    serial::SerialInterface,
+    usb::UsbHost,
+};
+use std::cell::{Cell, RefCell};
 use std::fmt::Display;
+use std::rc::Rc;
 
 pub const SYSTEM_FREQ: u64 = 16_000_000;
 
+/// CPU cycles per second
+pub const CYCLES_PER_SECOND: u64 = SYSTEM_FREQ / st2205u::CYCLE_FREQUENCY_DIVISOR;
+
 #[derive(Debug)]
 enum AddressType {
     Video,
@@ -69,12 +82,31 @@ impl AddressSpace for HandheldAddressSpace {
     fn write_u8(&mut self, address: usize, value: u8) {
         match AddressType::parse_machine_addr(address) {
             (AddressType::Video, vid_addr) => self.lcd.write_u8(vid_addr, value),
-            (AddressType::Otp, otp_addr) => println!("Attempt to write to OTP addr {otp_addr:X}"),
+            (AddressType::Otp, otp_addr) => eprintln!("Attempt to write to OTP addr {otp_addr:X}"),
             (AddressType::Flash, flash_addr) => self.flash.write_u8(flash_addr, value),
         }
     }
 }
 
+impl st2205u::Clock for HandheldAddressSpace {
+    fn set_clocks(&mut self, _clocks: u64, sysck: u64) {
+        self.lcd.set_current_cycle(sysck);
+    }
+}
+
+impl SaveState for HandheldAddressSpace {
+    fn save_state(&self, state: &mut StateWriter) {
+        // The OTP is read-only, so it is not part of the state
+        self.flash.save_state(state);
+        self.lcd.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.flash.load_state(state)?;
+        self.lcd.load_state(state)
+    }
+}
+
 #[derive(Debug)]
 pub enum ConfigurationError {
     InvalidOtpSize(usize),
@@ -96,8 +128,78 @@ impl Display for ConfigurationError {
     }
 }
 
+/// Passes frames on to another screen unless they are being held back
+struct HoldableScreen {
+    screen: Box<dyn Screen>,
+    held: Cell<bool>,
+    /// Whether the screen shows the ST2205U's LCD controller in place of the ST7626
+    internal_lcd: Cell<bool>,
+    /// The latest frame drawn while held, and the cycle it was drawn on
+    latest: RefCell<Option<(Vec<Pixel>, u64)>>,
+}
+
+impl HoldableScreen {
+    fn new(screen: Box<dyn Screen>) -> Self {
+        Self {
+            screen,
+            held: Cell::new(false),
+            internal_lcd: Cell::new(false),
+            latest: RefCell::new(None),
+        }
+    }
+
+    fn hold(&self) {
+        self.held.set(true);
+    }
+
+    /// Shows the latest frame which was held back, if there was one
+    fn release(&self) {
+        self.held.set(false);
+        if let Some((pixels, cycle)) = self.latest.take() {
+            self.screen.set_pixels(&pixels, cycle);
+        }
+    }
+
+    /// Stops holding frames back without showing the ones that were
+    fn discard(&self) {
+        self.held.set(false);
+        self.latest.take();
+    }
+
+    /// Shows a frame, or keeps it for later if frames are being held back
+    fn show(&self, pixels: &[Pixel], current_cycle: u64) {
+        if self.held.get() {
+            *self.latest.borrow_mut() = Some((pixels.to_vec(), current_cycle));
+        } else {
+            self.screen.set_pixels(pixels, current_cycle);
+        }
+    }
+}
+
+/// The ST7626's connection to the screen, which is cut off while the internal
+/// LCD controller is shown instead
+struct St7626Screen(Rc<HoldableScreen>);
+
+impl Screen for St7626Screen {
+    fn set_pixels(&self, pixels: &[Pixel], current_cycle: u64) {
+        if !self.0.internal_lcd.get() {
+            self.0.show(pixels, current_cycle);
+        }
+    }
+}
+
+/// The internal LCD controller's connection to the screen
+struct InternalLcdScreen(Rc<HoldableScreen>);
+
+impl Screen for InternalLcdScreen {
+    fn set_pixels(&self, pixels: &[Pixel], current_cycle: u64) {
+        self.0.show(pixels, current_cycle);
+    }
+}
+
 pub struct Handheld {
     pub mcu: st2205u::Mcu,
+    screen: Rc<HoldableScreen>,
 }
 
 impl Handheld {
@@ -108,18 +210,385 @@ impl Handheld {
         io: Box<dyn GpioInterface>,
         audio_sender: Box<dyn AudioInterface>,
     ) -> Result<Self, ConfigurationError> {
-        let machine_address_space = Box::new(HandheldAddressSpace::new(otp, flash, screen)?);
+        let screen = Rc::new(HoldableScreen::new(screen));
+        let machine_address_space = Box::new(HandheldAddressSpace::new(
+            otp,
+            flash,
+            Box::new(St7626Screen(screen.clone())),
+        )?);
 
         let mcu = Self {
             mcu: st2205u::Mcu::new(SYSTEM_FREQ, machine_address_space, io, audio_sender),
+            screen,
         };
 
         Ok(mcu)
     }
 
+    /// Runs until the CPU has executed at least `cycle` cycles
+    pub fn run_until(&mut self, cycle: u64) {
+        while self.mcu.core.cycles < cycle {
+            self.mcu.step();
+        }
+    }
+
+    /// Connects the UART's serial line to the host
+    pub fn connect_serial(&mut self, serial: Box<dyn SerialInterface>) {
+        self.mcu.core.address_space.uart.connect(serial);
+    }
+
+    /// Plugs the USB device controller into a host
+    pub fn connect_usb(&mut self, host: Box<dyn UsbHost>) {
+        self.mcu.core.address_space.usb.connect(host);
+    }
+
+    /// Attaches a device to the SPI bus
+    pub fn attach_spi_device(&mut self, device: Box<dyn st2205u::SpiDevice>) {
+        self.mcu.core.address_space.spi.attach(device);
+    }
+
+    /// The contents of the SPI device's non-volatile memory, if it has any
+    pub fn spi_storage(&self) -> Option<&[u8]> {
+        self.mcu.core.address_space.spi.device()?.storage()
+    }
+
+    /// Shows frames from the ST2205U's built-in LCD controller on the screen in
+    /// place of those from the ST7626, for firmware which drives a panel of
+    /// `width` by `height` pixels with it. The screen must be that size too.
+    pub fn show_internal_lcd(&mut self, width: usize, height: usize) {
+        self.screen.internal_lcd.set(true);
+        self.mcu.core.address_space.lcd.connect_panel(
+            Box::new(InternalLcdScreen(self.screen.clone())),
+            width,
+            height,
+        );
+    }
+
+    /// The real-time clock's time, in seconds since 2000-01-01
+    pub fn rtc_time(&self) -> u64 {
+        self.mcu.core.address_space.rtc.time()
+    }
+
+    /// Sets the real-time clock, in seconds since 2000-01-01
+    pub fn set_rtc_time(&mut self, seconds: u64) {
+        self.mcu.core.address_space.rtc.set_time(seconds);
+        self.mcu.core.address_space.schedule_rtc();
+    }
+
+    /// Runs for `cycles` as fast as possible, without drawing to the screen,
+    /// producing audio or reading input. The screen shows the latest frame once
+    /// it is done.
+    pub fn warp(&mut self, cycles: u64) {
+        self.screen.hold();
+        self.mcu.set_audio_suppressed(true);
+        self.mcu.set_input_suspended(true);
+
+        self.run_until(self.mcu.core.cycles.saturating_add(cycles));
+
+        self.mcu.set_input_suspended(false);
+        self.mcu.set_audio_suppressed(false);
+        self.screen.release();
+    }
+
     pub fn make_flash_dump(&mut self) -> Vec<u8> {
         let start = 1 << 25;
         let size = sst39vf1681::Flash::len();
         self.mcu.read_machine_area(start, size)
     }
+
+    /// Captures the complete state of the running device
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut state = StateWriter::new();
+        self.mcu.save_state(&mut state);
+        state.into_bytes()
+    }
+
+    /// Restores a state made by `save_state`. The device is left untouched if
+    /// the state cannot be loaded.
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), StateError> {
+        let backup = self.save_state();
+        // Frames drawn while loading are only shown if the state loads
+        self.screen.hold();
+
+        let result = StateReader::new(data).and_then(|mut state| {
+            self.mcu.load_state(&mut state)?;
+            state.finish()
+        });
+
+        if result.is_err() {
+            let mut state = StateReader::new(&backup).expect("Backup state should be valid");
+            self.mcu
+                .load_state(&mut state)
+                .expect("Backup state should load");
+            self.screen.discard();
+        } else {
+            self.screen.release();
+        }
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gpio::{ExternalClock, GpioButtonState};
+    use crate::platform::headless::NullAudio;
+
+    /// Counts in RAM at $80, programming each count into the flash and
+    /// sending it to the ST7626's memory, forever
+    #[rustfmt::skip]
+    const PROGRAM: &[u8] = &[
+        0xA2, 0xFF,             // $4000: LDX #$FF
+        0x9A,                   // $4002: TXS
+        0x64, 0x36,             // $4003: STZ BRRL
+        0xA9, 0x03,             // $4005: LDA #$03
+        0x85, 0x37,             // $4007: STA BRRH      ; $2000 is the ST7626
+        0x64, 0x34,             // $4009: STZ DRRL
+        0xA9, 0x04,             // $400B: LDA #$04
+        0x85, 0x35,             // $400D: STA DRRH      ; $8000 is the flash
+        0xA9, 0xAF,             // $400F: LDA #$AF
+        0x8D, 0x00, 0x20,       // $4011: STA $2000     ; display on
+        0xA9, 0x5C,             // $4014: LDA #$5C
+        0x8D, 0x00, 0x20,       // $4016: STA $2000     ; write to memory
+        0xE6, 0x80,             // $4019: INC $80
+        0xA6, 0x80,             // $401B: LDX $80
+        0xA9, 0xAA,             // $401D: LDA #$AA
+        0x8D, 0xAA, 0x8A,       // $401F: STA $8AAA
+        0xA9, 0x55,             // $4022: LDA #$55
+        0x8D, 0x55, 0x85,       // $4024: STA $8555
+        0xA9, 0xA0,             // $4027: LDA #$A0
+        0x8D, 0xAA, 0x8A,       // $4029: STA $8AAA     ; byte program
+        0x8A,                   // $402C: TXA
+        0x9D, 0x00, 0x90,       // $402D: STA $9000,X
+        0x8D, 0x01, 0x20,       // $4030: STA $2001
+        0x4C, 0x19, 0x40,       // $4033: JMP $4019
+    ];
+
+    struct NoInput;
+
+    impl GpioInterface for NoInput {
+        fn get_updates(&self, _current_cycle: u64) -> Option<GpioButtonState> {
+            None
+        }
+    }
+
+    /// Keeps the latest frame drawn to it
+    #[derive(Clone, Default)]
+    struct LatestFrame(Rc<RefCell<Vec<Pixel>>>);
+
+    impl Screen for LatestFrame {
+        fn set_pixels(&self, pixels: &[Pixel], _current_cycle: u64) {
+            *self.0.borrow_mut() = pixels.to_vec();
+        }
+    }
+
+    fn make_handheld() -> (Handheld, LatestFrame) {
+        make_handheld_running(PROGRAM, Box::new(NoInput))
+    }
+
+    fn make_handheld_running(
+        program: &[u8],
+        gpio: Box<dyn GpioInterface>,
+    ) -> (Handheld, LatestFrame) {
+        let mut otp = vec![0u8; st2205u::OTP_SIZE];
+        otp[..program.len()].copy_from_slice(program);
+        // The reset vector, at $7FFC
+        otp[0x3FFC..0x3FFE].copy_from_slice(&[0x00, 0x40]);
+        let flash = vec![0xFF; sst39vf1681::Flash::len()];
+
+        let frame = LatestFrame::default();
+        let handheld = Handheld::new(
+            &otp,
+            &flash,
+            Box::new(frame.clone()),
+            gpio,
+            Box::new(NullAudio),
+        )
+        .unwrap();
+
+        (handheld, frame)
+    }
+
+    /// What the program has done so far
+    #[derive(PartialEq)]
+    struct Snapshot {
+        cpu: String,
+        cycles: u64,
+        ram: Vec<u8>,
+        flash: Vec<u8>,
+        lcd: Vec<Pixel>,
+    }
+
+    impl Snapshot {
+        fn take(handheld: &mut Handheld, frame: &LatestFrame) -> Self {
+            let core = &mut handheld.mcu.core;
+            let cpu = core.registers.to_string();
+            let cycles = core.cycles;
+            let ram = (0x80..0x2000)
+                .map(|address| core.address_space.read_u8(address))
+                .collect();
+
+            Self {
+                cpu,
+                cycles,
+                ram,
+                flash: handheld.make_flash_dump(),
+                lcd: frame.0.borrow().clone(),
+            }
+        }
+
+        fn assert_same(&self, other: &Self) {
+            assert_eq!(self.cpu, other.cpu);
+            assert_eq!(self.cycles, other.cycles);
+            assert!(self.ram == other.ram, "RAM differs");
+            assert!(self.flash == other.flash, "Flash differs");
+            assert!(self.lcd == other.lcd, "LCD frame differs");
+        }
+    }
+
+    #[test]
+    fn round_trip_resumes_identically() {
+        let (mut handheld, frame) = make_handheld();
+        handheld.run_until(20_000);
+        let state = handheld.save_state();
+        let saved = Snapshot::take(&mut handheld, &frame);
+
+        handheld.run_until(50_000);
+        let expected = Snapshot::take(&mut handheld, &frame);
+        let expected_state = handheld.save_state();
+        assert!(
+            saved.ram != expected.ram,
+            "The program did not change the RAM"
+        );
+        assert!(
+            saved.flash != expected.flash,
+            "The program did not write the flash"
+        );
+        assert!(
+            saved.lcd != expected.lcd,
+            "The program did not draw to the LCD"
+        );
+
+        handheld.load_state(&state).unwrap();
+        // The LCD redraws from its restored memory, so only the rest matches right away
+        let restored = Snapshot::take(&mut handheld, &frame);
+        assert_eq!(restored.cpu, saved.cpu);
+        assert_eq!(restored.cycles, saved.cycles);
+        assert!(restored.ram == saved.ram && restored.flash == saved.flash);
+
+        handheld.run_until(50_000);
+        Snapshot::take(&mut handheld, &frame).assert_same(&expected);
+        assert!(handheld.save_state() == expected_state);
+    }
+
+    #[test]
+    fn rejected_state_leaves_device_unchanged() {
+        let (mut handheld, frame) = make_handheld();
+        handheld.run_until(20_000);
+        let state = handheld.save_state();
+        handheld.run_until(50_000);
+
+        let expected = Snapshot::take(&mut handheld, &frame);
+        let expected_state = handheld.save_state();
+
+        // Cut off partway through, after part of the device has been loaded
+        let truncated = &state[..state.len() / 2];
+        assert!(matches!(
+            handheld.load_state(truncated),
+            Err(StateError::UnexpectedEnd)
+        ));
+        Snapshot::take(&mut handheld, &frame).assert_same(&expected);
+        assert!(handheld.save_state() == expected_state);
+
+        // The version follows the 8 byte magic
+        let mut wrong_version = state.clone();
+        let version = u32::from_le_bytes(state[8..12].try_into().unwrap());
+        wrong_version[8..12].copy_from_slice(&(version + 1).to_le_bytes());
+        assert!(matches!(
+            handheld.load_state(&wrong_version),
+            Err(StateError::UnsupportedVersion(v)) if v == version + 1
+        ));
+        Snapshot::take(&mut handheld, &frame).assert_same(&expected);
+        assert!(handheld.save_state() == expected_state);
+    }
+
+    /// Counts the external clock with T0, forever
+    #[rustfmt::skip]
+    const EXTERNAL_CLOCK_PROGRAM: &[u8] = &[
+        0x64, 0x20,             // $4000: STZ T0CL
+        0xA9, 0x70,             // $4002: LDA #$70
+        0x85, 0x21,             // $4004: STA T0CH      ; external clock
+        0xA9, 0x01,             // $4006: LDA #$01
+        0x85, 0x28,             // $4008: STA TIEN      ; start T0
+        0x80, 0xFE,             // $400A: BRA $400A
+    ];
+
+    fn t0_count(handheld: &mut Handheld) -> u16 {
+        let address_space = &mut handheld.mcu.core.address_space;
+        let low = address_space.read_u8(0x20);
+        let high = address_space.read_u8(0x21) & 0x0F;
+        u16::from(high) << 8 | u16::from(low)
+    }
+
+    /// Runs to `cycle`, checking that T0 keeps its count until then
+    fn run_counting(handheld: &mut Handheld, cycle: u64, count: u16) {
+        while handheld.mcu.core.cycles < cycle {
+            assert_eq!(
+                t0_count(handheld),
+                count,
+                "Cycle {}",
+                handheld.mcu.core.cycles
+            );
+            handheld.mcu.step();
+        }
+    }
+
+    #[test]
+    fn external_clock_edges_are_counted_when_they_happen() {
+        let gpio = Box::new(ExternalClock::new(Box::new(NoInput), 1000));
+        let (mut handheld, _) = make_handheld_running(EXTERNAL_CLOCK_PROGRAM, gpio);
+
+        // Each edge is counted by the end of the instruction it happens in,
+        // rather than the next time the buttons are polled
+        run_counting(&mut handheld, 1000, 0);
+        run_counting(&mut handheld, 2000, 1);
+        run_counting(&mut handheld, 2500, 2);
+
+        let state = handheld.save_state();
+        handheld.run_until(5000);
+        assert_eq!(t0_count(&mut handheld), 5);
+
+        // Edges carry on from the restored cycle
+        handheld.load_state(&state).unwrap();
+        run_counting(&mut handheld, 3000, 2);
+        assert_eq!(t0_count(&mut handheld), 3);
+    }
+
+    #[test]
+    fn internal_lcd_replaces_the_st7626_on_the_screen() {
+        let (mut handheld, frame) = make_handheld();
+        handheld.show_internal_lcd(16, 8);
+        handheld.run_until(20_000);
+        assert!(frame.0.borrow().is_empty(), "An ST7626 frame was shown");
+
+        // A 16 by 8 frame at $1000 with only the top line set
+        let address_space = &mut handheld.mcu.core.address_space;
+        for (offset, byte) in [0xFF, 0xFF].into_iter().chain([0x00; 14]).enumerate() {
+            address_space.write_u8(0x1000 + offset, byte);
+        }
+        address_space.write_u8(0x40, 0x00); // LSSAL
+        address_space.write_u8(0x41, 0x10); // LSSAH
+        address_space.write_u8(0x43, 16); // LXMAX
+        address_space.write_u8(0x44, 8); // LYMAX
+        address_space.write_u8(0x47, 0x80); // LCTR: enabled, 1 bit per pixel
+        handheld.run_until(40_000);
+
+        let pixels = frame.0.borrow().clone();
+        assert_eq!(pixels.len(), 16 * 8);
+        let (top, rest) = pixels.split_at(16);
+        assert!(top.iter().all(|pixel| *pixel == top[0]));
+        assert!(rest.iter().all(|pixel| *pixel == rest[0]));
+        assert!(top[0] != rest[0]);
+    }
 }
@@ -1,6 +1,9 @@
 mod handheld;
+mod spi_memory;
 mod sst39vf1681;
 mod st2205u;
 mod st7626;
 
-pub use handheld::Handheld;
+pub use handheld::{Handheld, CYCLES_PER_SECOND};
+pub use spi_memory::{SpiMemory, SpiMemoryKind};
+pub use st7626::{LCD_HEIGHT, LCD_WIDTH};
@@ -0,0 +1,381 @@
+use crate::miuchiz::st2205u::SpiDevice;
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
+
+/// Largest memory which 3 address bytes can reach
+const MAX_CAPACITY: usize = 1 << 24;
+
+const EEPROM_PAGE_SIZE: usize = 32;
+const FLASH_PAGE_SIZE: usize = 256;
+const FLASH_SECTOR_SIZE: usize = 0x1000;
+const FLASH_BLOCK_SIZE: usize = 0x10000;
+
+/// JEDEC manufacturer and memory type reported by flash, followed by the capacity
+const FLASH_JEDEC_ID: [u8; 2] = [0xEF, 0x40];
+
+// Commands shared by 25 series EEPROMs and flash
+const WRSR: u8 = 0x01;
+const PROGRAM: u8 = 0x02;
+const READ: u8 = 0x03;
+const WRDI: u8 = 0x04;
+const RDSR: u8 = 0x05;
+const WREN: u8 = 0x06;
+
+// Flash only commands
+const FAST_READ: u8 = 0x0B;
+const SECTOR_ERASE: u8 = 0x20;
+const CHIP_ERASE: u8 = 0x60;
+const JEDEC_ID: u8 = 0x9F;
+const CHIP_ERASE_ALT: u8 = 0xC7;
+const BLOCK_ERASE: u8 = 0xD8;
+
+// Status register bits
+const STATUS_WRITE_ENABLE: u8 = 1 << 1;
+/// Block protect bits, which are kept but do not stop writes
+const STATUS_WRITABLE: u8 = 0b1000_1100;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum SpiMemoryKind {
+    /// A 25 series serial EEPROM. Bytes can be written without erasing them first.
+    Eeprom,
+    /// A 25 series serial flash. Programming can only clear bits, so it must be erased.
+    Flash,
+}
+
+/// Serial EEPROM or flash which speaks the common 25 series command set.
+///
+/// Writes and erases finish as soon as they are issued, so the status register
+/// never shows the memory as busy.
+pub struct SpiMemory {
+    kind: SpiMemoryKind,
+    data: Vec<u8>,
+    status: u8,
+
+    selected: bool,
+    /// The command sent first since the memory was selected
+    command: Option<u8>,
+    /// Bytes received since the command
+    operand_bytes: usize,
+    address: usize,
+}
+
+impl SpiMemory {
+    pub fn new(kind: SpiMemoryKind, data: &[u8]) -> Result<Self, String> {
+        if data.is_empty() || data.len() > MAX_CAPACITY {
+            return Err(format!(
+                "The SPI memory is {} bytes, but must be between 1 and {MAX_CAPACITY} bytes",
+                data.len()
+            ));
+        }
+
+        Ok(Self {
+            kind,
+            data: data.to_vec(),
+            status: 0,
+            selected: false,
+            command: None,
+            operand_bytes: 0,
+            address: 0,
+        })
+    }
+
+    /// Small EEPROMs take 2 address bytes, and everything else 3
+    fn address_bytes(&self) -> usize {
+        if self.kind == SpiMemoryKind::Eeprom && self.data.len() <= 0x10000 {
+            2
+        } else {
+            3
+        }
+    }
+
+    fn page_size(&self) -> usize {
+        match self.kind {
+            SpiMemoryKind::Eeprom => EEPROM_PAGE_SIZE,
+            SpiMemoryKind::Flash => FLASH_PAGE_SIZE,
+        }
+    }
+
+    fn write_enabled(&self) -> bool {
+        self.status & STATUS_WRITE_ENABLE != 0
+    }
+
+    fn erase(&mut self, start: usize, size: usize) {
+        let start = start / size * size;
+        for offset in 0..size {
+            let address = (start + offset) % self.data.len();
+            self.data[address] = 0xFF;
+        }
+    }
+
+    fn program(&mut self, value: u8) {
+        // Writes stay within the page they started in
+        let page_size = self.page_size();
+        let page = self.address / page_size * page_size;
+        let offset = (self.address + self.operand_bytes - self.address_bytes() - 1) % page_size;
+        let address = (page + offset) % self.data.len();
+
+        self.data[address] = match self.kind {
+            SpiMemoryKind::Eeprom => value,
+            SpiMemoryKind::Flash => self.data[address] & value,
+        };
+    }
+
+    fn read(&mut self) -> u8 {
+        let value = self.data[self.address % self.data.len()];
+        self.address = (self.address + 1) % self.data.len();
+        value
+    }
+
+    /// Handles a byte following `command`, returning the byte to send back
+    fn command_byte(&mut self, command: u8, value: u8) -> u8 {
+        let address_bytes = self.address_bytes();
+        let flash = self.kind == SpiMemoryKind::Flash;
+
+        // The address comes first, most significant byte first
+        let takes_address = matches!(command, READ | PROGRAM)
+            || (flash && matches!(command, FAST_READ | SECTOR_ERASE | BLOCK_ERASE));
+        if takes_address && self.operand_bytes <= address_bytes {
+            self.address = (self.address << 8 | value as usize) % (1 << (8 * address_bytes));
+
+            if self.operand_bytes == address_bytes && self.write_enabled() {
+                match command {
+                    SECTOR_ERASE => self.erase(self.address, FLASH_SECTOR_SIZE),
+                    BLOCK_ERASE => self.erase(self.address, FLASH_BLOCK_SIZE),
+                    _ => {}
+                }
+            }
+            return 0xFF;
+        }
+
+        match command {
+            READ => self.read(),
+            // A dummy byte comes between the address and the data
+            FAST_READ if flash && self.operand_bytes == address_bytes + 1 => 0xFF,
+            FAST_READ if flash => self.read(),
+            PROGRAM => {
+                if self.write_enabled() {
+                    self.program(value);
+                }
+                0xFF
+            }
+            RDSR => self.status,
+            WRSR if self.operand_bytes == 1 => {
+                if self.write_enabled() {
+                    self.status = (self.status & !STATUS_WRITABLE) | (value & STATUS_WRITABLE);
+                }
+                0xFF
+            }
+            JEDEC_ID if flash => match self.operand_bytes {
+                1 | 2 => FLASH_JEDEC_ID[self.operand_bytes - 1],
+                3 => self.data.len().next_power_of_two().trailing_zeros() as u8,
+                _ => 0xFF,
+            },
+            _ => 0xFF,
+        }
+    }
+}
+
+impl SpiDevice for SpiMemory {
+    fn select(&mut self, selected: bool) {
+        // Commands which change the contents use up the write enable once they end
+        let writes = matches!(
+            self.command,
+            Some(PROGRAM | WRSR | SECTOR_ERASE | BLOCK_ERASE | CHIP_ERASE | CHIP_ERASE_ALT)
+        );
+        if self.selected && !selected && writes {
+            self.status &= !STATUS_WRITE_ENABLE;
+        }
+
+        self.selected = selected;
+        self.command = None;
+        self.operand_bytes = 0;
+        self.address = 0;
+    }
+
+    fn exchange(&mut self, value: u8) -> u8 {
+        if !self.selected {
+            return 0xFF;
+        }
+
+        let Some(command) = self.command else {
+            self.command = Some(value);
+            match value {
+                WREN => self.status |= STATUS_WRITE_ENABLE,
+                WRDI => self.status &= !STATUS_WRITE_ENABLE,
+                CHIP_ERASE | CHIP_ERASE_ALT
+                    if self.kind == SpiMemoryKind::Flash && self.write_enabled() =>
+                {
+                    self.data.fill(0xFF);
+                }
+                _ => {}
+            }
+            return 0xFF;
+        };
+
+        self.operand_bytes += 1;
+        self.command_byte(command, value)
+    }
+
+    fn storage(&self) -> Option<&[u8]> {
+        Some(&self.data)
+    }
+}
+
+impl SaveState for SpiMemory {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u32(self.data.len() as u32);
+        state.write_bytes(&self.data);
+        state.write_u8(self.status);
+        state.write_bool(self.selected);
+        state.write_bool(self.command.is_some());
+        state.write_u8(self.command.unwrap_or_default());
+        state.write_u32(self.operand_bytes as u32);
+        state.write_u32(self.address as u32);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        if state.read_u32()? as usize != self.data.len() {
+            return Err(StateError::InvalidValue("SPI memory size"));
+        }
+        state.read_bytes(&mut self.data)?;
+        self.status = state.read_u8()?;
+        self.selected = state.read_bool()?;
+        let has_command = state.read_bool()?;
+        let command = state.read_u8()?;
+        self.command = has_command.then_some(command);
+        self.operand_bytes = state.read_u32()? as usize;
+        self.address = state.read_u32()? as usize;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Selects the memory, exchanges `bytes` and deselects it, returning what came back
+    fn transaction(memory: &mut SpiMemory, bytes: &[u8]) -> Vec<u8> {
+        memory.select(true);
+        let received = bytes.iter().map(|&byte| memory.exchange(byte)).collect();
+        memory.select(false);
+        received
+    }
+
+    fn status(memory: &mut SpiMemory) -> u8 {
+        transaction(memory, &[RDSR, 0])[1]
+    }
+
+    fn flash(data: &[u8]) -> SpiMemory {
+        SpiMemory::new(SpiMemoryKind::Flash, data).unwrap()
+    }
+
+    #[test]
+    fn rdsr_shows_the_write_enable_latch() {
+        let mut memory = flash(&[0xFF; 0x10000]);
+        assert_eq!(status(&mut memory), 0);
+
+        transaction(&mut memory, &[WREN]);
+        assert_eq!(status(&mut memory), STATUS_WRITE_ENABLE);
+
+        transaction(&mut memory, &[WRDI]);
+        assert_eq!(status(&mut memory), 0);
+    }
+
+    #[test]
+    fn writes_need_write_enable_and_use_it_up() {
+        let mut memory = flash(&[0xFF; 0x10000]);
+
+        transaction(&mut memory, &[PROGRAM, 0x00, 0x01, 0x00, 0x12]);
+        assert_eq!(memory.data[0x100], 0xFF);
+
+        transaction(&mut memory, &[WREN]);
+        transaction(&mut memory, &[PROGRAM, 0x00, 0x01, 0x00, 0x12]);
+        assert_eq!(memory.data[0x100], 0x12);
+        assert_eq!(status(&mut memory), 0);
+
+        transaction(&mut memory, &[PROGRAM, 0x00, 0x01, 0x01, 0x34]);
+        assert_eq!(memory.data[0x101], 0xFF);
+    }
+
+    #[test]
+    fn page_programs_wrap_within_the_page() {
+        let mut memory = flash(&[0xFF; 0x10000]);
+        transaction(&mut memory, &[WREN]);
+        transaction(&mut memory, &[PROGRAM, 0x00, 0x01, 0xFE, 1, 2, 3, 4]);
+
+        assert_eq!(memory.data[0x1FE..0x200], [1, 2]);
+        assert_eq!(memory.data[0x100..0x102], [3, 4]);
+        assert_eq!(memory.data[0x200], 0xFF);
+    }
+
+    #[test]
+    fn flash_programming_only_clears_bits() {
+        let mut memory = flash(&[0xFF; 0x10000]);
+        for value in [0b1100_1100, 0b1010_1010] {
+            transaction(&mut memory, &[WREN]);
+            transaction(&mut memory, &[PROGRAM, 0, 0, 0, value]);
+        }
+        assert_eq!(memory.data[0], 0b1000_1000);
+    }
+
+    #[test]
+    fn eeprom_writes_replace_bytes_within_32_byte_pages() {
+        // Small EEPROMs take 2 address bytes
+        let mut memory = SpiMemory::new(SpiMemoryKind::Eeprom, &[0x55; 0x2000]).unwrap();
+        transaction(&mut memory, &[WREN]);
+        transaction(&mut memory, &[PROGRAM, 0x00, 0x3F, 0xAA, 0x00]);
+
+        assert_eq!(memory.data[0x3F], 0xAA);
+        assert_eq!(memory.data[0x20], 0x00);
+        assert_eq!(memory.data[0x40], 0x55);
+    }
+
+    #[test]
+    fn sector_and_block_erases_clear_only_their_range() {
+        let mut memory = flash(&[0; 0x40000]);
+
+        transaction(&mut memory, &[SECTOR_ERASE, 0x00, 0x12, 0x34]);
+        assert_eq!(memory.data[0x1234], 0);
+
+        transaction(&mut memory, &[WREN]);
+        transaction(&mut memory, &[SECTOR_ERASE, 0x00, 0x12, 0x34]);
+        assert!(memory.data[0x1000..0x2000].iter().all(|&byte| byte == 0xFF));
+        assert_eq!(memory.data[0x0FFF], 0);
+        assert_eq!(memory.data[0x2000], 0);
+        assert_eq!(status(&mut memory), 0);
+
+        transaction(&mut memory, &[WREN]);
+        transaction(&mut memory, &[BLOCK_ERASE, 0x02, 0x00, 0x00]);
+        assert!(memory.data[0x20000..0x30000]
+            .iter()
+            .all(|&byte| byte == 0xFF));
+        assert_eq!(memory.data[0x1FFFF], 0);
+        assert_eq!(memory.data[0x30000], 0);
+    }
+
+    #[test]
+    fn reads_continue_through_the_memory() {
+        let data: Vec<u8> = (0..=255).cycle().take(0x10000).collect();
+        let mut memory = flash(&data);
+
+        let read = transaction(&mut memory, &[READ, 0x00, 0xFF, 0xFE, 0, 0, 0]);
+        assert_eq!(read[4..], [0xFE, 0xFF, 0x00]);
+
+        // Fast reads have a dummy byte after the address
+        let read = transaction(&mut memory, &[FAST_READ, 0x00, 0x00, 0x10, 0, 0, 0]);
+        assert_eq!(read[5..], [0x10, 0x11]);
+    }
+
+    #[test]
+    fn jedec_id_reports_the_capacity() {
+        let mut memory = flash(&[0xFF; 0x100000]);
+        let id = transaction(&mut memory, &[JEDEC_ID, 0, 0, 0]);
+        assert_eq!(id[1..], [0xEF, 0x40, 0x14]);
+    }
+
+    #[test]
+    fn ignores_bytes_while_deselected() {
+        let mut memory = flash(&[0xFF; 0x10000]);
+        assert_eq!(memory.exchange(WREN), 0xFF);
+        assert_eq!(status(&mut memory), 0);
+    }
+}
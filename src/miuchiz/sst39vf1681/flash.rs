@@ -1,6 +1,7 @@
 use std::cmp::PartialEq;
 
 use crate::memory::AddressSpace;
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
 
 const CHIP_CAPACITY: usize = 0x200000;
 const SECTOR_SIZE: usize = 0x1000;
@@ -135,7 +136,7 @@ impl AddressSpace for Flash {
                 // println!("Chip erase");
                 self.chip_erase();
             } else {
-                println!("Invalid erase command: {address:X} {value:02X}");
+                eprintln!("Invalid erase command: {address:X} {value:02X}");
             }
         } else if self.command_writes.ends_with(&BYTE_PROGRAM) {
             // println!("Program byte {address:X} to {value:02X}");
@@ -152,6 +153,61 @@ impl AddressSpace for Flash {
     }
 }
 
+impl SaveState for Flash {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bytes(self.data.as_ref());
+
+        match self.read_mode {
+            ReadMode::Status { address } => {
+                state.write_bool(true);
+                state.write_u64(address as u64);
+            }
+            ReadMode::Data => state.write_bool(false),
+        }
+
+        state.write_u8(self.command_writes.index as u8);
+        for command_write in &self.command_writes.data {
+            match command_write {
+                Some(CommandWrite { address, value }) => {
+                    state.write_bool(true);
+                    state.write_u64(*address as u64);
+                    state.write_u8(*value);
+                }
+                None => state.write_bool(false),
+            }
+        }
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.read_bytes(self.data.as_mut())?;
+
+        self.read_mode = if state.read_bool()? {
+            ReadMode::Status {
+                address: state.read_u64()? as usize,
+            }
+        } else {
+            ReadMode::Data
+        };
+
+        let index = state.read_u8()? as usize;
+        if index >= self.command_writes.size() {
+            return Err(StateError::InvalidValue("flash command index"));
+        }
+        self.command_writes.index = index;
+        for command_write in &mut self.command_writes.data {
+            *command_write = if state.read_bool()? {
+                Some(CommandWrite {
+                    address: state.read_u64()? as usize,
+                    value: state.read_u8()?,
+                })
+            } else {
+                None
+            };
+        }
+        Ok(())
+    }
+}
+
 struct RingBuf<const N: usize, T: Copy + PartialEq> {
     data: [Option<T>; N],
     index: usize,
@@ -1,15 +1,24 @@
 use super::bank;
 use super::base_timer;
+use super::clock::Clock;
 use super::dma;
 use super::gpio;
 use super::interrupt;
+use super::lcd;
 use super::psg;
 use super::psg::PsgChannel;
+use super::rtc;
+use super::scheduler::{Event, Scheduler};
+use super::spi;
 use super::timer;
 use super::timer::TimerIndex;
-use super::wdc_65c02::HandlesInterrupt;
+use super::uart;
+use super::usb;
+use super::vector;
+use super::wdc_65c02::{HandlesInterrupt, CYCLE_FREQUENCY_DIVISOR};
 use crate::gpio::GpioInterface;
 use crate::memory::AddressSpace;
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
 
 pub const OTP_SIZE: usize = 0x4000;
 pub type Otp = [u8; OTP_SIZE];
@@ -97,6 +106,8 @@ const TIEN: u16 = 0x0028;
 const BTEN: u16 = 0x002A;
 const BTREQ: u16 = 0x002B;
 const BTC: u16 = 0x002C;
+const T4CL: u16 = 0x002D;
+const T4CH: u16 = 0x002E;
 
 const IRRL: u16 = 0x0030;
 const IRRH: u16 = 0x0031;
@@ -114,9 +125,28 @@ const IREQH: u16 = 0x003D;
 const IENAL: u16 = 0x003E;
 const IENAH: u16 = 0x003F;
 
+const LSSAL: u16 = 0x0040;
+const LSSAH: u16 = 0x0041;
+const LVPW: u16 = 0x0042;
+const LXMAX: u16 = 0x0043;
+const LYMAX: u16 = 0x0044;
+const LPAN: u16 = 0x0045;
+
+const LCTR: u16 = 0x0047;
+const LCKR: u16 = 0x0048;
+const LFRA: u16 = 0x0049;
+const LAC: u16 = 0x004A;
+const LPWM: u16 = 0x004B;
+
 const PL: u16 = 0x004E;
 const PCL: u16 = 0x004F;
 
+const SCTR: u16 = 0x0052;
+const SCKR: u16 = 0x0053;
+const SSR: u16 = 0x0054;
+const SMOD: u16 = 0x0055;
+const SDATA: u16 = 0x0056;
+
 const DPRTL: u16 = 0x0058;
 const DPRTH: u16 = 0x0059;
 const DBKRL: u16 = 0x005A;
@@ -126,13 +156,45 @@ const DCNTH: u16 = 0x005D;
 const DSEL: u16 = 0x005E;
 const DMOD: u16 = 0x005F;
 
+const UCTR: u16 = 0x0060;
+const USR: u16 = 0x0061;
+const IRCTR: u16 = 0x0062;
+const BCTR: u16 = 0x0063;
+const UDATA: u16 = 0x0064;
+
+const BRS: u16 = 0x0066;
+const BDIV: u16 = 0x0067;
+
 const MULL: u16 = 0x006E;
 const MULH: u16 = 0x006F;
 
+const USBCON: u16 = 0x0070;
+const USBIEN: u16 = 0x0071;
+const USBIREQ: u16 = 0x0072;
+const USBADDR: u16 = 0x0073;
+const EPINDEX: u16 = 0x0074;
+const EPCTL: u16 = 0x0075;
+const EPCNT: u16 = 0x0076;
+const EPFIFO: u16 = 0x0077;
+
+const SEC: u16 = 0x0078;
+const MIN: u16 = 0x0079;
+const HOUR: u16 = 0x007A;
+const DAYL: u16 = 0x007B;
+const DAYH: u16 = 0x007C;
+const ALMM: u16 = 0x007D;
+const ALMH: u16 = 0x007E;
+const RTCC: u16 = 0x007F;
+
+/// The hardware attached to the ST2205U's external bus
+pub trait MachineAddressSpace: AddressSpace + SaveState + Clock {}
+
+impl<T: AddressSpace + SaveState + Clock> MachineAddressSpace for T {}
+
 pub struct St2205uAddressSpace {
     /// St2205uAddressSpace is 16 bits, but it can itself be used to access a
     /// larger address space through the use of its memory bank registers.
-    pub machine_addr_space: Box<dyn AddressSpace>,
+    pub machine_addr_space: Box<dyn MachineAddressSpace>,
 
     ram: Ram,
 
@@ -142,12 +204,18 @@ pub struct St2205uAddressSpace {
     pub base_timer: base_timer::State,
     pub timer: timer::TimerBlocksState,
     pub psg: psg::State,
+    pub uart: uart::State,
+    pub spi: spi::State,
+    pub usb: usb::State,
+    pub rtc: rtc::State,
+    pub lcd: lcd::State,
     pub interrupt: interrupt::State,
+    pub scheduler: Scheduler,
 }
 
 impl St2205uAddressSpace {
     pub fn new(
-        machine_addr_space: Box<dyn AddressSpace>,
+        machine_addr_space: Box<dyn MachineAddressSpace>,
         io: Box<dyn GpioInterface>,
         frequency: u64,
     ) -> Self {
@@ -159,12 +227,63 @@ impl St2205uAddressSpace {
             dma: dma::State::new(),
             gpio: gpio::State::new(io),
             base_timer: base_timer::State::new(frequency),
-            timer: timer::TimerBlocksState::new(),
+            timer: timer::TimerBlocksState::new(frequency / CYCLE_FREQUENCY_DIVISOR),
             psg: psg::State::new(),
+            uart: uart::State::new(),
+            spi: spi::State::new(),
+            usb: usb::State::new(),
+            rtc: rtc::State::new(frequency),
+            lcd: lcd::State::new(),
             interrupt: interrupt::State::new(),
+            scheduler: Scheduler::new(),
         }
     }
 
+    /// Schedules the next timer overflow, which moves whenever a timer is changed
+    pub fn schedule_timer_overflow(&mut self) {
+        let next_overflow = self.timer.next_overflow_tick();
+        self.scheduler.schedule(Event::TimerOverflow, next_overflow);
+    }
+
+    /// Schedules the UART's next transfer, which moves whenever it is written to
+    pub fn schedule_uart(&mut self) {
+        let next_event = self.uart.next_event_cycle();
+        self.scheduler.schedule(Event::Uart, next_event);
+    }
+
+    /// Schedules the SPI's next transfer, which moves whenever it is written to
+    pub fn schedule_spi(&mut self) {
+        let next_event = self.spi.next_event_cycle();
+        self.scheduler.schedule(Event::Spi, next_event);
+    }
+
+    /// Schedules the USB controller's next frame, which moves whenever it is written to
+    pub fn schedule_usb(&mut self) {
+        let next_event = self.usb.next_event_cycle();
+        self.scheduler.schedule(Event::Usb, next_event);
+    }
+
+    /// Schedules the RTC's next second, which moves whenever its time is set or it is started
+    pub fn schedule_rtc(&mut self) {
+        let next_tick = self
+            .rtc
+            .next_tick()
+            .map(|tick| tick.div_ceil(CYCLE_FREQUENCY_DIVISOR));
+        self.scheduler.schedule(Event::Rtc, next_tick);
+    }
+
+    /// Schedules the end of the LCD controller's frame, which moves whenever its timing is changed
+    pub fn schedule_lcd(&mut self) {
+        let next_event = self.lcd.next_event_cycle();
+        self.scheduler.schedule(Event::Lcd, next_event);
+    }
+
+    /// Lets the LCD controller read its frame buffer from RAM. Returns whether
+    /// to request the LcdBuffer interrupt.
+    pub fn update_lcd(&mut self) -> bool {
+        self.lcd.update(&self.ram)
+    }
+
     fn read_register(&mut self, address: u16) -> u8 {
         // println!("Read from register {address:X}");
         match address {
@@ -218,8 +337,21 @@ impl St2205uAddressSpace {
             T2CH => self.timer.read_txch(TimerIndex::T2),
             T3CL => self.timer.read_txcl(TimerIndex::T3),
             T3CH => self.timer.read_txch(TimerIndex::T3),
+            T4CL => self.timer.read_txcl(TimerIndex::T4),
+            T4CH => self.timer.read_txch(TimerIndex::T4),
             TIEN => self.timer.read_tien(),
             PMCR => gpio::read_pmcr(&self.gpio),
+            LSSAL => self.lcd.read_lssal(),
+            LSSAH => self.lcd.read_lssah(),
+            LVPW => self.lcd.read_lvpw(),
+            LXMAX => self.lcd.read_lxmax(),
+            LYMAX => self.lcd.read_lymax(),
+            LPAN => self.lcd.read_lpan(),
+            LCTR => self.lcd.read_lctr(),
+            LCKR => self.lcd.read_lckr(),
+            LFRA => self.lcd.read_lfra(),
+            LAC => self.lcd.read_lac(),
+            LPWM => self.lcd.read_lpwm(),
             PL => gpio::read_pl(&self.gpio),
             PCL => gpio::read_pcl(&self.gpio),
             BTEN => base_timer::read_bten(&self.base_timer),
@@ -229,8 +361,36 @@ impl St2205uAddressSpace {
             IREQH => interrupt::read_ireqh(&self.interrupt),
             IENAL => interrupt::read_ienal(&self.interrupt),
             IENAH => interrupt::read_ienah(&self.interrupt),
+            SCTR => self.spi.read_sctr(),
+            SCKR => self.spi.read_sckr(),
+            SSR => self.spi.read_ssr(),
+            SMOD => self.spi.read_smod(),
+            SDATA => self.spi.read_sdata(),
+            UCTR => self.uart.read_uctr(),
+            USR => self.uart.read_usr(),
+            IRCTR => self.uart.read_irctr(),
+            BCTR => self.uart.read_bctr(),
+            UDATA => self.uart.read_udata(),
+            BRS => self.uart.read_brs(),
+            BDIV => self.uart.read_bdiv(),
             MULL => self.psg.read_mull(),
             MULH => self.psg.read_mulh(),
+            USBCON => self.usb.read_usbcon(),
+            USBIEN => self.usb.read_usbien(),
+            USBIREQ => self.usb.read_usbireq(),
+            USBADDR => self.usb.read_usbaddr(),
+            EPINDEX => self.usb.read_epindex(),
+            EPCTL => self.usb.read_epctl(),
+            EPCNT => self.usb.read_epcnt(),
+            EPFIFO => self.usb.read_epfifo(),
+            SEC => self.rtc.read_sec(),
+            MIN => self.rtc.read_min(),
+            HOUR => self.rtc.read_hour(),
+            DAYL => self.rtc.read_dayl(),
+            DAYH => self.rtc.read_dayh(),
+            ALMM => self.rtc.read_almm(),
+            ALMH => self.rtc.read_almh(),
+            RTCC => self.rtc.read_rtcc(),
             _ => {
                 // println!("Unimplemented read of register {address:02X}");
                 0
@@ -295,8 +455,21 @@ impl St2205uAddressSpace {
             T2CH => self.timer.write_txch(TimerIndex::T2, value),
             T3CL => self.timer.write_txcl(TimerIndex::T3, value),
             T3CH => self.timer.write_txch(TimerIndex::T3, value),
+            T4CL => self.timer.write_txcl(TimerIndex::T4, value),
+            T4CH => self.timer.write_txch(TimerIndex::T4, value),
             TIEN => self.timer.write_tien(value),
             PMCR => gpio::write_pmcr(&mut self.gpio, value),
+            LSSAL => self.lcd.write_lssal(value),
+            LSSAH => self.lcd.write_lssah(value),
+            LVPW => self.lcd.write_lvpw(value),
+            LXMAX => self.lcd.write_lxmax(value),
+            LYMAX => self.lcd.write_lymax(value),
+            LPAN => self.lcd.write_lpan(value),
+            LCTR => self.lcd.write_lctr(value),
+            LCKR => self.lcd.write_lckr(value),
+            LFRA => self.lcd.write_lfra(value),
+            LAC => self.lcd.write_lac(value),
+            LPWM => self.lcd.write_lpwm(value),
             PL => gpio::write_pl(&mut self.gpio, value),
             PCL => gpio::write_pcl(&mut self.gpio, value),
             BTEN => base_timer::write_bten(&mut self.base_timer, value),
@@ -306,12 +479,67 @@ impl St2205uAddressSpace {
             IREQH => interrupt::write_ireqh(&mut self.interrupt, value),
             IENAL => interrupt::write_ienal(&mut self.interrupt, value),
             IENAH => interrupt::write_ienah(&mut self.interrupt, value),
+            SCTR => self.spi.write_sctr(value),
+            SCKR => self.spi.write_sckr(value),
+            SSR => self.spi.write_ssr(value),
+            SMOD => self.spi.write_smod(value),
+            SDATA => self.spi.write_sdata(value),
+            UCTR => self.uart.write_uctr(value),
+            USR => self.uart.write_usr(value),
+            IRCTR => self.uart.write_irctr(value),
+            BCTR => self.uart.write_bctr(value),
+            UDATA => self.uart.write_udata(value),
+            BRS => self.uart.write_brs(value),
+            BDIV => self.uart.write_bdiv(value),
             MULL => self.psg.write_mull(value),
             MULH => self.psg.write_mulh(value),
+            USBCON => self.usb.write_usbcon(value),
+            USBIEN => self.usb.write_usbien(value),
+            USBIREQ => self.usb.write_usbireq(value),
+            USBADDR => self.usb.write_usbaddr(value),
+            EPINDEX => self.usb.write_epindex(value),
+            EPCTL => self.usb.write_epctl(value),
+            EPCNT => self.usb.write_epcnt(value),
+            EPFIFO => self.usb.write_epfifo(value),
+            SEC => self.rtc.write_sec(value),
+            MIN => self.rtc.write_min(value),
+            HOUR => self.rtc.write_hour(value),
+            DAYL => self.rtc.write_dayl(value),
+            DAYH => self.rtc.write_dayh(value),
+            ALMM => self.rtc.write_almm(value),
+            ALMH => self.rtc.write_almh(value),
+            RTCC => self.rtc.write_rtcc(value),
             _ => {
-                println!("Unimplemented write of register {address:02X}");
+                eprintln!("Unimplemented write of register {address:02X}");
             }
         }
+
+        if matches!(
+            address,
+            T0CL | T0CH | T1CL | T1CH | T2CL | T2CH | T3CL | T3CH | T4CL | T4CH | TIEN
+        ) {
+            self.schedule_timer_overflow();
+        }
+
+        if matches!(address, UCTR | BCTR | UDATA | BRS | BDIV) {
+            self.schedule_uart();
+        }
+
+        if matches!(address, SCTR | SDATA) {
+            self.schedule_spi();
+        }
+
+        if matches!(address, USBCON | EPCTL) {
+            self.schedule_usb();
+        }
+
+        if matches!(address, SEC | MIN | HOUR | DAYL | DAYH | RTCC) {
+            self.schedule_rtc();
+        }
+
+        if matches!(address, LXMAX | LYMAX | LCTR | LCKR | LFRA) {
+            self.schedule_lcd();
+        }
     }
 
     fn read_ram(&self, address: usize) -> u8 {
@@ -324,6 +552,42 @@ impl St2205uAddressSpace {
     }
 }
 
+impl SaveState for St2205uAddressSpace {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bytes(&self.ram);
+        self.banks.save_state(state);
+        self.dma.save_state(state);
+        self.gpio.save_state(state);
+        self.base_timer.save_state(state);
+        self.timer.save_state(state);
+        self.psg.save_state(state);
+        self.uart.save_state(state);
+        self.spi.save_state(state);
+        self.usb.save_state(state);
+        self.rtc.save_state(state);
+        self.lcd.save_state(state);
+        self.interrupt.save_state(state);
+        self.machine_addr_space.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.read_bytes(&mut self.ram)?;
+        self.banks.load_state(state)?;
+        self.dma.load_state(state)?;
+        self.gpio.load_state(state)?;
+        self.base_timer.load_state(state)?;
+        self.timer.load_state(state)?;
+        self.psg.load_state(state)?;
+        self.uart.load_state(state)?;
+        self.spi.load_state(state)?;
+        self.usb.load_state(state)?;
+        self.rtc.load_state(state)?;
+        self.lcd.load_state(state)?;
+        self.interrupt.load_state(state)?;
+        self.machine_addr_space.load_state(state)
+    }
+}
+
 impl HandlesInterrupt for St2205uAddressSpace {
     fn set_interrupted(&mut self, interrupted: bool) {
         self.interrupt.set_interrupted(interrupted);
@@ -332,6 +596,10 @@ impl HandlesInterrupt for St2205uAddressSpace {
     fn interrupted(&self) -> bool {
         self.interrupt.interrupted()
     }
+
+    fn brk_vector(&self) -> u16 {
+        vector::BRK
+    }
 }
 
 impl AddressSpace for St2205uAddressSpace {
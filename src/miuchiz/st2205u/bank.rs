@@ -1,3 +1,4 @@
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
 use crate::{gpio::GpioInterface, memory::AddressSpace};
 
 use super::{reg::U16Register, St2205uAddressSpace};
@@ -118,3 +119,19 @@ pub fn irr(st2205u: &St2205uAddressSpace) -> u16 {
 pub fn set_irr(st2205u: &mut St2205uAddressSpace, value: u16) {
     st2205u.banks.irr.set_u16(value)
 }
+
+impl SaveState for State {
+    fn save_state(&self, state: &mut StateWriter) {
+        self.brr.save_state(state);
+        self.prr.save_state(state);
+        self.irr.save_state(state);
+        self.drr.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.brr.load_state(state)?;
+        self.prr.load_state(state)?;
+        self.irr.load_state(state)?;
+        self.drr.load_state(state)
+    }
+}
@@ -1,6 +1,10 @@
 use super::reg::U8Register;
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
 
-const TIMER_FREQUENCY: u64 = 8192;
+/// Frequency of BGRCK, the slow clock the base timer counts from
+pub const BGRCK_FREQUENCY: u64 = 32768;
+
+const TIMER_FREQUENCY: u64 = BGRCK_FREQUENCY / 4;
 
 pub struct State {
     /// The frequency of the clock source this timer receives
@@ -44,6 +48,11 @@ impl State {
         self.elapsed_ticks = ticks;
     }
 
+    /// The elapsed tick count at which the counter will next advance
+    pub fn next_tick(&self) -> u64 {
+        self.next_counter_tick
+    }
+
     fn update_next_counter_tick(&mut self) {
         self.next_counter_tick =
             ((self.counter + 1) * self.input_clock_frequency) / TIMER_FREQUENCY;
@@ -122,6 +131,25 @@ impl State {
     }
 }
 
+impl SaveState for State {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u64(self.elapsed_ticks);
+        state.write_u64(self.counter);
+        self.btc.save_state(state);
+        self.bten.save_state(state);
+        self.btreq.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.elapsed_ticks = state.read_u64()?;
+        self.counter = state.read_u64()?;
+        self.update_next_counter_tick();
+        self.btc.load_state(state)?;
+        self.bten.load_state(state)?;
+        self.btreq.load_state(state)
+    }
+}
+
 pub fn read_bten(state: &State) -> u8 {
     state.bten.get()
 }
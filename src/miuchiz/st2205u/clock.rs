@@ -7,6 +7,14 @@ pub trait Clock {
 impl Clock for St2205uAddressSpace {
     fn set_clocks(&mut self, oscx: u64, sysck: u64) {
         self.base_timer.set_elapsed_ticks(oscx);
+        self.rtc.set_elapsed_ticks(oscx);
         self.timer.set_elapsed_ticks(sysck);
+        self.psg.set_elapsed_ticks(sysck);
+        self.gpio.set_current_cycle(sysck);
+        self.uart.set_current_cycle(sysck);
+        self.spi.set_current_cycle(sysck);
+        self.usb.set_current_cycle(sysck);
+        self.lcd.set_current_cycle(sysck);
+        self.machine_addr_space.set_clocks(oscx, sysck);
     }
 }
@@ -4,6 +4,7 @@ use super::{
     St2205uAddressSpace,
 };
 use crate::memory::AddressSpace;
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
 
 // DMA channels and function modes are not implemented yet.
 
@@ -87,6 +88,28 @@ impl State {
     }
 }
 
+impl SaveState for State {
+    fn save_state(&self, state: &mut StateWriter) {
+        self.src_dptr.save_state(state);
+        self.dest_dptr.save_state(state);
+        self.src_dbkr.save_state(state);
+        self.dest_dbkr.save_state(state);
+        self.dcnt.save_state(state);
+        self.dsel.save_state(state);
+        self.dmod.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.src_dptr.load_state(state)?;
+        self.dest_dptr.load_state(state)?;
+        self.src_dbkr.load_state(state)?;
+        self.dest_dbkr.load_state(state)?;
+        self.dcnt.load_state(state)?;
+        self.dsel.load_state(state)?;
+        self.dmod.load_state(state)
+    }
+}
+
 pub fn write_dptrl(st2205u: &mut St2205uAddressSpace, val: u8) {
     // println!("Write dptrl {val:02X}");
     let dma = &mut st2205u.dma;
@@ -1,5 +1,6 @@
 use super::reg::U8Register;
-use crate::gpio::{GpioButton, GpioButtonState, GpioInterface};
+use crate::gpio::{GpioButton, GpioButtonState, GpioInterface, GpioPin, GpioPort};
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
 
 pub enum Port {
     A,
@@ -16,9 +17,55 @@ enum PortMode {
     Output,
 }
 
+/// Bit of port B for the pin timers can count as an external clock, PB7
+const EXTERNAL_CLOCK_BIT: u8 = 7;
+
+/// The pin each timer can drive, T0 to T4 in order. A timer drives its pin
+/// when the pin's bit is set in the port's function select register.
+///
+/// These are not checked against the pin function table in the ST2205U
+/// datasheet, which was not available. They follow the function select
+/// registers instead: PFC covers all of port C, and PFD only has bit 1
+/// writable, so T0 to T3 are taken to be on PC4 to PC7 and T4 on PD1.
+const TIMER_OUTPUT_PINS: [GpioPin; 5] = [
+    GpioPin {
+        port: GpioPort::C,
+        bit: 4,
+    },
+    GpioPin {
+        port: GpioPort::C,
+        bit: 5,
+    },
+    GpioPin {
+        port: GpioPort::C,
+        bit: 6,
+    },
+    GpioPin {
+        port: GpioPort::C,
+        bit: 7,
+    },
+    GpioPin {
+        port: GpioPort::D,
+        bit: 1,
+    },
+];
+
+const OUTPUT_PORTS: [GpioPort; 4] = [GpioPort::C, GpioPort::D, GpioPort::E, GpioPort::F];
+
 pub struct State {
     last_state: GpioButtonState,
 
+    /// The cycle of the next rising edge on the external clock pin
+    next_external_clock_edge: Option<u64>,
+
+    /// Output level of each timer, one bit per timer
+    timer_outputs: u8,
+
+    /// Pin levels of each of `OUTPUT_PORTS`, as last told to `io`
+    output_levels: [u8; 4],
+
+    current_cycle: u64,
+
     // Port data registers
     pa: U8Register,
     pb: U8Register,
@@ -79,12 +126,108 @@ impl State {
 
             io,
             last_state: GpioButtonState::default(),
+            next_external_clock_edge: None,
+            timer_outputs: 0,
+            output_levels: [0xFF; 4],
+            current_cycle: 0,
+        }
+    }
+
+    pub fn set_current_cycle(&mut self, current_cycle: u64) {
+        self.current_cycle = current_cycle;
+    }
+
+    /// Sets the output level of each timer, one bit per timer, for the pins they drive
+    pub fn set_timer_outputs(&mut self, outputs: u8) {
+        self.timer_outputs = outputs;
+        self.update_outputs();
+    }
+
+    fn port_registers(&self, port: GpioPort) -> (&U8Register, &U8Register) {
+        match port {
+            GpioPort::C => (&self.pc, &self.pcc),
+            GpioPort::D => (&self.pd, &self.pcd),
+            GpioPort::E => (&self.pe, &self.pce),
+            GpioPort::F => (&self.pf, &self.pcf),
         }
     }
 
+    fn function_select(&self, port: GpioPort) -> u8 {
+        match port {
+            GpioPort::C => self.pfc.get(),
+            GpioPort::D => self.pfd.get(),
+            GpioPort::E | GpioPort::F => 0,
+        }
+    }
+
+    /// The level of each pin of a port. Output pins follow the port's data
+    /// register, or a timer if one is routed there, and other pins are pulled high.
+    fn output_level(&self, port: GpioPort) -> u8 {
+        let (data, direction) = self.port_registers(port);
+        let mut level = (data.get() & direction.get()) | !direction.get();
+
+        for (timer, pin) in TIMER_OUTPUT_PINS.iter().enumerate() {
+            let mask = 1 << pin.bit;
+            if pin.port == port && self.function_select(port) & mask != 0 {
+                let high = self.timer_outputs & (1 << timer) != 0;
+                level = (level & !mask) | if high { mask } else { 0 };
+            }
+        }
+
+        level
+    }
+
+    /// Tells `io` about every pin which has changed level
+    fn update_outputs(&mut self) {
+        for (index, port) in OUTPUT_PORTS.into_iter().enumerate() {
+            let level = self.output_level(port);
+            let changed = level ^ self.output_levels[index];
+            for bit in 0..u8::BITS as u8 {
+                if changed & (1 << bit) != 0 {
+                    let pin = GpioPin { port, bit };
+                    self.io
+                        .output_changed(pin, level & (1 << bit) != 0, self.current_cycle);
+                }
+            }
+            self.output_levels[index] = level;
+        }
+    }
+
+    fn registers(&self) -> [&U8Register; 19] {
+        [
+            &self.pa, &self.pb, &self.pc, &self.pd, &self.pe, &self.pf, &self.pl, &self.psc,
+            &self.pse, &self.pca, &self.pcb, &self.pcc, &self.pcd, &self.pce, &self.pcf, &self.pcl,
+            &self.pfc, &self.pfd, &self.pmcr,
+        ]
+    }
+
+    fn registers_mut(&mut self) -> [&mut U8Register; 19] {
+        [
+            &mut self.pa,
+            &mut self.pb,
+            &mut self.pc,
+            &mut self.pd,
+            &mut self.pe,
+            &mut self.pf,
+            &mut self.pl,
+            &mut self.psc,
+            &mut self.pse,
+            &mut self.pca,
+            &mut self.pcb,
+            &mut self.pcc,
+            &mut self.pcd,
+            &mut self.pce,
+            &mut self.pcf,
+            &mut self.pcl,
+            &mut self.pfc,
+            &mut self.pfd,
+            &mut self.pmcr,
+        ]
+    }
+
     /// Updates the GPIO inputs and returns true if a port a transition occurred
-    pub fn update_gpio_inputs(&mut self) -> bool {
-        let Some(new_state) = self.io.get_updates() else {
+    pub fn update_gpio_inputs(&mut self, current_cycle: u64) -> bool {
+        let Some(new_state) = self.io.get_updates(current_cycle) else {
             return false;
         };
 
@@ -100,9 +243,34 @@ impl State {
         self.last_state = new_state;
         updated
     }
+
+    /// The cycle at which the external clock pin next rises, if it will
+    pub fn next_external_clock_edge(&self) -> Option<u64> {
+        self.next_external_clock_edge
+    }
+
+    /// Looks for the next rising edge on the external clock pin after
+    /// `current_cycle`, forgetting any before it, such as after loading a save state
+    pub fn restart_external_clock(&mut self, current_cycle: u64) {
+        self.next_external_clock_edge = self.io.next_external_clock_edge(current_cycle);
+    }
+
+    /// Returns the number of rising edges on the external clock pin up to
+    /// `current_cycle` which have not been counted yet
+    pub fn take_external_clock_edges(&mut self, current_cycle: u64) -> u64 {
+        let mut edges = 0;
+        while let Some(edge) = self.next_external_clock_edge {
+            if edge > current_cycle {
+                break;
+            }
+            edges += 1;
+            self.next_external_clock_edge = self.io.next_external_clock_edge(edge);
+        }
+        edges
+    }
 }
 
-fn get_input_bit(bit: u32, state: &GpioButtonState) -> bool {
+fn input_button(bit: u32) -> Option<GpioButton> {
     let button = match bit {
         0 => GpioButton::Up,
         1 => GpioButton::Down,
@@ -118,9 +286,41 @@ fn get_input_bit(bit: u32, state: &GpioButtonState) -> bool {
         11 => GpioButton::ScreenBottomRight,
         12 => GpioButton::Action,
         13 => GpioButton::Mute,
-        _ => return false,
+        _ => return None,
     };
-    state.get(button)
+    Some(button)
+}
+
+fn get_input_bit(bit: u32, state: &GpioButtonState) -> bool {
+    input_button(bit).is_some_and(|button| state.get(button))
+}
+
+impl SaveState for State {
+    fn save_state(&self, state: &mut StateWriter) {
+        let mut inputs = 0u16;
+        for bit in 0..u16::BITS {
+            inputs |= (get_input_bit(bit, &self.last_state) as u16) << bit;
+        }
+        state.write_u16(inputs);
+
+        for register in self.registers() {
+            register.save_state(state);
+        }
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        let inputs = state.read_u16()?;
+        for bit in 0..u16::BITS {
+            if let Some(button) = input_button(bit) {
+                self.last_state.set(button, inputs & (1 << bit) != 0);
+            }
+        }
+
+        for register in self.registers_mut() {
+            register.load_state(state)?;
+        }
+        Ok(())
+    }
 }
 
 pub fn read_pa(gpio: &State) -> u8 {
@@ -136,15 +336,22 @@ pub fn read_pb(gpio: &State) -> u8 {
     for i in 0..u8::BITS {
         result |= (get_input_bit(8 + i, &gpio.last_state) as u8) << i;
     }
+    if !gpio.io.external_clock_level(gpio.current_cycle) {
+        result |= 1 << EXTERNAL_CLOCK_BIT;
+    }
     !result
 }
 
 pub fn read_pc(gpio: &State) -> u8 {
-    gpio.pc.get()
+    // Pins driven by a timer read back the timer's output
+    let timer_pins = gpio.function_select(GpioPort::C);
+    (gpio.pc.get() & !timer_pins) | (gpio.output_level(GpioPort::C) & timer_pins)
 }
 
 pub fn read_pd(gpio: &State) -> u8 {
-    gpio.pd.get()
+    // Pins driven by a timer read back the timer's output
+    let timer_pins = gpio.function_select(GpioPort::D);
+    (gpio.pd.get() & !timer_pins) | (gpio.output_level(GpioPort::D) & timer_pins)
 }
 
 pub fn read_pe(gpio: &State) -> u8 {
@@ -208,7 +415,7 @@ pub fn read_pcl(gpio: &State) -> u8 {
 }
 
 pub fn write_pa(gpio: &mut State, value: u8) {
-    println!("Unimplemented write {value:02X} to PA");
+    eprintln!("Unimplemented write {value:02X} to PA");
 }
 
 pub fn write_pb(gpio: &mut State, value: u8) {
@@ -217,18 +424,22 @@ pub fn write_pb(gpio: &mut State, value: u8) {
 
 pub fn write_pc(gpio: &mut State, value: u8) {
     gpio.pc.set(value);
+    gpio.update_outputs();
 }
 
 pub fn write_pd(gpio: &mut State, value: u8) {
     gpio.pd.set(value);
+    gpio.update_outputs();
 }
 
 pub fn write_pe(gpio: &mut State, value: u8) {
     gpio.pe.set(value);
+    gpio.update_outputs();
 }
 
 pub fn write_pf(gpio: &mut State, value: u8) {
     gpio.pf.set(value);
+    gpio.update_outputs();
 }
 
 pub fn write_psc(gpio: &mut State, value: u8) {
@@ -249,26 +460,32 @@ pub fn write_pcb(gpio: &mut State, value: u8) {
 
 pub fn write_pcc(gpio: &mut State, value: u8) {
     gpio.pcc.set(value);
+    gpio.update_outputs();
 }
 
 pub fn write_pcd(gpio: &mut State, value: u8) {
     gpio.pcd.set(value);
+    gpio.update_outputs();
 }
 
 pub fn write_pce(gpio: &mut State, value: u8) {
     gpio.pce.set(value);
+    gpio.update_outputs();
 }
 
 pub fn write_pcf(gpio: &mut State, value: u8) {
     gpio.pcf.set(value);
+    gpio.update_outputs();
 }
 
 pub fn write_pfc(gpio: &mut State, value: u8) {
     gpio.pfc.set(value);
+    gpio.update_outputs();
 }
 
 pub fn write_pfd(gpio: &mut State, value: u8) {
     gpio.pfd.set(value);
+    gpio.update_outputs();
 }
 
 pub fn write_pmcr(gpio: &mut State, value: u8) {
@@ -276,9 +493,112 @@ pub fn write_pmcr(gpio: &mut State, value: u8) {
 }
 
 pub fn write_pl(gpio: &mut State, value: u8) {
-    println!("Unimplemented write {value:02X} to PL");
+    eprintln!("Unimplemented write {value:02X} to PL");
 }
 
 pub fn write_pcl(gpio: &mut State, value: u8) {
     gpio.pcl.set(value);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gpio::ExternalClock;
+    use crate::input_script::{InputScript, ScriptedGpio};
+    use crate::pin_recorder::PinRecorder;
+    use std::cell::RefCell;
+    use std::io::Write;
+    use std::rc::Rc;
+
+    /// Keeps what is written to it where the test can still read it
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn timer_outputs_reach_the_gpio_interface() {
+        let buffer = SharedBuffer::default();
+        let io = ScriptedGpio::new(InputScript::default());
+        let recorder = PinRecorder::new(Box::new(io), buffer.clone()).unwrap();
+        let mut gpio = State::new(Box::new(recorder));
+
+        // Routing T0 to PC4 pulls it low, as T0's output is low
+        gpio.set_current_cycle(100);
+        write_pfc(&mut gpio, 0b0001_0000);
+        gpio.set_current_cycle(200);
+        gpio.set_timer_outputs(0b0_0001);
+        assert_eq!(read_pc(&gpio) & 0b0001_0000, 0b0001_0000);
+
+        // T1 is not routed anywhere, so nothing changes
+        gpio.set_current_cycle(300);
+        gpio.set_timer_outputs(0b0_0011);
+
+        // T4 drives PD1
+        gpio.set_current_cycle(400);
+        write_pfd(&mut gpio, 0b10);
+        gpio.set_current_cycle(500);
+        gpio.set_timer_outputs(0b1_0010);
+
+        // Ports set to output follow their data register
+        gpio.set_current_cycle(600);
+        write_pcc(&mut gpio, 0b0000_0001);
+        write_pc(&mut gpio, 0b0000_0000);
+
+        let text = String::from_utf8(buffer.0.borrow().clone()).unwrap();
+        assert_eq!(
+            text,
+            "# emiu2 pin recording\n\
+             100 PC4 low\n\
+             200 PC4 high\n\
+             400 PD1 low\n\
+             500 PC4 low\n\
+             500 PD1 high\n\
+             600 PC0 low\n"
+        );
+    }
+
+    #[test]
+    fn external_clock_edges_are_counted_by_the_cycle_they_happen_on() {
+        // Rises every 100 cycles
+        let io = ExternalClock::new(Box::new(ScriptedGpio::new(InputScript::default())), 100);
+        let mut gpio = State::new(Box::new(io));
+        gpio.restart_external_clock(0);
+        assert_eq!(gpio.next_external_clock_edge(), Some(100));
+
+        assert_eq!(gpio.take_external_clock_edges(99), 0);
+        assert_eq!(gpio.take_external_clock_edges(100), 1);
+        assert_eq!(gpio.next_external_clock_edge(), Some(200));
+        // Edges missed between calls are still counted
+        assert_eq!(gpio.take_external_clock_edges(450), 3);
+        assert_eq!(gpio.next_external_clock_edge(), Some(500));
+
+        // PB7 follows the pin, high for the first half of each period
+        for (cycle, high) in [(500, true), (549, true), (550, false), (599, false)] {
+            gpio.set_current_cycle(cycle);
+            assert_eq!(read_pb(&gpio) & 0x80 != 0, high, "Cycle {cycle}");
+        }
+
+        // Restarting forgets the edges before it
+        gpio.restart_external_clock(1000);
+        assert_eq!(gpio.take_external_clock_edges(1099), 0);
+        assert_eq!(gpio.take_external_clock_edges(1100), 1);
+    }
+
+    #[test]
+    fn undriven_external_clock_never_rises() {
+        let mut gpio = State::new(Box::new(ScriptedGpio::new(InputScript::default())));
+        gpio.restart_external_clock(0);
+        assert_eq!(gpio.next_external_clock_edge(), None);
+        assert_eq!(gpio.take_external_clock_edges(u64::MAX), 0);
+        assert_eq!(read_pb(&gpio) & 0x80, 0x80);
+    }
+}
@@ -1,5 +1,6 @@
 use super::reg::U16Register;
 use super::wdc_65c02::HandlesInterrupt;
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
 
 #[derive(Debug)]
 pub struct State {
@@ -132,6 +133,23 @@ impl HandlesInterrupt for State {
     }
 }
 
+impl SaveState for State {
+    fn save_state(&self, state: &mut StateWriter) {
+        self.ireq.save_state(state);
+        self.shadow_ireq.save_state(state);
+        self.iena.save_state(state);
+        state.write_bool(self.interrupted);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.ireq.load_state(state)?;
+        self.shadow_ireq.load_state(state)?;
+        self.iena.load_state(state)?;
+        self.interrupted = state.read_bool()?;
+        Ok(())
+    }
+}
+
 pub fn read_ireql(state: &State) -> u8 {
     state.ireq.l()
 }
@@ -0,0 +1,510 @@
+use super::reg::{U16Register, U8Register};
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
+use crate::screen::{Pixel, Screen};
+
+// LCTR bits
+/// Turns the controller on
+const LCTR_ENABLE: u8 = 1 << 7;
+/// Uses 2 bits per pixel for 4 shades of grey, rather than 1 bit per pixel
+const LCTR_GREY: u8 = 1 << 0;
+
+/// Shades from a clear pixel to a fully dark one
+const SHADES: [Pixel; 4] = [
+    Pixel {
+        red: 0xFF,
+        green: 0xFF,
+        blue: 0xFF,
+    },
+    Pixel {
+        red: 0xAA,
+        green: 0xAA,
+        blue: 0xAA,
+    },
+    Pixel {
+        red: 0x55,
+        green: 0x55,
+        blue: 0x55,
+    },
+    Pixel {
+        red: 0x00,
+        green: 0x00,
+        blue: 0x00,
+    },
+];
+
+/// A panel connected to the controller's outputs, with the pixels it has
+struct Panel {
+    screen: Box<dyn Screen>,
+    width: usize,
+    height: usize,
+}
+
+/// The built-in STN LCD controller, which reads a frame buffer from RAM.
+///
+/// A frame is LXMAX pixels by LYMAX lines, and LVPW bytes apart in RAM from
+/// one line to the next, or tightly packed if LVPW is 0. Each line starts
+/// LPAN (0 to 7) pixels into its first byte, and the most significant bits of
+/// a byte are the leftmost pixel. A line takes LXMAX pixel clocks of
+/// LCKR + 1 instruction cycles, and LFRA blank lines follow each frame.
+///
+/// The start address is taken from LSSA as each frame begins, so firmware can
+/// point it at another buffer while a frame is shown. The frame is read from
+/// RAM in one go once its last line is due, at which point the buffer is empty
+/// and the LcdBuffer interrupt is requested.
+pub struct State {
+    panel: Option<Panel>,
+
+    lssa: U16Register,
+    lvpw: U8Register,
+    lxmax: U8Register,
+    lymax: U8Register,
+    lpan: U8Register,
+    lctr: U8Register,
+    lckr: U8Register,
+    lfra: U8Register,
+    /// The panel's drive signals make no difference to the picture, so these are only kept
+    lac: U8Register,
+    lpwm: U8Register,
+
+    /// The start address taken at the beginning of the current frame
+    frame_address: u16,
+    /// The instruction cycle the current frame began on
+    frame_start: u64,
+    current_cycle: u64,
+}
+
+impl State {
+    pub fn new() -> Self {
+        Self {
+            panel: None,
+            lssa: U16Register::new(0x0000, 0x7FFF),
+            lvpw: U8Register::new(0b0000_0000, 0b1111_1111),
+            lxmax: U8Register::new(0b0000_0000, 0b1111_1111),
+            lymax: U8Register::new(0b0000_0000, 0b1111_1111),
+            lpan: U8Register::new(0b0000_0000, 0b0000_0111),
+            lctr: U8Register::new(0b0000_0000, 0b1000_0001),
+            lckr: U8Register::new(0b0000_0000, 0b1111_1111),
+            lfra: U8Register::new(0b0000_0000, 0b1111_1111),
+            lac: U8Register::new(0b0000_0000, 0b1111_1111),
+            lpwm: U8Register::new(0b0000_0000, 0b1111_1111),
+            frame_address: 0,
+            frame_start: 0,
+            current_cycle: 0,
+        }
+    }
+
+    /// Connects a panel of `width` by `height` pixels to show frames on. Frames
+    /// of another size are cut off or left blank at the right and bottom.
+    pub fn connect_panel(&mut self, screen: Box<dyn Screen>, width: usize, height: usize) {
+        self.panel = Some(Panel {
+            screen,
+            width,
+            height,
+        });
+    }
+
+    pub fn set_current_cycle(&mut self, cycle: u64) {
+        self.current_cycle = cycle;
+    }
+
+    fn enabled(&self) -> bool {
+        self.lctr.get() & LCTR_ENABLE != 0
+    }
+
+    fn bits_per_pixel(&self) -> usize {
+        if self.lctr.get() & LCTR_GREY != 0 {
+            2
+        } else {
+            1
+        }
+    }
+
+    /// Instruction cycles from the start of one frame to the next
+    fn frame_cycles(&self) -> u64 {
+        let line_cycles = u64::from(self.lxmax.get().max(1)) * (u64::from(self.lckr.get()) + 1);
+        let lines = u64::from(self.lymax.get().max(1)) + u64::from(self.lfra.get());
+        line_cycles * lines
+    }
+
+    /// Bytes from the start of one line to the next in RAM
+    fn line_stride(&self) -> usize {
+        match self.lvpw.get() {
+            0 => {
+                let bits = (usize::from(self.lpan.get()) + usize::from(self.lxmax.get()))
+                    * self.bits_per_pixel();
+                bits.div_ceil(8)
+            }
+            width => usize::from(width),
+        }
+    }
+
+    /// Shows the frame if it is over and starts the next one. Returns whether
+    /// to request the LcdBuffer interrupt.
+    pub fn update(&mut self, ram: &[u8]) -> bool {
+        if !self.enabled() || self.current_cycle < self.frame_start + self.frame_cycles() {
+            return false;
+        }
+
+        self.show_frame(ram);
+
+        self.frame_start += self.frame_cycles();
+        // Frames which were missed entirely are skipped
+        if self.current_cycle >= self.frame_start + self.frame_cycles() {
+            self.frame_start = self.current_cycle;
+        }
+        self.frame_address = self.lssa.u16();
+        true
+    }
+
+    /// The instruction cycle at which the current frame's buffer runs out
+    pub fn next_event_cycle(&self) -> Option<u64> {
+        self.enabled()
+            .then(|| self.frame_start + self.frame_cycles())
+    }
+
+    fn show_frame(&self, ram: &[u8]) {
+        let Some(panel) = &self.panel else {
+            return;
+        };
+
+        let bits_per_pixel = self.bits_per_pixel();
+        let pixels_per_byte = 8 / bits_per_pixel;
+        let width = usize::from(self.lxmax.get()).min(panel.width);
+        let height = usize::from(self.lymax.get()).min(panel.height);
+        let stride = self.line_stride();
+
+        let mut pixels = vec![SHADES[0]; panel.width * panel.height];
+        for y in 0..height {
+            let line = usize::from(self.frame_address) + y * stride;
+            for x in 0..width {
+                let position = usize::from(self.lpan.get()) + x;
+                let byte = ram[(line + position / pixels_per_byte) % ram.len()];
+                let shift = 8 - bits_per_pixel * (position % pixels_per_byte + 1);
+                let value = (byte >> shift) as usize & ((1 << bits_per_pixel) - 1);
+                pixels[y * panel.width + x] = if bits_per_pixel == 1 {
+                    SHADES[value * 3]
+                } else {
+                    SHADES[value]
+                };
+            }
+        }
+
+        panel.screen.set_pixels(&pixels, self.current_cycle);
+    }
+
+    pub fn read_lssal(&self) -> u8 {
+        self.lssa.l()
+    }
+
+    pub fn write_lssal(&mut self, value: u8) {
+        self.lssa.set_l(value);
+    }
+
+    pub fn read_lssah(&self) -> u8 {
+        self.lssa.h()
+    }
+
+    pub fn write_lssah(&mut self, value: u8) {
+        self.lssa.set_h(value);
+    }
+
+    pub fn read_lvpw(&self) -> u8 {
+        self.lvpw.get()
+    }
+
+    pub fn write_lvpw(&mut self, value: u8) {
+        self.lvpw.set(value);
+    }
+
+    pub fn read_lxmax(&self) -> u8 {
+        self.lxmax.get()
+    }
+
+    pub fn write_lxmax(&mut self, value: u8) {
+        self.lxmax.set(value);
+    }
+
+    pub fn read_lymax(&self) -> u8 {
+        self.lymax.get()
+    }
+
+    pub fn write_lymax(&mut self, value: u8) {
+        self.lymax.set(value);
+    }
+
+    pub fn read_lpan(&self) -> u8 {
+        self.lpan.get()
+    }
+
+    pub fn write_lpan(&mut self, value: u8) {
+        self.lpan.set(value);
+    }
+
+    pub fn read_lctr(&self) -> u8 {
+        self.lctr.get()
+    }
+
+    pub fn write_lctr(&mut self, value: u8) {
+        let was_enabled = self.enabled();
+        self.lctr.set(value);
+
+        // Turning the controller on starts a new frame
+        if self.enabled() && !was_enabled {
+            self.frame_start = self.current_cycle;
+            self.frame_address = self.lssa.u16();
+        }
+    }
+
+    pub fn read_lckr(&self) -> u8 {
+        self.lckr.get()
+    }
+
+    pub fn write_lckr(&mut self, value: u8) {
+        self.lckr.set(value);
+    }
+
+    pub fn read_lfra(&self) -> u8 {
+        self.lfra.get()
+    }
+
+    pub fn write_lfra(&mut self, value: u8) {
+        self.lfra.set(value);
+    }
+
+    pub fn read_lac(&self) -> u8 {
+        self.lac.get()
+    }
+
+    pub fn write_lac(&mut self, value: u8) {
+        self.lac.set(value);
+    }
+
+    pub fn read_lpwm(&self) -> u8 {
+        self.lpwm.get()
+    }
+
+    pub fn write_lpwm(&mut self, value: u8) {
+        self.lpwm.set(value);
+    }
+}
+
+impl SaveState for State {
+    fn save_state(&self, state: &mut StateWriter) {
+        self.lssa.save_state(state);
+        self.lvpw.save_state(state);
+        self.lxmax.save_state(state);
+        self.lymax.save_state(state);
+        self.lpan.save_state(state);
+        self.lctr.save_state(state);
+        self.lckr.save_state(state);
+        self.lfra.save_state(state);
+        self.lac.save_state(state);
+        self.lpwm.save_state(state);
+        state.write_u16(self.frame_address);
+        state.write_u64(self.frame_start);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.lssa.load_state(state)?;
+        self.lvpw.load_state(state)?;
+        self.lxmax.load_state(state)?;
+        self.lymax.load_state(state)?;
+        self.lpan.load_state(state)?;
+        self.lctr.load_state(state)?;
+        self.lckr.load_state(state)?;
+        self.lfra.load_state(state)?;
+        self.lac.load_state(state)?;
+        self.lpwm.load_state(state)?;
+        self.frame_address = state.read_u16()?;
+        self.frame_start = state.read_u64()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    /// A frame as shade indices, and the cycle it was shown on
+    type Frame = (Vec<usize>, u64);
+
+    /// Keeps each frame shown
+    #[derive(Clone, Default)]
+    struct TestScreen {
+        frames: Rc<RefCell<Vec<Frame>>>,
+    }
+
+    impl Screen for TestScreen {
+        fn set_pixels(&self, pixels: &[Pixel], current_cycle: u64) {
+            let shades = pixels
+                .iter()
+                .map(|pixel| SHADES.iter().position(|shade| shade == pixel).unwrap())
+                .collect();
+            self.frames.borrow_mut().push((shades, current_cycle));
+        }
+    }
+
+    /// A controller for frames of `width` by `height` pixels, connected to a
+    /// panel of the same size, with one instruction cycle per pixel clock
+    fn connected_lcd(width: u8, height: u8) -> (State, TestScreen) {
+        let screen = TestScreen::default();
+        let mut lcd = State::new();
+        lcd.connect_panel(Box::new(screen.clone()), width.into(), height.into());
+        lcd.write_lxmax(width);
+        lcd.write_lymax(height);
+        (lcd, screen)
+    }
+
+    /// Turns the controller on at cycle 0 and runs until the first frame is shown
+    fn show_one_frame(lcd: &mut State, lctr: u8, ram: &[u8]) {
+        lcd.set_current_cycle(0);
+        lcd.write_lctr(LCTR_ENABLE | lctr);
+        lcd.set_current_cycle(lcd.next_event_cycle().unwrap());
+        assert!(lcd.update(ram));
+    }
+
+    fn ram_with(address: usize, bytes: &[u8]) -> Vec<u8> {
+        let mut ram = vec![0; 0x8000];
+        ram[address..address + bytes.len()].copy_from_slice(bytes);
+        ram
+    }
+
+    #[test]
+    fn frames_take_each_line_and_the_blank_lines() {
+        let (mut lcd, screen) = connected_lcd(8, 4);
+        lcd.write_lckr(1);
+        lcd.write_lfra(2);
+        let ram = vec![0; 0x8000];
+
+        lcd.set_current_cycle(10);
+        lcd.write_lctr(LCTR_ENABLE);
+        // 8 pixels of 2 cycles, for 4 lines and 2 blank ones
+        let frame = 8 * 2 * (4 + 2);
+        assert_eq!(lcd.next_event_cycle(), Some(10 + frame));
+
+        lcd.set_current_cycle(10 + frame - 1);
+        assert!(!lcd.update(&ram));
+        assert!(screen.frames.borrow().is_empty());
+
+        lcd.set_current_cycle(10 + frame);
+        assert!(lcd.update(&ram));
+        assert_eq!(screen.frames.borrow()[0].1, 10 + frame);
+        assert_eq!(lcd.next_event_cycle(), Some(10 + frame * 2));
+    }
+
+    #[test]
+    fn missed_frames_are_skipped() {
+        let (mut lcd, screen) = connected_lcd(8, 1);
+        let ram = vec![0; 0x8000];
+        lcd.write_lctr(LCTR_ENABLE);
+
+        lcd.set_current_cycle(100);
+        assert!(lcd.update(&ram));
+        assert!(!lcd.update(&ram));
+        assert_eq!(screen.frames.borrow().len(), 1);
+        assert_eq!(lcd.next_event_cycle(), Some(108));
+    }
+
+    #[test]
+    fn does_nothing_while_off() {
+        let (mut lcd, screen) = connected_lcd(8, 1);
+        lcd.set_current_cycle(1000);
+        assert_eq!(lcd.next_event_cycle(), None);
+        assert!(!lcd.update(&[0; 0x8000]));
+        assert!(screen.frames.borrow().is_empty());
+    }
+
+    #[test]
+    fn decodes_1bpp_with_the_leftmost_pixel_in_the_top_bit() {
+        let (mut lcd, screen) = connected_lcd(8, 2);
+        lcd.write_lssal(0x00);
+        lcd.write_lssah(0x10);
+        show_one_frame(&mut lcd, 0, &ram_with(0x1000, &[0b1100_0001, 0b0000_1111]));
+
+        let frames = screen.frames.borrow();
+        assert_eq!(
+            frames[0].0,
+            [3, 3, 0, 0, 0, 0, 0, 3, 0, 0, 0, 0, 3, 3, 3, 3]
+        );
+    }
+
+    #[test]
+    fn decodes_2bpp_into_four_shades() {
+        let (mut lcd, screen) = connected_lcd(4, 2);
+        show_one_frame(
+            &mut lcd,
+            LCTR_GREY,
+            &ram_with(0, &[0b00_01_10_11, 0b11_10_01_00]),
+        );
+
+        let frames = screen.frames.borrow();
+        assert_eq!(frames[0].0, [0, 1, 2, 3, 3, 2, 1, 0]);
+    }
+
+    #[test]
+    fn lines_start_lpan_pixels_in_and_lvpw_bytes_apart() {
+        let (mut lcd, screen) = connected_lcd(6, 2);
+        lcd.write_lpan(3);
+        lcd.write_lvpw(4);
+        let ram = ram_with(
+            0,
+            &[0b0001_0000, 0b1000_0000, 0, 0, 0b0000_0011, 0b1100_0000],
+        );
+        show_one_frame(&mut lcd, 0, &ram);
+
+        let frames = screen.frames.borrow();
+        assert_eq!(frames[0].0, [3, 0, 0, 0, 0, 3, 0, 0, 0, 3, 3, 3]);
+    }
+
+    #[test]
+    fn packed_lines_include_the_panning() {
+        // 4 panned pixels and 8 shown take 2 bytes a line
+        let (mut lcd, screen) = connected_lcd(8, 2);
+        lcd.write_lpan(4);
+        let ram = ram_with(0, &[0b0000_1000, 0b0000_0000, 0b0000_0000, 0b0001_0000]);
+        show_one_frame(&mut lcd, 0, &ram);
+
+        let frames = screen.frames.borrow();
+        assert_eq!(
+            frames[0].0,
+            [3, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 3]
+        );
+    }
+
+    #[test]
+    fn start_address_is_taken_as_each_frame_begins() {
+        let (mut lcd, screen) = connected_lcd(8, 1);
+        let mut ram = ram_with(0x100, &[0xFF]);
+        lcd.write_lssah(0x01);
+        lcd.write_lctr(LCTR_ENABLE);
+
+        // Pointing at another buffer mid-frame only takes effect on the next one
+        lcd.set_current_cycle(4);
+        lcd.write_lssah(0x02);
+        lcd.set_current_cycle(8);
+        assert!(lcd.update(&ram));
+
+        ram[0x200] = 0x0F;
+        lcd.set_current_cycle(16);
+        assert!(lcd.update(&ram));
+
+        let frames = screen.frames.borrow();
+        assert_eq!(frames[0].0, [3; 8]);
+        assert_eq!(frames[1].0, [0, 0, 0, 0, 3, 3, 3, 3]);
+    }
+
+    #[test]
+    fn frames_are_cut_to_the_panel() {
+        let screen = TestScreen::default();
+        let mut lcd = State::new();
+        lcd.connect_panel(Box::new(screen.clone()), 4, 3);
+        lcd.write_lxmax(8);
+        lcd.write_lymax(2);
+        show_one_frame(&mut lcd, 0, &ram_with(0, &[0xFF, 0xFF]));
+
+        let frames = screen.frames.borrow();
+        assert_eq!(frames[0].0, [3, 3, 3, 3, 3, 3, 3, 3, 0, 0, 0, 0]);
+    }
+}
@@ -1,13 +1,18 @@
 use super::clock::Clock;
 use super::interrupt::Interrupt;
-use super::psg::PsgChannel;
+use super::psg::{AudioTap, PsgChannel};
+use super::scheduler::Event;
 use super::vector;
 use super::wdc_65c02;
 use super::wdc_65c02::HandlesInterrupt;
-use super::St2205uAddressSpace;
+use super::{MachineAddressSpace, St2205uAddressSpace};
 use crate::audio::AudioInterface;
 use crate::gpio::GpioInterface;
 use crate::memory::AddressSpace;
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
+
+/// Instruction cycles between checks for new input
+const GPIO_POLL_INTERVAL: u64 = 1024;
 
 /// Representation of a ST2205U microcontroller.
 ///
@@ -19,12 +24,18 @@ use crate::memory::AddressSpace;
 pub struct Mcu {
     pub core: wdc_65c02::Core<St2205uAddressSpace>,
     pub audio_sender: Box<dyn AudioInterface>,
+    /// Where audio is recorded to, sampled separately from `audio_sender`
+    audio_recorder: Option<Box<dyn AudioInterface>>,
+    /// Whether samples are being withheld from `audio_sender` and `audio_recorder`
+    audio_suppressed: bool,
+    /// Whether the inputs are left alone instead of being polled
+    input_suspended: bool,
 }
 
 impl Mcu {
     pub fn new(
         frequency: u64,
-        address_space: Box<dyn AddressSpace>,
+        address_space: Box<dyn MachineAddressSpace>,
         io: Box<dyn GpioInterface>,
         mut audio_sender: Box<dyn AudioInterface>,
     ) -> Self {
@@ -35,29 +46,230 @@ impl Mcu {
                 St2205uAddressSpace::new(address_space, io, frequency),
             ),
             audio_sender,
+            audio_recorder: None,
+            audio_suppressed: false,
+            input_suspended: false,
         };
 
         mcu.reset();
+        mcu.core.address_space.gpio.restart_external_clock(0);
+        mcu.schedule_events();
 
         mcu
     }
 
     pub fn step(&mut self) {
-        self.core.step();
+        if self.core.run_state == wdc_65c02::RunState::Running {
+            self.core.step();
+        } else {
+            // Nothing can change until a peripheral does something, so skip ahead
+            self.core.cycles = self
+                .core
+                .address_space
+                .scheduler
+                .next_cycle()
+                .max(self.core.cycles + 1);
+        }
         self.core.address_space.set_clocks(
             self.core.oscillator_cycles(),
             self.core.instruction_cycles(),
         );
 
-        if self.core.address_space.base_timer.update() {
-            self.core
+        if self.core.cycles >= self.core.address_space.scheduler.next_cycle() {
+            self.service_events();
+        }
+
+        let interrupt = self
+            .core
+            .address_space
+            .interrupt
+            .highest_priority_interrupt();
+
+        // WAI resumes on any interrupt request, even if interrupts are disabled
+        if interrupt.is_some() && self.core.run_state == wdc_65c02::RunState::Waiting {
+            self.core.run_state = wdc_65c02::RunState::Running;
+        }
+
+        // A stopped core does not respond to interrupts, only to a reset
+        if !self.core.flags.interrupt_disable
+            && !self.core.interrupted()
+            && self.core.run_state != wdc_65c02::RunState::Stopped
+        {
+            if let Some(interrupt) = interrupt {
+                self.core
+                    .address_space
+                    .interrupt
+                    .clear_interrupt_request(interrupt);
+                self.core.address_space.set_interrupted(true);
+                self.core.push_u16(self.core.registers.pc);
+                self.core.push_u8(self.core.flags.to_u8());
+
+                let interrupt_vector = match interrupt {
+                    Interrupt::Intx => vector::INTX.into(),
+                    Interrupt::Timer0 => vector::T0.into(),
+                    Interrupt::Timer1 => vector::T1.into(),
+                    Interrupt::Timer2 => vector::T2.into(),
+                    Interrupt::Timer3 => vector::T3.into(),
+                    Interrupt::PortATransition => vector::PT.into(),
+                    Interrupt::BaseTimer => vector::BT.into(),
+                    Interrupt::LcdBuffer => vector::LCD.into(),
+                    Interrupt::SpiTxEmpty => vector::STX.into(),
+                    Interrupt::SpiRxReady => vector::SRX.into(),
+                    Interrupt::UartTx => vector::UTX.into(),
+                    Interrupt::UartRx => vector::URX.into(),
+                    Interrupt::Usb => vector::USB.into(),
+                    Interrupt::Pcm => vector::PCM.into(),
+                    Interrupt::Rtc => vector::RTC.into(),
+                };
+
+                self.core.registers.pc = self.core.address_space.read_u16_le(interrupt_vector);
+            }
+        }
+    }
+
+    /// Handles each peripheral event which is due, and schedules the next one of its kind
+    fn service_events(&mut self) {
+        let cycle = self.core.cycles;
+
+        if self
+            .core
+            .address_space
+            .scheduler
+            .is_due(Event::BaseTimerTick, cycle)
+        {
+            if self.core.address_space.base_timer.update() {
+                self.core
+                    .address_space
+                    .interrupt
+                    .assert_interrupt(Interrupt::BaseTimer);
+            }
+            self.schedule_base_timer_tick();
+        }
+
+        if self
+            .core
+            .address_space
+            .scheduler
+            .is_due(Event::TimerOverflow, cycle)
+        {
+            self.service_timer_overflows();
+            self.core.address_space.schedule_timer_overflow();
+        }
+
+        if self
+            .core
+            .address_space
+            .scheduler
+            .is_due(Event::AudioSample, cycle)
+        {
+            // Sample the state of the PSG and send it to the audio interfaces
+            let oscillator_cycles = self.core.oscillator_cycles();
+            let psg = &mut self.core.address_space.psg;
+            if self.audio_sender.needs_sample(oscillator_cycles) {
+                let mix = psg.take_sample(AudioTap::Output);
+                self.audio_sender.add_sample(mix, oscillator_cycles);
+            }
+            if let Some(recorder) = &mut self.audio_recorder {
+                if recorder.needs_sample(oscillator_cycles) {
+                    let mix = psg.take_sample(AudioTap::Recording);
+                    recorder.add_sample(mix, oscillator_cycles);
+                }
+            }
+            self.schedule_audio_sample();
+        }
+
+        if self
+            .core
+            .address_space
+            .scheduler
+            .is_due(Event::GpioPoll, cycle)
+        {
+            let port_a_transition = self.core.address_space.gpio.update_gpio_inputs(cycle);
+            if port_a_transition {
+                self.core
+                    .address_space
+                    .interrupt
+                    .assert_interrupt(Interrupt::PortATransition);
+            }
+
+            self.schedule_gpio_poll();
+        }
+
+        if self
+            .core
+            .address_space
+            .scheduler
+            .is_due(Event::ExternalClock, cycle)
+        {
+            let edges = self
+                .core
                 .address_space
-                .interrupt
-                .assert_interrupt(Interrupt::BaseTimer);
+                .gpio
+                .take_external_clock_edges(cycle);
+            self.core.address_space.timer.clock_external(edges);
+            self.core.address_space.schedule_timer_overflow();
+            self.schedule_external_clock_edge();
+        }
+
+        if self.core.address_space.scheduler.is_due(Event::Uart, cycle) {
+            let requests = self.core.address_space.uart.update();
+            let interrupt = &mut self.core.address_space.interrupt;
+            if requests.tx {
+                interrupt.assert_interrupt(Interrupt::UartTx);
+            }
+            if requests.rx {
+                interrupt.assert_interrupt(Interrupt::UartRx);
+            }
+            self.core.address_space.schedule_uart();
+        }
+
+        if self.core.address_space.scheduler.is_due(Event::Spi, cycle) {
+            let requests = self.core.address_space.spi.update();
+            let interrupt = &mut self.core.address_space.interrupt;
+            if requests.tx {
+                interrupt.assert_interrupt(Interrupt::SpiTxEmpty);
+            }
+            if requests.rx {
+                interrupt.assert_interrupt(Interrupt::SpiRxReady);
+            }
+            self.core.address_space.schedule_spi();
+        }
+
+        if self.core.address_space.scheduler.is_due(Event::Usb, cycle) {
+            if self.core.address_space.usb.update() {
+                self.core
+                    .address_space
+                    .interrupt
+                    .assert_interrupt(Interrupt::Usb);
+            }
+            self.core.address_space.schedule_usb();
+        }
+
+        if self.core.address_space.scheduler.is_due(Event::Rtc, cycle) {
+            if self.core.address_space.rtc.update() {
+                self.core
+                    .address_space
+                    .interrupt
+                    .assert_interrupt(Interrupt::Rtc);
+            }
+            self.core.address_space.schedule_rtc();
+        }
+
+        if self.core.address_space.scheduler.is_due(Event::Lcd, cycle) {
+            if self.core.address_space.update_lcd() {
+                self.core
+                    .address_space
+                    .interrupt
+                    .assert_interrupt(Interrupt::LcdBuffer);
+            }
+            self.core.address_space.schedule_lcd();
         }
+    }
 
+    fn service_timer_overflows(&mut self) {
         let timers_int = self.core.address_space.timer.update();
 
+        // T4 has no interrupt or PSG channel, so only T0 to T3 are handled here
         for i in 0..4 {
             // If a timer interrupt is pending, assert the interrupt and save the current PSG sample
             if timers_int & (1 << i) != 0 {
@@ -82,67 +294,126 @@ impl Mcu {
                     .assert_interrupt(interrupt);
 
                 // This gets the state of the audio, and it will be sent to the audio interface when the interface wants it
-                self.core.address_space.psg.pop_current_sample(channel);
+                if self.core.address_space.psg.pop_current_sample(channel) {
+                    self.core
+                        .address_space
+                        .interrupt
+                        .assert_interrupt(Interrupt::Pcm);
+                }
             }
         }
 
-        // Sample the state of the PSG and send it to the audio interface
-        if self
-            .audio_sender
-            .needs_sample(self.core.oscillator_cycles())
-        {
-            let mix = self.core.address_space.psg.get_mix_f32();
-            self.audio_sender.add_sample(mix);
-        }
+        self.update_timer_outputs();
+    }
 
-        let port_a_transition = self.core.address_space.gpio.update_gpio_inputs();
-        if port_a_transition {
-            self.core
-                .address_space
-                .interrupt
-                .assert_interrupt(Interrupt::PortATransition);
-        }
+    /// Passes the timers' outputs on to the pins they drive
+    fn update_timer_outputs(&mut self) {
+        let outputs = self.core.address_space.timer.outputs();
+        self.core.address_space.gpio.set_timer_outputs(outputs);
+    }
 
-        let interrupt = self
+    /// Works out when every kind of peripheral event is next due, such as
+    /// after loading a save state
+    fn schedule_events(&mut self) {
+        self.schedule_base_timer_tick();
+        self.core.address_space.schedule_timer_overflow();
+        self.schedule_audio_sample();
+        self.schedule_gpio_poll();
+        self.schedule_external_clock_edge();
+        self.core.address_space.schedule_uart();
+        self.core.address_space.schedule_spi();
+        self.core.address_space.schedule_usb();
+        self.core.address_space.schedule_rtc();
+        self.core.address_space.schedule_lcd();
+    }
+
+    fn schedule_base_timer_tick(&mut self) {
+        let next_tick = self
             .core
+            .instruction_cycle_from_oscillator(self.core.address_space.base_timer.next_tick());
+        self.core
             .address_space
-            .interrupt
-            .highest_priority_interrupt();
+            .scheduler
+            .schedule(Event::BaseTimerTick, Some(next_tick));
+    }
 
-        if !self.core.flags.interrupt_disable && !self.core.interrupted() {
-            if let Some(interrupt) = interrupt {
-                self.core
-                    .address_space
-                    .interrupt
-                    .clear_interrupt_request(interrupt);
-                self.core.address_space.set_interrupted(true);
-                self.core.push_u16(self.core.registers.pc);
-                self.core.push_u8(self.core.flags.to_u8());
+    fn schedule_audio_sample(&mut self) {
+        let next_sample = (!self.audio_suppressed).then(|| {
+            let next_recorded = self
+                .audio_recorder
+                .as_ref()
+                .map_or(u64::MAX, |recorder| recorder.next_sample_cycle());
+            self.core.instruction_cycle_from_oscillator(
+                self.audio_sender.next_sample_cycle().min(next_recorded),
+            )
+        });
+        self.core
+            .address_space
+            .scheduler
+            .schedule(Event::AudioSample, next_sample);
+    }
 
-                let interrupt_vector = match interrupt {
-                    Interrupt::Intx => vector::INTX.into(),
-                    Interrupt::Timer0 => vector::T0.into(),
-                    Interrupt::Timer1 => vector::T1.into(),
-                    Interrupt::Timer2 => vector::T2.into(),
-                    Interrupt::Timer3 => vector::T3.into(),
-                    Interrupt::PortATransition => vector::PT.into(),
-                    Interrupt::BaseTimer => vector::BT.into(),
-                    Interrupt::LcdBuffer => vector::LCD.into(),
-                    Interrupt::SpiTxEmpty => vector::STX.into(),
-                    Interrupt::SpiRxReady => vector::SRX.into(),
-                    Interrupt::UartTx => vector::UTX.into(),
-                    Interrupt::UartRx => vector::URX.into(),
-                    Interrupt::Usb => vector::USB.into(),
-                    Interrupt::Pcm => vector::PCM.into(),
-                    Interrupt::Rtc => vector::RTC.into(),
-                };
+    /// Sets where audio is recorded to, alongside the audio interface
+    pub fn set_audio_recorder(&mut self, recorder: Option<Box<dyn AudioInterface>>) {
+        self.audio_recorder = recorder.map(|mut recorder| {
+            recorder.set_clock_rate(self.core.frequency());
+            recorder
+        });
+        self.restart_audio_sampling();
+    }
 
-                self.core.registers.pc = self.core.address_space.read_u16_le(interrupt_vector);
-            }
+    /// Stops sending samples to the audio interfaces, or starts again from the
+    /// current cycle. The PSG keeps running either way.
+    pub fn set_audio_suppressed(&mut self, suppressed: bool) {
+        let resumed = self.audio_suppressed && !suppressed;
+        self.audio_suppressed = suppressed;
+        if resumed {
+            self.restart_audio_sampling();
         }
+        self.schedule_audio_sample();
+    }
+
+    /// Starts taking samples for every audio interface from the current cycle
+    fn restart_audio_sampling(&mut self) {
+        let oscillator_cycles = self.core.oscillator_cycles();
+        self.audio_sender.reset_clock(oscillator_cycles);
+        if let Some(recorder) = &mut self.audio_recorder {
+            recorder.reset_clock(oscillator_cycles);
+        }
+        self.core
+            .address_space
+            .psg
+            .restart_sampling(self.core.instruction_cycles());
+        self.schedule_audio_sample();
+    }
+
+    /// Stops polling the inputs, which keep their last state, or starts again.
+    /// Input due while polling is suspended is picked up once it resumes.
+    pub fn set_input_suspended(&mut self, suspended: bool) {
+        self.input_suspended = suspended;
+        self.schedule_gpio_poll();
+    }
+
+    fn schedule_gpio_poll(&mut self) {
+        // Polling on multiples of the interval keeps input timing the same across save states
+        let next_poll = (!self.input_suspended)
+            .then(|| (self.core.cycles / GPIO_POLL_INTERVAL + 1) * GPIO_POLL_INTERVAL);
+        self.core
+            .address_space
+            .scheduler
+            .schedule(Event::GpioPoll, next_poll);
+    }
+
+    fn schedule_external_clock_edge(&mut self) {
+        let next_edge = self.core.address_space.gpio.next_external_clock_edge();
+        self.core
+            .address_space
+            .scheduler
+            .schedule(Event::ExternalClock, next_edge);
     }
 
     pub fn reset(&mut self) {
+        self.core.run_state = wdc_65c02::RunState::Running;
         self.core.set_interrupted(true);
         let reset_vector = self.core.address_space.read_u16_le(vector::RESET.into());
         self.core.registers.pc = reset_vector;
@@ -160,3 +431,22 @@ impl Mcu {
         data
     }
 }
+
+impl SaveState for Mcu {
+    fn save_state(&self, state: &mut StateWriter) {
+        self.core.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.core.load_state(state)?;
+        // Samples and clock edges should continue from the restored point in time
+        self.restart_audio_sampling();
+        self.core
+            .address_space
+            .gpio
+            .restart_external_clock(self.core.cycles);
+        self.schedule_events();
+        self.update_timer_outputs();
+        Ok(())
+    }
+}
@@ -5,14 +5,24 @@ mod clock;
 mod dma;
 mod gpio;
 mod interrupt;
+mod lcd;
 mod mcu;
 mod psg;
 mod reg;
+mod rtc;
+mod scheduler;
+mod spi;
 mod timer;
+mod uart;
+mod usb;
 mod vector;
 mod wdc_65c02;
 
+pub use addr_space::MachineAddressSpace;
 pub use addr_space::Otp;
 pub use addr_space::St2205uAddressSpace;
 pub use addr_space::OTP_SIZE;
+pub use clock::Clock;
 pub use mcu::Mcu;
+pub use spi::SpiDevice;
+pub use wdc_65c02::CYCLE_FREQUENCY_DIVISOR;
@@ -1,11 +1,79 @@
 use std::collections::VecDeque;
 
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
+
+/// SYSCK ticks per tick of the tone generators' clock.
+///
+/// Not hardware behaviour: the datasheet's description of the PSG clock was
+/// not available, so this value is a placeholder rather than derived from it.
+/// It puts the 12 bit period range between about 31 Hz and 125 kHz with an
+/// 8 MHz SYSCK. Tone pitches will be off by a constant factor if it is wrong.
+const TONE_CLOCK_DIVISOR: u64 = 32;
+
+/// Number of samples each DAC channel's FIFO can hold.
+///
+/// Not hardware behaviour: the datasheet was not available to take the depth
+/// from. 16 is only the capacity the emulator's queues used to be created
+/// with. The one constraint known is that PSGxB reports the level in 5 bits,
+/// so the real depth is no more than 31.
+const FIFO_DEPTH: usize = 16;
+
+/// The FIFO accepts more samples, and asks for them, below this many.
+///
+/// Not hardware behaviour either: it is where the emulator already set the
+/// PSGxB write available bit, not a datasheet figure.
+const FIFO_REFILL_LEVEL: usize = 8;
+
+/// Range of the ADPCM accumulator.
+///
+/// Not hardware behaviour: this only follows from the samples being 9 bits
+/// wide. Whether the accumulator is two's complement, and whether it
+/// saturates rather than wraps, could not be checked against the datasheet.
+const ADPCM_MIN: i16 = -256;
+const ADPCM_MAX: i16 = 255;
+
 /// Programmable Sound Generator
 pub struct State {
     psgc: Psgc,
     psg_states: [PsgModeState; 4],
     volumes: [PsgVolume; 4],
     multiplicator: Multiplicator,
+    elapsed_ticks: u64,
+    /// SYSCK tick up to which the integrals have been taken
+    integrated_until: u64,
+    /// One filter for each tap, so that each is sampled on its own schedule
+    filters: [SampleFilter; 2],
+}
+
+/// Where samples of the mix are taken to. Each tap filters the mix over its
+/// own sample periods, so taking samples for one doesn't change the other's.
+#[derive(Clone, Copy)]
+pub enum AudioTap {
+    /// The audio output
+    Output,
+    /// A recording, which may be at another rate than the output
+    Recording,
+}
+
+/// The mix taken since the last sample at one tap
+#[derive(Default)]
+struct SampleFilter {
+    /// Integral of the mix over the SYSCK ticks since the last sample was taken
+    mix_integral: f64,
+    /// Integral of the mix weighted by the ticks since the last sample was taken
+    mix_moment: f64,
+    /// SYSCK tick at which the last sample was taken
+    sample_start: u64,
+    /// The part of the last sample period's integral under the rising side of the
+    /// next sample's filter, and the length of that period
+    previous_rising: f64,
+    previous_ticks: u64,
+}
+
+/// What a channel puts out, before its volume is applied
+enum ChannelOutput {
+    Level(f64),
+    Tone { period: u16, start_tick: u64 },
 }
 
 pub struct Multiplicator {
@@ -57,6 +125,27 @@ impl Multiplicator {
     }
 }
 
+impl SaveState for Multiplicator {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.external_mull);
+        state.write_u8(self.external_mulh);
+        state.write_bool(self.last_mulh_was_1);
+        state.write_u8(self.internal_mulh0);
+        state.write_u8(self.internal_mulh1);
+        state.write_u8(self.internal_mull);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.external_mull = state.read_u8()?;
+        self.external_mulh = state.read_u8()?;
+        self.last_mulh_was_1 = state.read_bool()?;
+        self.internal_mulh0 = state.read_u8()?;
+        self.internal_mulh1 = state.read_u8()?;
+        self.internal_mull = state.read_u8()?;
+        Ok(())
+    }
+}
+
 pub enum PsgChannel {
     Channel0,
     Channel1,
@@ -81,9 +170,99 @@ impl State {
                 PsgVolume::new(),
             ],
             multiplicator: Multiplicator::new(),
+            elapsed_ticks: 0,
+            integrated_until: 0,
+            filters: Default::default(),
         }
     }
 
+    pub fn set_elapsed_ticks(&mut self, sysck: u64) {
+        self.elapsed_ticks = sysck;
+    }
+
+    /// Adds the mix up to the current tick to the integrals. This must be
+    /// called before anything which changes the mix.
+    fn integrate_mix(&mut self) {
+        if self.elapsed_ticks <= self.integrated_until {
+            return;
+        }
+
+        let start = self.integrated_until;
+        let end = self.elapsed_ticks;
+        let ticks = (end - start) as f64;
+        let (low, high) = (TONE_LOW, TONE_HIGH);
+
+        let integral = self.mix(|output| match output {
+            ChannelOutput::Level(level) => level * ticks,
+            ChannelOutput::Tone { period, start_tick } => {
+                let (high_ticks, _) = tone_high_integrals(period, start_tick, start, end);
+                low * ticks + (high - low) * high_ticks
+            }
+        });
+        let moment = self.mix(|output| match output {
+            ChannelOutput::Level(level) => level * ticks * ticks / 2.0,
+            ChannelOutput::Tone { period, start_tick } => {
+                let (_, high_moment) = tone_high_integrals(period, start_tick, start, end);
+                low * ticks * ticks / 2.0 + (high - low) * high_moment
+            }
+        });
+
+        // The moment is relative to the start of this stretch, so move it to the
+        // start of each tap's sample period
+        for filter in &mut self.filters {
+            filter.mix_integral += integral;
+            filter.mix_moment += moment + (start - filter.sample_start) as f64 * integral;
+        }
+        self.integrated_until = end;
+    }
+
+    /// Returns the mix filtered over the last two sample periods.
+    ///
+    /// The filter is a triangle which peaks at the previous sample's tick. Taking
+    /// the mix over time rather than at one instant keeps square waves and DAC
+    /// updates far above the sample rate from aliasing down into the audible
+    /// range, and a triangle rejects them far better than a plain average.
+    pub fn take_sample(&mut self, tap: AudioTap) -> f32 {
+        self.integrate_mix();
+
+        let end = self.integrated_until;
+        let filter = &mut self.filters[tap as usize];
+        let ticks = end - filter.sample_start;
+        let (rising, falling) = if ticks == 0 {
+            (0.0, 0.0)
+        } else {
+            let rising = filter.mix_moment / ticks as f64;
+            (rising, filter.mix_integral - rising)
+        };
+
+        let width = (filter.previous_ticks + ticks) as f64 / 2.0;
+        let sample = (width != 0.0).then(|| ((filter.previous_rising + falling) / width) as f32);
+
+        *filter = SampleFilter {
+            previous_rising: rising,
+            previous_ticks: ticks,
+            sample_start: end,
+            ..Default::default()
+        };
+        sample.unwrap_or_else(|| self.get_mix_f32())
+    }
+
+    /// Starts sampling again from `sysck` at every tap, forgetting the mix
+    /// before it, such as after loading a save state
+    pub fn restart_sampling(&mut self, sysck: u64) {
+        self.integrated_until = sysck;
+        self.filters = [
+            SampleFilter {
+                sample_start: sysck,
+                ..Default::default()
+            },
+            SampleFilter {
+                sample_start: sysck,
+                ..Default::default()
+            },
+        ];
+    }
+
     fn get_psg_state_mut(&mut self, channel: PsgChannel) -> &mut PsgModeState {
         &mut self.psg_states[match channel {
             PsgChannel::Channel0 => 0,
@@ -125,6 +304,7 @@ impl State {
     }
 
     pub fn write_psgc(&mut self, value: u8) {
+        self.integrate_mix();
         self.psgc.write_psgc(value);
     }
 
@@ -142,11 +322,12 @@ impl State {
     }
 
     pub fn write_psgm(&mut self, value: u8) {
+        self.integrate_mix();
         for i in 0..4 {
             let mode = (value >> (i * 2)) & 0b11;
             self.psg_states[i] = match mode {
                 0b00 => PsgModeState::default_pcmdac(),
-                0b01 => PsgModeState::Tone,
+                0b01 => PsgModeState::default_tone(self.elapsed_ticks),
                 0b11 => PsgModeState::default_adpcmdac(),
                 _ => PsgModeState::default(),
             };
@@ -154,27 +335,51 @@ impl State {
     }
 
     pub fn write_psgxa(&mut self, channel: PsgChannel, value: u8) {
+        self.integrate_mix();
+        let elapsed_ticks = self.elapsed_ticks;
         match self.get_psg_state_mut(channel) {
-            PsgModeState::AdpcmDac { fifo, .. } => {
-                // ADPCM is differential, so the new value is calculated from the previous value
-                let raw_value = (fifo.back().unwrap_or(&0) + i16::from(value)).clamp(-255, 256);
-                fifo.push_back(raw_value);
+            PsgModeState::AdpcmDac {
+                fifo, accumulator, ..
+            } => {
+                // ADPCM is differential, so each write moves the accumulator up by the written value
+                if fifo.len() < FIFO_DEPTH {
+                    *accumulator = (*accumulator + i16::from(value)).clamp(ADPCM_MIN, ADPCM_MAX);
+                    fifo.push_back(*accumulator);
+                }
             }
             PsgModeState::PcmDac { fifo, .. } => {
-                fifo.push_back(value);
+                // Writes to a full FIFO are lost
+                if fifo.len() < FIFO_DEPTH {
+                    fifo.push_back(value);
+                }
+            }
+            PsgModeState::Tone { period, start_tick } => {
+                // PSGxA holds the low 8 bits of the period
+                *period = (*period & 0x0F00) | u16::from(value);
+                *start_tick = elapsed_ticks;
             }
-            _ => todo!(),
         }
     }
 
     pub fn write_psgxb(&mut self, channel: PsgChannel, value: u8) {
+        self.integrate_mix();
+        let elapsed_ticks = self.elapsed_ticks;
         match self.get_psg_state_mut(channel) {
-            PsgModeState::AdpcmDac { fifo, .. } => {
-                let raw_value = (fifo.back().unwrap_or(&0) - i16::from(value)).clamp(-255, 256);
-                fifo.push_back(raw_value);
+            PsgModeState::AdpcmDac {
+                fifo, accumulator, ..
+            } if fifo.len() < FIFO_DEPTH => {
+                // ... and writes to PSGxB move it down
+                *accumulator = (*accumulator - i16::from(value)).clamp(ADPCM_MIN, ADPCM_MAX);
+                fifo.push_back(*accumulator);
+            }
+            PsgModeState::Tone { period, start_tick } => {
+                // PSGxB holds the high 4 bits of the period
+                *period = (*period & 0x00FF) | (u16::from(value & 0x0F) << 8);
+                *start_tick = elapsed_ticks;
             }
             _ => {
-                // According to the datasheet, nothing happens if the channel is not ADPCM
+                // According to the datasheet, nothing happens if the channel is not ADPCM,
+                // and writes to a full FIFO are lost
             }
         }
     }
@@ -186,12 +391,11 @@ impl State {
         let fifo_len = match self.get_psg_state(channel) {
             PsgModeState::AdpcmDac { fifo, .. } => fifo.len(),
             PsgModeState::PcmDac { fifo, .. } => fifo.len(),
-            _ => {
-                todo!()
-            }
+            // Tone generators have no FIFO, so it always appears empty
+            PsgModeState::Tone { .. } => 0,
         };
 
-        if fifo_len < 8 {
+        if fifo_len < FIFO_REFILL_LEVEL {
             result |= 0b00100000; // FIFO write available
         }
 
@@ -202,6 +406,7 @@ impl State {
     }
 
     pub fn write_volx(&mut self, channel: PsgChannel, value: u8) {
+        self.integrate_mix();
         self.get_volume_mut(channel).set_u8(value);
     }
 
@@ -225,63 +430,189 @@ impl State {
         self.multiplicator.write_mulh(value);
     }
 
-    pub fn pop_current_sample(&mut self, channel: PsgChannel) {
-        match self.get_psg_state_mut(channel) {
+    /// Moves the next sample out of the channel's FIFO and returns true if the
+    /// FIFO should now be refilled. The last sample is held if the FIFO is empty.
+    pub fn pop_current_sample(&mut self, channel: PsgChannel) -> bool {
+        self.integrate_mix();
+        let enabled = self.psgc.pcmen && self.psgc.channel_enabled(&channel);
+
+        let fifo_len = match self.get_psg_state_mut(channel) {
             PsgModeState::AdpcmDac {
                 fifo,
                 current_sample,
+                ..
             } => {
-                let value = fifo.pop_front().unwrap_or(0);
-                *current_sample = value;
+                if let Some(value) = fifo.pop_front() {
+                    *current_sample = value;
+                }
+                fifo.len()
             }
             PsgModeState::PcmDac {
                 fifo,
                 current_sample,
             } => {
-                let value = fifo.pop_front().unwrap_or(0);
-                *current_sample = value;
+                if let Some(value) = fifo.pop_front() {
+                    *current_sample = value;
+                }
+                fifo.len()
             }
-            _ => {}
-        }
+            PsgModeState::Tone { .. } => return false,
+        };
+
+        enabled && fifo_len < FIFO_REFILL_LEVEL
     }
 
     pub fn get_mix_f32(&self) -> f32 {
-        let adpcm_as_f32 = |current_sample: i16| -> f32 { current_sample as f32 / 256.0 };
+        self.mix(|output| match output {
+            ChannelOutput::Level(level) => level,
+            ChannelOutput::Tone { period, start_tick } => {
+                if tone_is_high(period, start_tick, self.elapsed_ticks) {
+                    TONE_HIGH
+                } else {
+                    TONE_LOW
+                }
+            }
+        }) as f32
+    }
 
-        // I think this should be divided by 256.0, but that results in very loud sounds.
-        // Maybe this is internally a 9 bit value like the ADPCM?
-        let pcm_as_f32 = |current_sample: u8| -> f32 { (current_sample as f32 - 128.0) / 512.0 };
+    /// Mixes the channels, taking the value of each from `channel_value`. This
+    /// is linear, so it works as well on integrals of the channels as on levels.
+    fn mix(&self, channel_value: impl Fn(ChannelOutput) -> f64) -> f64 {
+        if self.psgc.mute || self.psgc.output_driver() == OutputDriver::None {
+            return 0.0;
+        }
 
-        let channel_as_f32 = |channel: PsgChannel| -> f32 {
-            match self.get_psg_state(channel) {
-                PsgModeState::AdpcmDac { current_sample, .. } => adpcm_as_f32(*current_sample),
-                PsgModeState::PcmDac { current_sample, .. } => pcm_as_f32(*current_sample),
-                PsgModeState::Tone => todo!(),
+        let channel_as_f64 = |channel: PsgChannel| -> f64 {
+            if !self.psgc.channel_enabled(&channel) {
+                return 0.0;
             }
+
+            channel_value(match self.get_psg_state(channel) {
+                // The DACs must also be enabled for PCM and ADPCM channels to make any sound
+                PsgModeState::AdpcmDac { .. } | PsgModeState::PcmDac { .. } if !self.psgc.pcmen => {
+                    return 0.0;
+                }
+                PsgModeState::AdpcmDac { current_sample, .. } => {
+                    ChannelOutput::Level(adpcm_as_f64(*current_sample))
+                }
+                PsgModeState::PcmDac { current_sample, .. } => {
+                    ChannelOutput::Level(pcm_as_f64(*current_sample))
+                }
+                PsgModeState::Tone { period, start_tick } => ChannelOutput::Tone {
+                    period: *period,
+                    start_tick: *start_tick,
+                },
+            })
         };
 
+        let volume = |channel: PsgChannel| f64::from(self.get_volume(channel).as_f32());
+
         let mixer0 = {
-            let channel0 = channel_as_f32(PsgChannel::Channel0);
-            let channel1 = channel_as_f32(PsgChannel::Channel1);
-            let channel0_scaled = channel0 * self.get_volume(PsgChannel::Channel0).as_f32();
-            let channel1_scaled = channel1 * self.get_volume(PsgChannel::Channel1).as_f32();
+            let channel0 = channel_as_f64(PsgChannel::Channel0);
+            let channel1 = channel_as_f64(PsgChannel::Channel1);
+            let channel0_scaled = channel0 * volume(PsgChannel::Channel0);
+            let channel1_scaled = channel1 * volume(PsgChannel::Channel1);
 
             (channel0_scaled + channel1_scaled) / 2.0
         };
 
         let mixer1 = {
-            let channel2 = channel_as_f32(PsgChannel::Channel2);
-            let channel3 = channel_as_f32(PsgChannel::Channel3);
-            let channel2_scaled = channel2 * self.get_volume(PsgChannel::Channel2).as_f32();
-            let channel3_scaled = channel3 * self.get_volume(PsgChannel::Channel3).as_f32();
+            let channel2 = channel_as_f64(PsgChannel::Channel2);
+            let channel3 = channel_as_f64(PsgChannel::Channel3);
+            let channel2_scaled = channel2 * volume(PsgChannel::Channel2);
+            let channel3_scaled = channel3 * volume(PsgChannel::Channel3);
 
             (channel2_scaled + channel3_scaled) / 2.0
         };
 
-        let result = (mixer0 + mixer1) / 2.0;
+        (mixer0 + mixer1) / 2.0
+    }
+}
 
-        result
+fn adpcm_as_f64(current_sample: i16) -> f64 {
+    current_sample as f64 / 256.0
+}
+
+// I think this should be divided by 256.0, but that results in very loud sounds.
+// Maybe this is internally a 9 bit value like the ADPCM?
+fn pcm_as_f64(current_sample: u8) -> f64 {
+    (current_sample as f64 - 128.0) / 512.0
+}
+
+// Tones swing across the full range of the 8 bit DAC
+const TONE_HIGH: f64 = (0xFF as f64 - 128.0) / 512.0;
+const TONE_LOW: f64 = (0x00 as f64 - 128.0) / 512.0;
+
+impl SaveState for State {
+    fn save_state(&self, state: &mut StateWriter) {
+        self.psgc.save_state(state);
+        for psg_state in &self.psg_states {
+            psg_state.save_state(state);
+        }
+        for volume in &self.volumes {
+            state.write_u8(volume.get_u8());
+        }
+        self.multiplicator.save_state(state);
     }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.psgc.load_state(state)?;
+        for psg_state in &mut self.psg_states {
+            psg_state.load_state(state)?;
+        }
+        for volume in &mut self.volumes {
+            volume.set_u8(state.read_u8()?);
+        }
+        self.multiplicator.load_state(state)
+    }
+}
+
+/// Whether a tone generator's square wave is high at `elapsed_ticks`.
+///
+/// The output starts high whenever the period is written, and flips every
+/// `period + 1` ticks of the tone clock, for a 50% duty cycle at
+/// SYSCK / (2 * TONE_CLOCK_DIVISOR * (period + 1)). The duty cycle and the
+/// `+ 1` are likewise assumptions, not taken from the datasheet.
+fn tone_is_high(period: u16, start_tick: u64, elapsed_ticks: u64) -> bool {
+    let tone_ticks = elapsed_ticks.saturating_sub(start_tick) / TONE_CLOCK_DIVISOR;
+    let half_periods = tone_ticks / (u64::from(period) + 1);
+    half_periods & 1 == 0
+}
+
+/// Integrals of a tone generator's square wave, taken as 1 while high and 0
+/// while low, over the SYSCK ticks from `start` to `end`. The first is the
+/// number of ticks it is high for, and the second weights each of those ticks
+/// by how long after `start` it is.
+fn tone_high_integrals(period: u16, start_tick: u64, start: u64, end: u64) -> (f64, f64) {
+    let half_period = TONE_CLOCK_DIVISOR * (u64::from(period) + 1);
+    let cycle = 2 * half_period;
+
+    // Measure from the beginning of the cycle `start` is in, which keeps the
+    // numbers small however long the tone has been playing
+    let since_tone_start = start.saturating_sub(start_tick);
+    let origin = start - since_tone_start % cycle;
+
+    // Integrals of the wave and of the wave times the ticks since `origin`, up to `tick`
+    let integrals_to = |tick: u64| -> (f64, f64) {
+        let cycles = ((tick - origin) / cycle) as f64;
+        let into_cycle = ((tick - origin) % cycle).min(half_period) as f64;
+        let half_period = half_period as f64;
+        let cycle = cycle as f64;
+
+        let high_ticks = cycles * half_period + into_cycle;
+        let moment = half_period * cycle * cycles * (cycles - 1.0) / 2.0
+            + cycles * half_period * half_period / 2.0
+            + into_cycle * cycles * cycle
+            + into_cycle * into_cycle / 2.0;
+        (high_ticks, moment)
+    };
+
+    let (start_high, start_moment) = integrals_to(start);
+    let (end_high, end_moment) = integrals_to(end);
+    let high_ticks = end_high - start_high;
+    let offset = (start - origin) as f64;
+
+    (high_ticks, end_moment - start_moment - offset * high_ticks)
 }
 
 #[derive(Debug)]
@@ -290,10 +621,16 @@ enum PsgModeState {
         fifo: VecDeque<u8>, // 8 bits
         current_sample: u8,
     },
-    Tone,
+    Tone {
+        period: u16, // 12 bits
+        /// SYSCK tick at which the current period began
+        start_tick: u64,
+    },
     AdpcmDac {
         fifo: VecDeque<i16>, // 9 bits
         current_sample: i16,
+        /// Sum of the deltas written so far, which is what gets queued
+        accumulator: i16,
     },
 }
 
@@ -306,23 +643,124 @@ impl Default for PsgModeState {
 impl PsgModeState {
     pub fn default_pcmdac() -> Self {
         PsgModeState::PcmDac {
-            fifo: VecDeque::with_capacity(16),
+            fifo: VecDeque::with_capacity(FIFO_DEPTH),
             current_sample: 128,
         }
     }
 
+    pub fn default_tone(elapsed_ticks: u64) -> Self {
+        PsgModeState::Tone {
+            period: 0,
+            start_tick: elapsed_ticks,
+        }
+    }
+
     pub fn default_adpcmdac() -> Self {
         PsgModeState::AdpcmDac {
-            fifo: VecDeque::with_capacity(16),
+            fifo: VecDeque::with_capacity(FIFO_DEPTH),
             current_sample: 0,
+            accumulator: 0,
         }
     }
 }
 
+impl SaveState for PsgModeState {
+    fn save_state(&self, state: &mut StateWriter) {
+        // Modes are saved using their PSGM encoding
+        match self {
+            PsgModeState::PcmDac {
+                fifo,
+                current_sample,
+            } => {
+                state.write_u8(0b00);
+                state.write_u32(fifo.len() as u32);
+                for value in fifo {
+                    state.write_u8(*value);
+                }
+                state.write_u8(*current_sample);
+            }
+            PsgModeState::Tone { period, start_tick } => {
+                state.write_u8(0b01);
+                state.write_u16(*period);
+                state.write_u64(*start_tick);
+            }
+            PsgModeState::AdpcmDac {
+                fifo,
+                current_sample,
+                accumulator,
+            } => {
+                state.write_u8(0b11);
+                state.write_u32(fifo.len() as u32);
+                for value in fifo {
+                    state.write_i16(*value);
+                }
+                state.write_i16(*current_sample);
+                state.write_i16(*accumulator);
+            }
+        }
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        *self = match state.read_u8()? {
+            0b00 => {
+                let len = read_fifo_len(state)?;
+                let fifo = (0..len)
+                    .map(|_| state.read_u8())
+                    .collect::<Result<_, _>>()?;
+                let current_sample = state.read_u8()?;
+                PsgModeState::PcmDac {
+                    fifo,
+                    current_sample,
+                }
+            }
+            0b01 => PsgModeState::Tone {
+                period: state.read_u16()? & 0x0FFF,
+                start_tick: state.read_u64()?,
+            },
+            0b11 => {
+                let len = read_fifo_len(state)?;
+                let fifo = (0..len)
+                    .map(|_| state.read_i16())
+                    .collect::<Result<_, _>>()?;
+                let current_sample = state.read_i16()?;
+                let accumulator = state.read_i16()?;
+                PsgModeState::AdpcmDac {
+                    fifo,
+                    current_sample,
+                    accumulator,
+                }
+            }
+            _ => return Err(StateError::InvalidValue("PSG mode")),
+        };
+        Ok(())
+    }
+}
+
+fn read_fifo_len(state: &mut StateReader) -> Result<u32, StateError> {
+    let len = state.read_u32()?;
+    if len as usize > FIFO_DEPTH {
+        return Err(StateError::InvalidValue("PSG FIFO length"));
+    }
+    Ok(len)
+}
+
+/// What drives the audio pins
+#[derive(PartialEq)]
+enum OutputDriver {
+    /// Once the speaker filters out the PWM carrier, it hears the same level as
+    /// from the DAC, and that filtered level is what the emulator produces
+    Pwm,
+    Dac,
+    /// The pins are left idle, so nothing is heard
+    None,
+}
+
 // PSG Control
 pub struct Psgc {
     mute: bool,
-    // psgo not implemented
+    /// Output driver select, see `output_driver`
+    psgo: u8,
+    /// Enables the PCM and ADPCM DACs
     pcmen: bool,
     p0en: bool,
     p1en: bool,
@@ -334,6 +772,7 @@ impl Psgc {
     pub fn new() -> Self {
         Self {
             mute: false,
+            psgo: 0,
             pcmen: false,
             p0en: false,
             p1en: false,
@@ -348,17 +787,53 @@ impl Psgc {
             | ((self.p1en as u8) << 5)
             | ((self.p0en as u8) << 4)
             | ((self.pcmen as u8) << 3)
+            | (self.psgo << 1)
             | ((self.mute as u8) << 0)
     }
 
     pub fn write_psgc(&mut self, value: u8) {
         self.mute = (value & 0b00000001) != 0;
+        self.psgo = (value & 0b00000110) >> 1;
         self.pcmen = (value & 0b00001000) != 0;
         self.p0en = (value & 0b00010000) != 0;
         self.p1en = (value & 0b00100000) != 0;
         self.p2en = (value & 0b01000000) != 0;
         self.p3en = (value & 0b10000000) != 0;
     }
+
+    /// The driver which puts the mix on the audio pins.
+    ///
+    /// Which PSGO value picks which driver is assumed, not taken from the
+    /// datasheet: 0 is the PWM driver, so that firmware which never sets PSGO
+    /// still plays, 1 is the DAC driver and the others drive neither.
+    fn output_driver(&self) -> OutputDriver {
+        match self.psgo {
+            0b00 => OutputDriver::Pwm,
+            0b01 => OutputDriver::Dac,
+            _ => OutputDriver::None,
+        }
+    }
+
+    /// Whether the channel is enabled
+    fn channel_enabled(&self, channel: &PsgChannel) -> bool {
+        match channel {
+            PsgChannel::Channel0 => self.p0en,
+            PsgChannel::Channel1 => self.p1en,
+            PsgChannel::Channel2 => self.p2en,
+            PsgChannel::Channel3 => self.p3en,
+        }
+    }
+}
+
+impl SaveState for Psgc {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.read_psgc());
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.write_psgc(state.read_u8()?);
+        Ok(())
+    }
 }
 
 struct PsgVolume {
@@ -382,3 +857,368 @@ impl PsgVolume {
         self.volume as f32 / 63.0
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resampler::Resampler;
+
+    #[test]
+    fn tone_flips_every_period_plus_one_tone_ticks() {
+        let mut psg = State::new();
+        psg.write_psgm(0b01);
+        psg.write_psgc(0b0001_0000);
+        psg.write_psgxa(PsgChannel::Channel0, 2);
+
+        let half_period = 3 * TONE_CLOCK_DIVISOR;
+        for (tick, high) in [
+            (0, true),
+            (half_period - 1, true),
+            (half_period, false),
+            (2 * half_period - 1, false),
+            (2 * half_period, true),
+        ] {
+            psg.set_elapsed_ticks(tick);
+            assert_eq!(psg.get_mix_f32() > 0.0, high, "At tick {tick}");
+        }
+    }
+
+    #[test]
+    fn tone_period_spans_both_registers_and_restarts_high() {
+        let mut psg = State::new();
+        psg.write_psgm(0b01);
+        psg.write_psgc(0b0001_0000);
+        psg.write_psgxa(PsgChannel::Channel0, 0x34);
+        // Only the low 4 bits of PSGxB are part of the period
+        psg.write_psgxb(PsgChannel::Channel0, 0xF2);
+
+        let half_period = 0x235 * TONE_CLOCK_DIVISOR;
+        psg.set_elapsed_ticks(1000 + half_period);
+        assert!(psg.get_mix_f32() < 0.0);
+
+        // Rewriting the period starts a new high half
+        psg.write_psgxb(PsgChannel::Channel0, 0x02);
+        assert!(psg.get_mix_f32() > 0.0);
+        psg.set_elapsed_ticks(1000 + 2 * half_period);
+        assert!(psg.get_mix_f32() < 0.0);
+    }
+
+    #[test]
+    fn output_driver_selects_what_is_heard() {
+        let mut psg = State::new();
+        psg.write_psgm(0b00);
+        psg.write_psgxa(PsgChannel::Channel0, 0xC0);
+        psg.write_psgc(0b0001_1000);
+        psg.pop_current_sample(PsgChannel::Channel0);
+        let mix = psg.get_mix_f32();
+        assert_ne!(mix, 0.0);
+
+        for (psgo, expected) in [(0b00, mix), (0b01, mix), (0b10, 0.0), (0b11, 0.0)] {
+            psg.write_psgc(0b0001_1000 | (psgo << 1));
+            assert_eq!(psg.read_psgc() & 0b110, psgo << 1);
+            assert_eq!(psg.get_mix_f32(), expected);
+        }
+
+        // Samples are silenced too, not only the instantaneous mix
+        psg.set_elapsed_ticks(1000);
+        psg.take_sample(AudioTap::Output);
+        psg.set_elapsed_ticks(2000);
+        assert_eq!(psg.take_sample(AudioTap::Output), 0.0);
+
+        // Mute silences every driver
+        psg.write_psgc(0b0001_1001);
+        assert_eq!(psg.get_mix_f32(), 0.0);
+    }
+
+    /// The level reported in the low 5 bits of PSGxB
+    fn fifo_level(psg: &State, channel: PsgChannel) -> usize {
+        (psg.read_psgxb(channel) & 0b11111) as usize
+    }
+
+    fn write_available(psg: &State, channel: PsgChannel) -> bool {
+        psg.read_psgxb(channel) & 0b00100000 != 0
+    }
+
+    #[test]
+    fn pcm_fifo_drops_writes_when_full() {
+        let mut psg = State::new();
+        psg.write_psgm(0b00);
+
+        for sample in 0..FIFO_DEPTH as u8 + 4 {
+            psg.write_psgxa(PsgChannel::Channel0, sample);
+        }
+        assert_eq!(fifo_level(&psg, PsgChannel::Channel0), FIFO_DEPTH);
+        assert!(!write_available(&psg, PsgChannel::Channel0));
+
+        // The samples that fit come out in order, and the last one is held once empty
+        psg.write_psgc(0b0001_1000);
+        for expected in 0..FIFO_DEPTH as u8 + 2 {
+            psg.pop_current_sample(PsgChannel::Channel0);
+            let expected = expected.min(FIFO_DEPTH as u8 - 1);
+            let level = (expected as f32 - 128.0) / 512.0 / 4.0;
+            assert_eq!(psg.get_mix_f32(), level);
+        }
+        assert_eq!(fifo_level(&psg, PsgChannel::Channel0), 0);
+    }
+
+    #[test]
+    fn refill_is_requested_below_the_refill_level() {
+        let mut psg = State::new();
+        psg.write_psgm(0b00);
+        psg.write_psgc(0b0001_1000);
+
+        for sample in 0..FIFO_DEPTH as u8 {
+            psg.write_psgxa(PsgChannel::Channel0, sample);
+        }
+
+        // Each pop is one timer overflow; the PCM interrupt is raised from the
+        // first one that leaves fewer than FIFO_REFILL_LEVEL samples
+        let requests: Vec<bool> = (0..FIFO_DEPTH)
+            .map(|_| psg.pop_current_sample(PsgChannel::Channel0))
+            .collect();
+        let first_request = FIFO_DEPTH - FIFO_REFILL_LEVEL;
+        assert!(requests[..first_request].iter().all(|request| !request));
+        assert!(requests[first_request..].iter().all(|request| *request));
+
+        assert_eq!(fifo_level(&psg, PsgChannel::Channel0), 0);
+        assert!(write_available(&psg, PsgChannel::Channel0));
+    }
+
+    #[test]
+    fn refill_is_not_requested_while_disabled() {
+        let mut psg = State::new();
+        psg.write_psgm(0b00);
+
+        // The channel is enabled but the DACs are not
+        psg.write_psgc(0b0001_0000);
+        assert!(!psg.pop_current_sample(PsgChannel::Channel0));
+
+        psg.write_psgc(0b0001_1000);
+        assert!(psg.pop_current_sample(PsgChannel::Channel0));
+        assert!(!psg.pop_current_sample(PsgChannel::Channel1));
+    }
+
+    /// Pops every queued ADPCM sample from channel 0
+    fn drain_adpcm(psg: &mut State) -> Vec<i16> {
+        let mut samples = Vec::new();
+        while fifo_level(psg, PsgChannel::Channel0) > 0 {
+            psg.pop_current_sample(PsgChannel::Channel0);
+            match psg.get_psg_state(PsgChannel::Channel0) {
+                PsgModeState::AdpcmDac { current_sample, .. } => samples.push(*current_sample),
+                state => panic!("Channel is in {state:?}"),
+            }
+        }
+        samples
+    }
+
+    #[test]
+    fn adpcm_steps_up_on_psgxa_and_down_on_psgxb() {
+        let mut psg = State::new();
+        psg.write_psgm(0b11);
+
+        psg.write_psgxa(PsgChannel::Channel0, 10);
+        psg.write_psgxa(PsgChannel::Channel0, 5);
+        psg.write_psgxb(PsgChannel::Channel0, 20);
+        psg.write_psgxb(PsgChannel::Channel0, 0);
+
+        assert_eq!(drain_adpcm(&mut psg), [10, 15, -5, -5]);
+    }
+
+    #[test]
+    fn adpcm_accumulator_saturates() {
+        let mut psg = State::new();
+        psg.write_psgm(0b11);
+
+        for _ in 0..3 {
+            psg.write_psgxa(PsgChannel::Channel0, 0xFF);
+        }
+        psg.write_psgxb(PsgChannel::Channel0, 1);
+        assert_eq!(drain_adpcm(&mut psg), [255, 255, 255, 254]);
+
+        for _ in 0..3 {
+            psg.write_psgxb(PsgChannel::Channel0, 0xFF);
+        }
+        psg.write_psgxa(PsgChannel::Channel0, 1);
+        assert_eq!(drain_adpcm(&mut psg), [-1, -256, -256, -255]);
+    }
+
+    #[test]
+    fn adpcm_fifo_full_writes_leave_the_accumulator() {
+        let mut psg = State::new();
+        psg.write_psgm(0b11);
+
+        for _ in 0..FIFO_DEPTH {
+            psg.write_psgxa(PsgChannel::Channel0, 1);
+        }
+        // Lost, so the next step continues from 16
+        psg.write_psgxa(PsgChannel::Channel0, 100);
+        psg.write_psgxb(PsgChannel::Channel0, 100);
+
+        let samples = drain_adpcm(&mut psg);
+        assert_eq!(samples.len(), FIFO_DEPTH);
+        psg.write_psgxa(PsgChannel::Channel0, 1);
+        assert_eq!(drain_adpcm(&mut psg), [FIFO_DEPTH as i16 + 1]);
+    }
+
+    /// Amplitude of the component of `samples` at `frequency`
+    fn amplitude_at(samples: &[f32], frequency: f64, sample_rate: f64) -> f64 {
+        let (sin, cos) = samples
+            .iter()
+            .enumerate()
+            .fold((0.0, 0.0), |(sin, cos), (i, sample)| {
+                let phase = 2.0 * std::f64::consts::PI * frequency * i as f64 / sample_rate;
+                (
+                    sin + phase.sin() * f64::from(*sample),
+                    cos + phase.cos() * f64::from(*sample),
+                )
+            });
+        2.0 * (sin * sin + cos * cos).sqrt() / samples.len() as f64
+    }
+
+    #[test]
+    fn fast_dac_updates_do_not_alias_into_the_audible_range() {
+        const SYSCK: u64 = 8_000_000;
+        const INPUT_RATE: u32 = 192_000;
+        const OUTPUT_RATE: u32 = 48_000;
+        // A timer refilling the DAC every 10 ticks makes a 400 kHz square wave, which
+        // folds down to 400 kHz - 2 * 192 kHz = 16 kHz when sampled at single instants
+        const TICKS_PER_UPDATE: u64 = 10;
+        const ALIAS: f64 = 16_000.0;
+
+        let run = |averaged: bool| -> Vec<f32> {
+            let mut psg = State::new();
+            psg.write_psgm(0b00);
+            psg.write_psgc(0b0001_1000);
+
+            let mut resampler = Resampler::new(INPUT_RATE, OUTPUT_RATE);
+            let mut output = Vec::new();
+            let mut next_sample = 0.0;
+            let mut high = false;
+
+            for tick in 0..SYSCK / 10 {
+                psg.set_elapsed_ticks(tick);
+                if tick % TICKS_PER_UPDATE == 0 {
+                    high = !high;
+                    psg.write_psgxa(PsgChannel::Channel0, if high { 0xFF } else { 0x00 });
+                    psg.pop_current_sample(PsgChannel::Channel0);
+                }
+                if tick as f64 >= next_sample {
+                    next_sample += SYSCK as f64 / INPUT_RATE as f64;
+                    let sample = psg.take_sample(AudioTap::Output);
+                    let sample = if averaged { sample } else { psg.get_mix_f32() };
+                    resampler.push([sample]);
+                    output.extend(resampler.next_sample());
+                }
+            }
+            output
+        };
+
+        let decibels = |samples: &[f32]| {
+            let full_scale = TONE_HIGH / 4.0;
+            20.0 * (amplitude_at(samples, ALIAS, OUTPUT_RATE as f64) / full_scale).log10()
+        };
+
+        // Taking the mix at one instant per sample lets the alias through at full strength
+        let point_sampled = decibels(&run(false));
+        assert!(
+            point_sampled > -6.0,
+            "Point sampled alias at {point_sampled} dB"
+        );
+
+        let filtered = decibels(&run(true));
+        assert!(filtered < -50.0, "Filtered alias at {filtered} dB");
+    }
+
+    #[test]
+    fn audible_tones_keep_their_level() {
+        const SYSCK: u64 = 8_000_000;
+        const SAMPLE_RATE: u32 = 192_000;
+        // 8 MHz / (2 * 32 * 125) = 1 kHz
+        const PERIOD: u8 = 124;
+
+        let run = |filtered: bool| -> Vec<f32> {
+            let mut psg = State::new();
+            psg.write_psgm(0b01);
+            psg.write_psgc(0b0001_0000);
+            psg.write_psgxa(PsgChannel::Channel0, PERIOD);
+
+            (0..SAMPLE_RATE / 10)
+                .map(|sample| {
+                    psg.set_elapsed_ticks(sample as u64 * SYSCK / SAMPLE_RATE as u64);
+                    let mix = psg.take_sample(AudioTap::Output);
+                    if filtered {
+                        mix
+                    } else {
+                        psg.get_mix_f32()
+                    }
+                })
+                .collect()
+        };
+
+        let point_sampled = amplitude_at(&run(false), 1000.0, SAMPLE_RATE as f64);
+        let filtered = amplitude_at(&run(true), 1000.0, SAMPLE_RATE as f64);
+        assert!((filtered / point_sampled - 1.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn recording_samples_do_not_change_the_output() {
+        const SYSCK: u64 = 8_000_000;
+        const OUTPUT_RATE: u64 = 48_000;
+        const RECORDING_RATE: u64 = 44_100;
+
+        let run = |recording: bool| -> Vec<f32> {
+            let mut psg = State::new();
+            psg.write_psgm(0b01);
+            psg.write_psgc(0b0001_0000);
+            psg.write_psgxa(PsgChannel::Channel0, 37);
+
+            let mut output = Vec::new();
+            let mut next_recording = 0;
+            for sample in 0..OUTPUT_RATE / 100 {
+                let tick = sample * SYSCK / OUTPUT_RATE;
+                while recording && next_recording * SYSCK / RECORDING_RATE < tick {
+                    psg.set_elapsed_ticks(next_recording * SYSCK / RECORDING_RATE);
+                    psg.take_sample(AudioTap::Recording);
+                    next_recording += 1;
+                }
+                psg.set_elapsed_ticks(tick);
+                output.push(psg.take_sample(AudioTap::Output));
+            }
+            output
+        };
+
+        assert_eq!(run(true), run(false));
+    }
+
+    #[test]
+    fn tone_integrals_match_summing_each_tick() {
+        for (period, start_tick, start, end) in [
+            (0, 0, 0, 1),
+            (0, 0, 0, 200),
+            (3, 10, 10, 1000),
+            (3, 10, 77, 300),
+            (7, 5, 1_000_000, 1_000_513),
+            (0xFFF, 0, 123_456, 654_321),
+        ] {
+            let mut expected = (0.0, 0.0);
+            for tick in start..end {
+                if tone_is_high(period, start_tick, tick) {
+                    // Each tick lasts until the next, so weigh it by its middle
+                    expected.0 += 1.0;
+                    expected.1 += (tick - start) as f64 + 0.5;
+                }
+            }
+
+            let (high_ticks, moment) = tone_high_integrals(period, start_tick, start, end);
+            assert_eq!(
+                high_ticks, expected.0,
+                "Period {period} from {start} to {end}"
+            );
+            assert!(
+                (moment - expected.1).abs() <= expected.1 * 1e-12,
+                "Period {period} from {start} to {end}: {moment} != {}",
+                expected.1
+            );
+        }
+    }
+}
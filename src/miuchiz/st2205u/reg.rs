@@ -1,3 +1,5 @@
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
+
 #[derive(Default, Clone, Debug)]
 pub struct U8Register {
     val: u8,
@@ -97,3 +99,26 @@ impl U16Register {
         self.h.mask()
     }
 }
+
+impl SaveState for U8Register {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.val);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.set(state.read_u8()?);
+        Ok(())
+    }
+}
+
+impl SaveState for U16Register {
+    fn save_state(&self, state: &mut StateWriter) {
+        self.l.save_state(state);
+        self.h.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.l.load_state(state)?;
+        self.h.load_state(state)
+    }
+}
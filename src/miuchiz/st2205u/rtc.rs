@@ -0,0 +1,225 @@
+use super::reg::U8Register;
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
+
+const SECONDS_PER_MINUTE: u64 = 60;
+const SECONDS_PER_HOUR: u64 = 60 * SECONDS_PER_MINUTE;
+const SECONDS_PER_DAY: u64 = 24 * SECONDS_PER_HOUR;
+
+/// The day counter is 16 bits wide
+const DAYS: u64 = 1 << 16;
+
+// RTCC bits
+const RTCC_SECOND_ENABLE: u8 = 1 << 0;
+const RTCC_ALARM_ENABLE: u8 = 1 << 1;
+const RTCC_SECOND_FLAG: u8 = 1 << 4;
+const RTCC_ALARM_FLAG: u8 = 1 << 5;
+const RTCC_RUN: u8 = 1 << 7;
+/// Bits which are cleared by writing 1 to them
+const RTCC_FLAGS: u8 = RTCC_SECOND_FLAG | RTCC_ALARM_FLAG;
+
+/// Real-time clock, counting seconds, minutes, hours and days from the
+/// oscillator. Days are counted from 1 January 2000.
+///
+/// The alarm goes off at the start of the minute which matches ALMH and ALMM.
+pub struct State {
+    /// The frequency of the clock source this clock receives
+    input_clock_frequency: u64,
+
+    /// The number of cycles at `input_clock_frequency` which have elapsed
+    elapsed_ticks: u64,
+
+    /// The time, in seconds, at `origin_tick`
+    seconds_at_origin: u64,
+    origin_tick: u64,
+    /// Whole seconds since `origin_tick` which have been counted
+    counted_seconds: u64,
+
+    rtcc: U8Register,
+    almm: U8Register,
+    almh: U8Register,
+}
+
+impl State {
+    pub fn new(clock_frequency: u64) -> Self {
+        Self {
+            input_clock_frequency: clock_frequency,
+            elapsed_ticks: 0,
+            seconds_at_origin: 0,
+            origin_tick: 0,
+            counted_seconds: 0,
+            rtcc: U8Register::new(RTCC_RUN, 0b1011_0011),
+            almm: U8Register::new(0b0000_0000, 0b0011_1111),
+            almh: U8Register::new(0b0000_0000, 0b0001_1111),
+        }
+    }
+
+    pub fn set_elapsed_ticks(&mut self, ticks: u64) {
+        self.elapsed_ticks = ticks;
+    }
+
+    fn running(&self) -> bool {
+        self.rtcc.get() & RTCC_RUN != 0
+    }
+
+    /// Seconds since the start of 1 January 2000
+    pub fn time(&self) -> u64 {
+        if !self.running() {
+            return self.seconds_at_origin;
+        }
+
+        let elapsed = (self.elapsed_ticks - self.origin_tick) / self.input_clock_frequency;
+        (self.seconds_at_origin + elapsed) % (DAYS * SECONDS_PER_DAY)
+    }
+
+    /// Sets the time, in seconds since the start of 1 January 2000. The next
+    /// second starts counting from now.
+    pub fn set_time(&mut self, seconds: u64) {
+        self.seconds_at_origin = seconds % (DAYS * SECONDS_PER_DAY);
+        self.origin_tick = self.elapsed_ticks;
+        self.counted_seconds = 0;
+    }
+
+    /// The elapsed tick count at which the next second starts, if the clock is running
+    pub fn next_tick(&self) -> Option<u64> {
+        self.running()
+            .then(|| self.origin_tick + (self.counted_seconds + 1) * self.input_clock_frequency)
+    }
+
+    /// Counts the seconds which have passed. Returns whether an interrupt should be triggered.
+    pub fn update(&mut self) -> bool {
+        let mut flags = 0;
+
+        while self
+            .next_tick()
+            .is_some_and(|next_tick| self.elapsed_ticks >= next_tick)
+        {
+            self.counted_seconds += 1;
+            flags |= RTCC_SECOND_FLAG;
+
+            let time = (self.seconds_at_origin + self.counted_seconds) % SECONDS_PER_DAY;
+            let alarm = u64::from(self.almh.get()) * SECONDS_PER_HOUR
+                + u64::from(self.almm.get()) * SECONDS_PER_MINUTE;
+            if time == alarm {
+                flags |= RTCC_ALARM_FLAG;
+            }
+        }
+
+        let rtcc = self.rtcc.get();
+        self.rtcc.set(rtcc | flags);
+
+        let second = flags & RTCC_SECOND_FLAG != 0 && rtcc & RTCC_SECOND_ENABLE != 0;
+        let alarm = flags & RTCC_ALARM_FLAG != 0 && rtcc & RTCC_ALARM_ENABLE != 0;
+        second || alarm
+    }
+
+    /// Replaces one part of the time, keeping the rest
+    fn set_field(&mut self, unit: u64, range: u64, value: impl Into<u64>) {
+        let time = self.time();
+        let old = time / unit % range;
+        let new = value.into() % range;
+        self.set_time(time - old * unit + new * unit);
+    }
+
+    pub fn read_sec(&self) -> u8 {
+        (self.time() % SECONDS_PER_MINUTE) as u8
+    }
+
+    pub fn write_sec(&mut self, value: u8) {
+        self.set_field(1, 60, value);
+    }
+
+    pub fn read_min(&self) -> u8 {
+        (self.time() / SECONDS_PER_MINUTE % 60) as u8
+    }
+
+    pub fn write_min(&mut self, value: u8) {
+        self.set_field(SECONDS_PER_MINUTE, 60, value);
+    }
+
+    pub fn read_hour(&self) -> u8 {
+        (self.time() / SECONDS_PER_HOUR % 24) as u8
+    }
+
+    pub fn write_hour(&mut self, value: u8) {
+        self.set_field(SECONDS_PER_HOUR, 24, value);
+    }
+
+    pub fn read_dayl(&self) -> u8 {
+        (self.time() / SECONDS_PER_DAY) as u8
+    }
+
+    pub fn write_dayl(&mut self, value: u8) {
+        let days = (self.time() / SECONDS_PER_DAY) as u16;
+        let days = (days & 0xFF00) | u16::from(value);
+        self.set_field(SECONDS_PER_DAY, DAYS, days);
+    }
+
+    pub fn read_dayh(&self) -> u8 {
+        ((self.time() / SECONDS_PER_DAY) >> 8) as u8
+    }
+
+    pub fn write_dayh(&mut self, value: u8) {
+        let days = (self.time() / SECONDS_PER_DAY) as u16;
+        let days = (days & 0x00FF) | u16::from(value) << 8;
+        self.set_field(SECONDS_PER_DAY, DAYS, days);
+    }
+
+    pub fn read_almm(&self) -> u8 {
+        self.almm.get()
+    }
+
+    pub fn write_almm(&mut self, value: u8) {
+        self.almm.set(value);
+    }
+
+    pub fn read_almh(&self) -> u8 {
+        self.almh.get()
+    }
+
+    pub fn write_almh(&mut self, value: u8) {
+        self.almh.set(value);
+    }
+
+    pub fn read_rtcc(&self) -> u8 {
+        self.rtcc.get()
+    }
+
+    pub fn write_rtcc(&mut self, value: u8) {
+        let time = self.time();
+        let was_running = self.running();
+
+        // Writing 1 to a flag clears it
+        let flags = self.rtcc.get() & RTCC_FLAGS & !value;
+        self.rtcc.set((value & !RTCC_FLAGS) | flags);
+
+        // Stopping holds the time, and starting counts on from it
+        if self.running() != was_running {
+            self.set_time(time);
+        }
+    }
+}
+
+impl SaveState for State {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u64(self.elapsed_ticks);
+        state.write_u64(self.seconds_at_origin);
+        state.write_u64(self.origin_tick);
+        state.write_u64(self.counted_seconds);
+        self.rtcc.save_state(state);
+        self.almm.save_state(state);
+        self.almh.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.elapsed_ticks = state.read_u64()?;
+        self.seconds_at_origin = state.read_u64()?;
+        self.origin_tick = state.read_u64()?;
+        self.counted_seconds = state.read_u64()?;
+        if self.origin_tick > self.elapsed_ticks {
+            return Err(StateError::InvalidValue("RTC origin"));
+        }
+        self.rtcc.load_state(state)?;
+        self.almm.load_state(state)?;
+        self.almh.load_state(state)
+    }
+}
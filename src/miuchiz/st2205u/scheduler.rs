@@ -0,0 +1,54 @@
+/// Things which peripherals need to do at a known instruction cycle
+#[derive(Clone, Copy)]
+pub enum Event {
+    BaseTimerTick,
+    TimerOverflow,
+    AudioSample,
+    GpioPoll,
+    ExternalClock,
+    Uart,
+    Spi,
+    Usb,
+    Rtc,
+    Lcd,
+}
+
+impl Event {
+    const COUNT: usize = 10;
+}
+
+/// Keeps the instruction cycle at which each kind of event is next due, so
+/// that peripherals only need to be serviced when something will happen.
+///
+/// The schedule is not part of the save state; it can be worked out again
+/// from the state of the peripherals.
+pub struct Scheduler {
+    due: [u64; Event::COUNT],
+    /// The earliest of `due`
+    next: u64,
+}
+
+impl Scheduler {
+    /// Creates a schedule with every event due straight away
+    pub fn new() -> Self {
+        Self {
+            due: [0; Event::COUNT],
+            next: 0,
+        }
+    }
+
+    /// Sets the cycle at which `event` is next due, or `None` if it will not happen
+    pub fn schedule(&mut self, event: Event, cycle: Option<u64>) {
+        self.due[event as usize] = cycle.unwrap_or(u64::MAX);
+        self.next = self.due.iter().copied().min().unwrap_or(u64::MAX);
+    }
+
+    /// The cycle at which the earliest event is due
+    pub fn next_cycle(&self) -> u64 {
+        self.next
+    }
+
+    pub fn is_due(&self, event: Event, current_cycle: u64) -> bool {
+        self.due[event as usize] <= current_cycle
+    }
+}
@@ -0,0 +1,278 @@
+use super::reg::U8Register;
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
+
+// SCTR bits
+/// Level of the chip select line. The device is selected while it is low.
+const SCTR_CHIP_SELECT: u8 = 1 << 0;
+const SCTR_ENABLE: u8 = 1 << 7;
+
+// SSR bits
+const SSR_TX_EMPTY: u8 = 1 << 0;
+const SSR_RX_READY: u8 = 1 << 1;
+const SSR_BUSY: u8 = 1 << 2;
+const SSR_OVERRUN: u8 = 1 << 3;
+
+/// SMOD bit which sends and receives the least significant bit first
+const SMOD_LSB_FIRST: u8 = 1 << 2;
+
+/// What the bus reads when no device drives it
+const IDLE_BUS: u8 = 0xFF;
+
+/// A peripheral on the SPI bus
+pub trait SpiDevice: SaveState {
+    /// Called when the chip select line changes
+    fn select(&mut self, selected: bool);
+
+    /// Takes the byte the ST2205U sent while selected, and returns the one it receives
+    fn exchange(&mut self, value: u8) -> u8;
+
+    /// The device's non-volatile contents, if it has any worth keeping between runs
+    fn storage(&self) -> Option<&[u8]> {
+        None
+    }
+}
+
+/// What the SPI wants from the interrupt controller after an update
+#[derive(Default)]
+pub struct SpiRequests {
+    /// The transmit data register has room for another byte
+    pub tx: bool,
+    /// A byte has been received
+    pub rx: bool,
+}
+
+/// SPI master, with at most one device attached.
+///
+/// Each byte is sent and received at once, 8 bit clocks after it starts. The bit
+/// clock is SYSCK / (2 × (SCKR + 1)). The clock polarity and phase bits of SMOD
+/// are kept, but make no difference to the bytes a device sees.
+pub struct State {
+    device: Option<Box<dyn SpiDevice>>,
+
+    sctr: U8Register,
+    sckr: U8Register,
+    smod: U8Register,
+    overrun: bool,
+
+    /// Transmit data register, waiting to be moved into the shift register
+    tx_data: Option<u8>,
+    /// Byte being exchanged, and the cycle it finishes on
+    shifting: Option<(u8, u64)>,
+
+    /// Receive data register
+    rx_data: u8,
+    rx_ready: bool,
+
+    current_cycle: u64,
+}
+
+impl State {
+    pub fn new() -> Self {
+        Self {
+            device: None,
+            sctr: U8Register::new(SCTR_CHIP_SELECT, 0b1000_0001),
+            sckr: U8Register::new(0b0000_0000, 0b1111_1111),
+            smod: U8Register::new(0b0000_0000, 0b0000_0111),
+            overrun: false,
+            tx_data: None,
+            shifting: None,
+            rx_data: 0,
+            rx_ready: false,
+            current_cycle: 0,
+        }
+    }
+
+    /// Attaches `device` to the bus, in place of any other
+    pub fn attach(&mut self, mut device: Box<dyn SpiDevice>) {
+        device.select(self.selected());
+        self.device = Some(device);
+    }
+
+    pub fn device(&self) -> Option<&dyn SpiDevice> {
+        self.device.as_deref()
+    }
+
+    pub fn set_current_cycle(&mut self, cycle: u64) {
+        self.current_cycle = cycle;
+    }
+
+    fn enabled(&self) -> bool {
+        self.sctr.get() & SCTR_ENABLE != 0
+    }
+
+    fn selected(&self) -> bool {
+        self.sctr.get() & SCTR_CHIP_SELECT == 0
+    }
+
+    /// Instruction cycles taken to exchange one byte
+    fn byte_cycles(&self) -> u64 {
+        8 * 2 * (u64::from(self.sckr.get()) + 1)
+    }
+
+    /// Puts a byte in the order it goes over the wire, or back again
+    fn wire_order(&self, value: u8) -> u8 {
+        if self.smod.get() & SMOD_LSB_FIRST != 0 {
+            value.reverse_bits()
+        } else {
+            value
+        }
+    }
+
+    /// Finishes and starts transfers which are due. Returns the interrupts to request.
+    pub fn update(&mut self) -> SpiRequests {
+        let mut requests = SpiRequests::default();
+        let now = self.current_cycle;
+
+        if let Some((byte, done)) = self.shifting {
+            if now >= done {
+                let sent = self.wire_order(byte);
+                let selected = self.selected();
+                let received = match &mut self.device {
+                    Some(device) if selected => device.exchange(sent),
+                    _ => IDLE_BUS,
+                };
+
+                if self.rx_ready {
+                    // The last byte was never read, so this one is lost
+                    self.overrun = true;
+                } else {
+                    self.rx_data = self.wire_order(received);
+                    self.rx_ready = true;
+                    requests.rx = true;
+                }
+                self.shifting = None;
+            }
+        }
+
+        if self.shifting.is_none() && self.enabled() {
+            if let Some(byte) = self.tx_data.take() {
+                self.shifting = Some((byte, now + self.byte_cycles()));
+                requests.tx = true;
+            }
+        }
+
+        requests
+    }
+
+    /// The instruction cycle at which `update` next has something to do
+    pub fn next_event_cycle(&self) -> Option<u64> {
+        match (self.shifting, self.tx_data) {
+            (Some((_, done)), _) => Some(done),
+            (None, Some(_)) if self.enabled() => Some(self.current_cycle),
+            _ => None,
+        }
+    }
+
+    pub fn read_sctr(&self) -> u8 {
+        self.sctr.get()
+    }
+
+    pub fn write_sctr(&mut self, value: u8) {
+        let was_selected = self.selected();
+        self.sctr.set(value);
+
+        let selected = self.selected();
+        if selected != was_selected {
+            if let Some(device) = &mut self.device {
+                device.select(selected);
+            }
+        }
+    }
+
+    pub fn read_sckr(&self) -> u8 {
+        self.sckr.get()
+    }
+
+    pub fn write_sckr(&mut self, value: u8) {
+        self.sckr.set(value);
+    }
+
+    pub fn read_ssr(&self) -> u8 {
+        let mut ssr = 0;
+        if self.tx_data.is_none() {
+            ssr |= SSR_TX_EMPTY;
+        }
+        if self.rx_ready {
+            ssr |= SSR_RX_READY;
+        }
+        if self.shifting.is_some() {
+            ssr |= SSR_BUSY;
+        }
+        if self.overrun {
+            ssr |= SSR_OVERRUN;
+        }
+        ssr
+    }
+
+    pub fn write_ssr(&mut self, value: u8) {
+        // Writing 1 to the overrun bit clears it
+        if value & SSR_OVERRUN != 0 {
+            self.overrun = false;
+        }
+    }
+
+    pub fn read_smod(&self) -> u8 {
+        self.smod.get()
+    }
+
+    pub fn write_smod(&mut self, value: u8) {
+        self.smod.set(value);
+    }
+
+    pub fn read_sdata(&mut self) -> u8 {
+        self.rx_ready = false;
+        self.rx_data
+    }
+
+    pub fn write_sdata(&mut self, value: u8) {
+        // A byte written while the register is still full replaces the old one
+        self.tx_data = Some(value);
+    }
+}
+
+impl SaveState for State {
+    fn save_state(&self, state: &mut StateWriter) {
+        self.sctr.save_state(state);
+        self.sckr.save_state(state);
+        self.smod.save_state(state);
+        state.write_bool(self.overrun);
+        state.write_bool(self.tx_data.is_some());
+        state.write_u8(self.tx_data.unwrap_or_default());
+        state.write_bool(self.shifting.is_some());
+        let (byte, done) = self.shifting.unwrap_or_default();
+        state.write_u8(byte);
+        state.write_u64(done);
+        state.write_u8(self.rx_data);
+        state.write_bool(self.rx_ready);
+
+        state.write_bool(self.device.is_some());
+        if let Some(device) = &self.device {
+            device.save_state(state);
+        }
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.sctr.load_state(state)?;
+        self.sckr.load_state(state)?;
+        self.smod.load_state(state)?;
+        self.overrun = state.read_bool()?;
+        let tx_full = state.read_bool()?;
+        let tx_data = state.read_u8()?;
+        self.tx_data = tx_full.then_some(tx_data);
+        let shifting = state.read_bool()?;
+        let byte = state.read_u8()?;
+        let done = state.read_u64()?;
+        self.shifting = shifting.then_some((byte, done));
+        self.rx_data = state.read_u8()?;
+        self.rx_ready = state.read_bool()?;
+
+        // The state can only be restored onto the same hardware
+        if state.read_bool()? != self.device.is_some() {
+            return Err(StateError::InvalidValue("SPI device"));
+        }
+        if let Some(device) = &mut self.device {
+            device.load_state(state)?;
+        }
+        Ok(())
+    }
+}
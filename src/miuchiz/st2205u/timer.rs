@@ -1,18 +1,35 @@
+use super::base_timer::BGRCK_FREQUENCY;
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
+
+/// Clock select for counting edges on the external clock pin
+const EXTERNAL_CLOCK: u8 = 7;
+
+#[derive(Clone)]
 pub struct TimerState {
     counter: u16, // 12-bit counter
     reload_value: u16,
     clock_select: u8,
     enabled: bool,
     auto_reload: bool,
+    /// Level of the timer's output, which toggles on each overflow
+    output: bool,
 }
 
+#[derive(Clone)]
 pub struct TimerBlocksState {
     t0: TimerState,
     t1: TimerState,
     t2: TimerState,
     t3: TimerState,
+    /// T4 has no interrupt or PSG channel of its own
+    t4: TimerState,
+    /// Frequency of the SYSCK ticks the timers are given
+    sysck_frequency: u64,
     elapsed_ticks: u64,
+    /// The tick the counters have been brought up to
     previous_elapsed_ticks: u64,
+    /// Timers which have overflowed since `update` last returned
+    pending_overflows: u8,
 }
 
 pub enum TimerIndex {
@@ -20,17 +37,41 @@ pub enum TimerIndex {
     T1,
     T2,
     T3,
+    T4,
+}
+
+/// The rate of a timer's clock, as `numerator` increments every `denominator` SYSCK ticks
+#[derive(Clone, Copy)]
+struct Rate {
+    numerator: u64,
+    denominator: u64,
+}
+
+impl Rate {
+    /// Number of increments there have been by `tick`
+    fn increments_by(&self, tick: u64) -> u64 {
+        (u128::from(tick) * u128::from(self.numerator) / u128::from(self.denominator)) as u64
+    }
+
+    /// The first tick by which there have been `increments` increments
+    fn tick_of(&self, increments: u64) -> u64 {
+        (u128::from(increments) * u128::from(self.denominator)).div_ceil(u128::from(self.numerator))
+            as u64
+    }
 }
 
 impl TimerBlocksState {
-    pub fn new() -> Self {
+    pub fn new(sysck_frequency: u64) -> Self {
         Self {
             t0: TimerState::new(),
             t1: TimerState::new(),
             t2: TimerState::new(),
             t3: TimerState::new(),
+            t4: TimerState::new(),
+            sysck_frequency,
             elapsed_ticks: 0,
             previous_elapsed_ticks: 0,
+            pending_overflows: 0,
         }
     }
 
@@ -38,103 +79,131 @@ impl TimerBlocksState {
         self.elapsed_ticks = sysck;
     }
 
+    /// Brings the timers up to date. Returns a bit for each timer which has
+    /// overflowed since the last update.
     pub fn update(&mut self) -> u8 {
-        let num_ticks = self.elapsed_ticks - self.previous_elapsed_ticks;
-        let mut interrupts = 0;
-        self.elapsed_ticks = self.previous_elapsed_ticks;
-
-        // TODO: do this without a loop?
-        for _ in 0..num_ticks {
-            self.elapsed_ticks += 1;
-            interrupts |= self.update2();
-        }
-
-        self.previous_elapsed_ticks = self.elapsed_ticks;
-        interrupts
+        self.sync();
+        std::mem::take(&mut self.pending_overflows)
     }
 
-    pub fn update2(&mut self) -> u8 {
-        let mut interrupts = 0;
+    /// Advances the counters to the elapsed tick count in one go
+    fn sync(&mut self) {
+        let from = self.previous_elapsed_ticks;
+        let to = self.elapsed_ticks;
+        if to <= from {
+            return;
+        }
 
-        for (i, timer) in [&mut self.t0, &mut self.t1, &mut self.t2, &mut self.t3]
-            .iter_mut()
-            .enumerate()
-        {
-            if !timer.enabled {
-                continue;
+        let sysck_frequency = self.sysck_frequency;
+        let mut overflows = 0;
+        for (i, timer) in self.timers_mut().into_iter().enumerate() {
+            if timer.advance(from, to, sysck_frequency) {
+                overflows |= 1 << i;
             }
+        }
 
-            let should_increment = match timer.clock_select {
-                0 => self.elapsed_ticks % 2 == 0,    // SYSCK/2
-                1 => self.elapsed_ticks % 4 == 0,    // SYSCK/4
-                2 => self.elapsed_ticks % 8 == 0,    // SYSCK/8
-                3 => self.elapsed_ticks % 32 == 0,   // SYSCK/32
-                4 => self.elapsed_ticks % 1024 == 0, // SYSCK/1024
-                5 => self.elapsed_ticks % 4096 == 0, // SYSCK/4096
-                6 => false,                          // BGRCK (not implemented)
-                7 => false,                          // External clock (not implemented)
-                _ => false,
-            };
-
-            if should_increment {
-                let updated_counter16 = timer.counter + 1;
-                let updated_counter12 = updated_counter16 & 0x0FFF;
-                let overflowed = updated_counter12 != updated_counter16; // 12-bit overflow
-                timer.counter = updated_counter12;
-
-                if overflowed {
-                    interrupts |= 1 << i;
-                    if timer.auto_reload {
-                        timer.counter = timer.reload_value;
-                    } else {
-                        timer.counter = 0;
-                    }
-                }
+        self.pending_overflows |= overflows;
+        self.previous_elapsed_ticks = to;
+    }
+
+    /// Counts `edges` rising edges on the external clock pin, for timers which use it
+    pub fn clock_external(&mut self, edges: u64) {
+        self.sync();
+        let mut overflows = 0;
+        for (i, timer) in self.timers_mut().into_iter().enumerate() {
+            if timer.enabled && timer.clock_select == EXTERNAL_CLOCK && timer.count(edges) {
+                overflows |= 1 << i;
             }
         }
+        self.pending_overflows |= overflows;
+    }
+
+    /// The level of each timer's output, one bit per timer
+    pub fn outputs(&mut self) -> u8 {
+        self.sync();
+        self.timers()
+            .into_iter()
+            .enumerate()
+            .fold(0, |outputs, (i, timer)| outputs | (timer.output as u8) << i)
+    }
+
+    fn timers(&self) -> [&TimerState; 5] {
+        [&self.t0, &self.t1, &self.t2, &self.t3, &self.t4]
+    }
 
-        interrupts
+    fn timers_mut(&mut self) -> [&mut TimerState; 5] {
+        [
+            &mut self.t0,
+            &mut self.t1,
+            &mut self.t2,
+            &mut self.t3,
+            &mut self.t4,
+        ]
     }
 
-    pub fn read_txcl(&self, timer: TimerIndex) -> u8 {
-        let timer = match timer {
+    fn timer(&self, timer: TimerIndex) -> &TimerState {
+        match timer {
             TimerIndex::T0 => &self.t0,
             TimerIndex::T1 => &self.t1,
             TimerIndex::T2 => &self.t2,
             TimerIndex::T3 => &self.t3,
-        };
-        (timer.counter & 0xFF) as u8
+            TimerIndex::T4 => &self.t4,
+        }
     }
 
-    pub fn write_txcl(&mut self, timer: TimerIndex, value: u8) {
-        let timer = match timer {
+    fn timer_mut(&mut self, timer: TimerIndex) -> &mut TimerState {
+        match timer {
             TimerIndex::T0 => &mut self.t0,
             TimerIndex::T1 => &mut self.t1,
             TimerIndex::T2 => &mut self.t2,
             TimerIndex::T3 => &mut self.t3,
-        };
+            TimerIndex::T4 => &mut self.t4,
+        }
+    }
+
+    /// The elapsed tick count at which the next timer overflow will occur, if
+    /// any enabled timer is counting. Overflows from the external clock cannot
+    /// be predicted.
+    pub fn next_overflow_tick(&mut self) -> Option<u64> {
+        self.sync();
+        if self.pending_overflows != 0 {
+            return Some(self.elapsed_ticks);
+        }
+
+        self.timers()
+            .into_iter()
+            .filter(|timer| timer.enabled)
+            .filter_map(|timer| {
+                let rate = timer.rate(self.sysck_frequency)?;
+                let increments_to_overflow = 0x1000 - u64::from(timer.counter);
+                Some(rate.tick_of(rate.increments_by(self.elapsed_ticks) + increments_to_overflow))
+            })
+            .min()
+    }
+
+    pub fn read_txcl(&mut self, timer: TimerIndex) -> u8 {
+        self.sync();
+        let timer = self.timer(timer);
+        (timer.counter & 0xFF) as u8
+    }
+
+    pub fn write_txcl(&mut self, timer: TimerIndex, value: u8) {
+        self.sync();
+        let timer = self.timer_mut(timer);
         timer.counter = (timer.counter & 0xFF00) | value as u16;
         timer.reload_value = (timer.reload_value & 0xFF00) | value as u16;
     }
 
-    pub fn read_txch(&self, timer: TimerIndex) -> u8 {
-        let timer = match timer {
-            TimerIndex::T0 => &self.t0,
-            TimerIndex::T1 => &self.t1,
-            TimerIndex::T2 => &self.t2,
-            TimerIndex::T3 => &self.t3,
-        };
+    pub fn read_txch(&mut self, timer: TimerIndex) -> u8 {
+        self.sync();
+        let timer = self.timer(timer);
         let auto_reload = if timer.auto_reload { 0x80 } else { 0 };
         auto_reload | (timer.clock_select << 4) | ((timer.counter >> 8) & 0x0F) as u8
     }
 
     pub fn write_txch(&mut self, timer: TimerIndex, value: u8) {
-        let timer = match timer {
-            TimerIndex::T0 => &mut self.t0,
-            TimerIndex::T1 => &mut self.t1,
-            TimerIndex::T2 => &mut self.t2,
-            TimerIndex::T3 => &mut self.t3,
-        };
+        self.sync();
+        let timer = self.timer_mut(timer);
         timer.auto_reload = (value & 0x80) != 0;
         timer.clock_select = (value >> 4) & 0x07;
         timer.counter = (timer.counter & 0x00FF) | ((value as u16 & 0x0F) << 8);
@@ -146,14 +215,16 @@ impl TimerBlocksState {
             | (self.t1.enabled as u8) << 1
             | (self.t2.enabled as u8) << 2
             | (self.t3.enabled as u8) << 3
+            | (self.t4.enabled as u8) << 4
     }
 
     pub fn write_tien(&mut self, value: u8) {
+        self.sync();
         self.t0.enabled = (value & 0b00000001) != 0;
         self.t1.enabled = (value & 0b00000010) != 0;
         self.t2.enabled = (value & 0b00000100) != 0;
         self.t3.enabled = (value & 0b00001000) != 0;
-        // todo: T4 is not implemented
+        self.t4.enabled = (value & 0b00010000) != 0;
     }
 }
 
@@ -165,6 +236,246 @@ impl TimerState {
             clock_select: 0,
             enabled: false,
             auto_reload: false,
+            output: false,
+        }
+    }
+
+    /// Counts the increments due in the ticks after `from`, up to and including `to`.
+    /// Returns whether the counter overflowed.
+    fn advance(&mut self, from: u64, to: u64, sysck_frequency: u64) -> bool {
+        if !self.enabled {
+            return false;
         }
+        let Some(rate) = self.rate(sysck_frequency) else {
+            return false;
+        };
+
+        self.count(rate.increments_by(to) - rate.increments_by(from))
+    }
+
+    /// Adds `increments` to the counter. Returns whether it overflowed.
+    fn count(&mut self, increments: u64) -> bool {
+        let increments_to_overflow = 0x1000 - u64::from(self.counter);
+        if increments < increments_to_overflow {
+            self.counter += increments as u16;
+            return false;
+        }
+
+        // Each overflow starts the count again from the reload value
+        let start = if self.auto_reload {
+            self.reload_value
+        } else {
+            0
+        };
+        let period = 0x1000 - u64::from(start);
+        let remaining = increments - increments_to_overflow;
+        self.counter = start + (remaining % period) as u16;
+
+        let overflows = 1 + remaining / period;
+        self.output ^= overflows & 1 == 1;
+        true
+    }
+
+    /// How fast the counter increments, unless it is clocked externally
+    fn rate(&self, sysck_frequency: u64) -> Option<Rate> {
+        let divided = |divisor| Rate {
+            numerator: 1,
+            denominator: divisor,
+        };
+
+        match self.clock_select {
+            0 => Some(divided(2)),    // SYSCK/2
+            1 => Some(divided(4)),    // SYSCK/4
+            2 => Some(divided(8)),    // SYSCK/8
+            3 => Some(divided(32)),   // SYSCK/32
+            4 => Some(divided(1024)), // SYSCK/1024
+            5 => Some(divided(4096)), // SYSCK/4096
+            6 => Some(Rate {
+                // BGRCK, which also drives the base timer
+                numerator: BGRCK_FREQUENCY,
+                denominator: sysck_frequency,
+            }),
+            _ => None, // External clock
+        }
+    }
+}
+
+impl SaveState for TimerState {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u16(self.counter);
+        state.write_u16(self.reload_value);
+        state.write_u8(self.clock_select);
+        state.write_bool(self.enabled);
+        state.write_bool(self.auto_reload);
+        state.write_bool(self.output);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.counter = state.read_u16()? & 0x0FFF;
+        self.reload_value = state.read_u16()? & 0x0FFF;
+        self.clock_select = state.read_u8()? & 0x07;
+        self.enabled = state.read_bool()?;
+        self.auto_reload = state.read_bool()?;
+        self.output = state.read_bool()?;
+        Ok(())
+    }
+}
+
+impl SaveState for TimerBlocksState {
+    fn save_state(&self, state: &mut StateWriter) {
+        // Counters are only brought up to date when needed, so save them as they are now
+        let mut synced = self.clone();
+        synced.sync();
+
+        for timer in synced.timers() {
+            timer.save_state(state);
+        }
+        state.write_u64(synced.elapsed_ticks);
+        state.write_u64(synced.previous_elapsed_ticks);
+        state.write_u8(synced.pending_overflows);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        for timer in self.timers_mut() {
+            timer.load_state(state)?;
+        }
+        self.elapsed_ticks = state.read_u64()?;
+        self.previous_elapsed_ticks = state.read_u64()?;
+        self.pending_overflows = state.read_u8()? & 0x1F;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SYSCK: u64 = 8_000_000;
+
+    /// Starts T0 counting up from `counter` with the given clock select
+    fn start_t0(timers: &mut TimerBlocksState, clock_select: u8, auto_reload: bool, counter: u16) {
+        let auto_reload = if auto_reload { 0x80 } else { 0 };
+        timers.write_txcl(TimerIndex::T0, (counter & 0xFF) as u8);
+        timers.write_txch(
+            TimerIndex::T0,
+            auto_reload | (clock_select << 4) | (counter >> 8) as u8,
+        );
+        timers.write_tien(0b00001);
+    }
+
+    fn t0_counter(timers: &mut TimerBlocksState) -> u16 {
+        let low = timers.read_txcl(TimerIndex::T0);
+        let high = timers.read_txch(TimerIndex::T0) & 0x0F;
+        u16::from(high) << 8 | u16::from(low)
+    }
+
+    #[test]
+    fn overflows_after_4096_counts_at_each_prescaler() {
+        for (clock_select, divisor) in [(0, 2), (1, 4), (2, 8), (3, 32), (4, 1024), (5, 4096)] {
+            let mut timers = TimerBlocksState::new(SYSCK);
+            start_t0(&mut timers, clock_select, false, 0);
+
+            let overflow = 0x1000 * divisor;
+            assert_eq!(timers.next_overflow_tick(), Some(overflow));
+
+            timers.set_elapsed_ticks(overflow - 1);
+            assert_eq!(timers.update(), 0, "Clock select {clock_select}");
+            assert_eq!(t0_counter(&mut timers), 0xFFF);
+
+            timers.set_elapsed_ticks(overflow);
+            assert_eq!(timers.update(), 0b00001, "Clock select {clock_select}");
+            assert_eq!(t0_counter(&mut timers), 0);
+        }
+    }
+
+    #[test]
+    fn bgrck_counts_at_32768_hz() {
+        let mut timers = TimerBlocksState::new(SYSCK);
+        start_t0(&mut timers, 6, false, 0);
+
+        // 4096 counts at 32768 Hz is an eighth of a second
+        let overflow = SYSCK / 8;
+        assert_eq!(timers.next_overflow_tick(), Some(overflow));
+
+        // One and a half BGRCK periods in
+        timers.set_elapsed_ticks(SYSCK * 3 / BGRCK_FREQUENCY / 2);
+        assert_eq!(t0_counter(&mut timers), 1);
+
+        timers.set_elapsed_ticks(overflow - 1);
+        assert_eq!(timers.update(), 0);
+        timers.set_elapsed_ticks(overflow);
+        assert_eq!(timers.update(), 0b00001);
+    }
+
+    #[test]
+    fn auto_reload_restarts_from_the_reload_value() {
+        let mut timers = TimerBlocksState::new(SYSCK);
+        start_t0(&mut timers, 0, true, 0xF00);
+
+        // 0x100 counts of SYSCK/2 to each overflow
+        assert_eq!(timers.next_overflow_tick(), Some(0x200));
+        timers.set_elapsed_ticks(0x200);
+        assert_eq!(timers.update(), 0b00001);
+        assert_eq!(t0_counter(&mut timers), 0xF00);
+        assert_eq!(timers.next_overflow_tick(), Some(0x400));
+
+        // Several overflows between updates still leave the counter in the right place
+        timers.set_elapsed_ticks(0x400 + 0x200 * 3 + 0x20);
+        assert_eq!(timers.update(), 0b00001);
+        assert_eq!(t0_counter(&mut timers), 0xF10);
+    }
+
+    #[test]
+    fn without_auto_reload_counts_from_zero() {
+        let mut timers = TimerBlocksState::new(SYSCK);
+        start_t0(&mut timers, 0, false, 0xF00);
+
+        timers.set_elapsed_ticks(0x200);
+        assert_eq!(timers.update(), 0b00001);
+        assert_eq!(t0_counter(&mut timers), 0);
+        assert_eq!(timers.next_overflow_tick(), Some(0x200 + 0x2000));
+    }
+
+    #[test]
+    fn output_toggles_on_each_overflow() {
+        let mut timers = TimerBlocksState::new(SYSCK);
+        start_t0(&mut timers, 0, true, 0xF00);
+        assert_eq!(timers.outputs(), 0);
+
+        timers.set_elapsed_ticks(0x200);
+        assert_eq!(timers.outputs(), 0b00001);
+
+        timers.set_elapsed_ticks(0x400);
+        assert_eq!(timers.outputs(), 0);
+
+        // Three overflows at once leave it toggled, two leave it as it was
+        timers.set_elapsed_ticks(0xA00);
+        assert_eq!(timers.outputs(), 0b00001);
+        timers.set_elapsed_ticks(0xE00);
+        assert_eq!(timers.outputs(), 0b00001);
+    }
+
+    #[test]
+    fn disabled_timers_hold_their_count() {
+        let mut timers = TimerBlocksState::new(SYSCK);
+        start_t0(&mut timers, 0, false, 0x123);
+        timers.write_tien(0);
+
+        timers.set_elapsed_ticks(0x10000);
+        assert_eq!(timers.update(), 0);
+        assert_eq!(timers.next_overflow_tick(), None);
+        assert_eq!(t0_counter(&mut timers), 0x123);
+    }
+
+    #[test]
+    fn t4_overflows_on_bit_4() {
+        let mut timers = TimerBlocksState::new(SYSCK);
+        timers.write_txch(TimerIndex::T4, 0x0F);
+        timers.write_txcl(TimerIndex::T4, 0xFF);
+        timers.write_tien(0b10000);
+
+        timers.set_elapsed_ticks(2);
+        assert_eq!(timers.update(), 0b10000);
+        assert_eq!(timers.outputs(), 0b10000);
     }
 }
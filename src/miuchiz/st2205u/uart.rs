@@ -0,0 +1,456 @@
+use super::reg::U8Register;
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
+use crate::serial::SerialInterface;
+
+// UCTR bits
+const UCTR_RX_ENABLE: u8 = 1 << 0;
+const UCTR_TX_ENABLE: u8 = 1 << 1;
+const UCTR_PARITY_ENABLE: u8 = 1 << 2;
+const UCTR_TWO_STOP_BITS: u8 = 1 << 4;
+
+// USR bits
+const USR_RX_READY: u8 = 1 << 0;
+const USR_TX_EMPTY: u8 = 1 << 1;
+const USR_TX_COMPLETE: u8 = 1 << 2;
+const USR_OVERRUN: u8 = 1 << 3;
+/// Bits which are cleared by writing 1 to them. The host's line never has
+/// parity or framing errors, so only an overrun can set one.
+const USR_ERRORS: u8 = 0b0011_1000;
+
+/// BCTR bit which runs the baud rate generator
+const BCTR_ENABLE: u8 = 1 << 7;
+
+/// The baud rate generator divides SYSCK by this as well as by its divisor
+const SAMPLES_PER_BIT: u64 = 16;
+
+/// Instruction cycles between checks for bytes from the host while the line is idle
+const RX_POLL_INTERVAL: u64 = 1024;
+
+/// What the UART wants from the interrupt controller after an update
+#[derive(Default)]
+pub struct UartRequests {
+    /// The transmit data register has room for another byte
+    pub tx: bool,
+    /// A byte has been received
+    pub rx: bool,
+}
+
+/// Asynchronous serial port, connected to the host through a `SerialInterface`.
+///
+/// Bytes are sent and received one frame time after they start, with the frame
+/// made up of a start bit, 8 data bits, an optional parity bit and 1 or 2 stop
+/// bits. The baud rate is SYSCK / (16 × (divisor + 1)), where the 12 bit divisor
+/// is BRS (high 4 bits) and BDIV (low 8 bits).
+pub struct State {
+    serial: Option<Box<dyn SerialInterface>>,
+
+    uctr: U8Register,
+    /// IrDA encoding makes no difference to the bytes, so it is only kept
+    irctr: U8Register,
+    bctr: U8Register,
+    brs: U8Register,
+    bdiv: U8Register,
+    errors: u8,
+
+    /// Transmit data register, waiting to be moved into the shift register
+    tx_data: Option<u8>,
+    /// Byte being transmitted, and the cycle it finishes on
+    tx_shifting: Option<(u8, u64)>,
+
+    /// Receive data register
+    rx_data: u8,
+    rx_ready: bool,
+    /// Byte being received, and the cycle it finishes on
+    rx_shifting: Option<(u8, u64)>,
+    /// When to next ask the host for a byte
+    next_rx_poll: u64,
+
+    current_cycle: u64,
+}
+
+impl State {
+    pub fn new() -> Self {
+        Self {
+            serial: None,
+            uctr: U8Register::new(0b0000_0000, 0b0001_1111),
+            irctr: U8Register::new(0b0000_0000, 0b1111_1111),
+            bctr: U8Register::new(0b0000_0000, 0b1000_0000),
+            brs: U8Register::new(0b0000_0000, 0b0000_1111),
+            bdiv: U8Register::new(0b0000_0000, 0b1111_1111),
+            errors: 0,
+            tx_data: None,
+            tx_shifting: None,
+            rx_data: 0,
+            rx_ready: false,
+            rx_shifting: None,
+            next_rx_poll: 0,
+            current_cycle: 0,
+        }
+    }
+
+    /// Connects the serial line to the host. Until then, transmitted bytes are
+    /// lost and nothing is received.
+    pub fn connect(&mut self, serial: Box<dyn SerialInterface>) {
+        self.serial = Some(serial);
+    }
+
+    pub fn set_current_cycle(&mut self, cycle: u64) {
+        self.current_cycle = cycle;
+    }
+
+    fn tx_enabled(&self) -> bool {
+        self.bctr.get() & BCTR_ENABLE != 0 && self.uctr.get() & UCTR_TX_ENABLE != 0
+    }
+
+    fn rx_enabled(&self) -> bool {
+        self.bctr.get() & BCTR_ENABLE != 0 && self.uctr.get() & UCTR_RX_ENABLE != 0
+    }
+
+    /// Instruction cycles taken to send or receive one byte
+    fn frame_cycles(&self) -> u64 {
+        let divisor = (u64::from(self.brs.get()) << 8 | u64::from(self.bdiv.get())) + 1;
+        let bit_cycles = SAMPLES_PER_BIT * divisor;
+
+        let uctr = self.uctr.get();
+        let parity_bits = u64::from(uctr & UCTR_PARITY_ENABLE != 0);
+        let stop_bits = if uctr & UCTR_TWO_STOP_BITS != 0 { 2 } else { 1 };
+
+        bit_cycles * (1 + 8 + parity_bits + stop_bits)
+    }
+
+    /// Finishes and starts transfers which are due. Returns the interrupts to request.
+    pub fn update(&mut self) -> UartRequests {
+        let mut requests = UartRequests::default();
+        let now = self.current_cycle;
+
+        if let Some((byte, done)) = self.tx_shifting {
+            if now >= done {
+                if let Some(serial) = &mut self.serial {
+                    serial.send(byte);
+                }
+                self.tx_shifting = None;
+            }
+        }
+
+        if self.tx_shifting.is_none() && self.tx_enabled() {
+            if let Some(byte) = self.tx_data.take() {
+                self.tx_shifting = Some((byte, now + self.frame_cycles()));
+                requests.tx = true;
+            }
+        }
+
+        if let Some((byte, done)) = self.rx_shifting {
+            if now >= done {
+                if self.rx_ready {
+                    // The last byte was never read, so this one is lost
+                    self.errors |= USR_OVERRUN;
+                } else {
+                    self.rx_data = byte;
+                    self.rx_ready = true;
+                    requests.rx = true;
+                }
+                self.rx_shifting = None;
+            }
+        }
+
+        if self.rx_shifting.is_none() && self.rx_enabled() && now >= self.next_rx_poll {
+            let frame_cycles = self.frame_cycles();
+            match self.serial.as_mut().and_then(|serial| serial.receive()) {
+                Some(byte) => self.rx_shifting = Some((byte, now + frame_cycles)),
+                None => self.next_rx_poll = now + RX_POLL_INTERVAL.max(frame_cycles),
+            }
+        }
+
+        requests
+    }
+
+    /// The instruction cycle at which `update` next has something to do
+    pub fn next_event_cycle(&self) -> Option<u64> {
+        let tx = match (self.tx_shifting, self.tx_data) {
+            (Some((_, done)), _) => Some(done),
+            (None, Some(_)) if self.tx_enabled() => Some(self.current_cycle),
+            _ => None,
+        };
+
+        let rx = match self.rx_shifting {
+            Some((_, done)) => Some(done),
+            None if self.rx_enabled() => Some(self.next_rx_poll.max(self.current_cycle)),
+            None => None,
+        };
+
+        [tx, rx].into_iter().flatten().min()
+    }
+
+    pub fn read_uctr(&self) -> u8 {
+        self.uctr.get()
+    }
+
+    pub fn write_uctr(&mut self, value: u8) {
+        self.uctr.set(value);
+    }
+
+    pub fn read_usr(&self) -> u8 {
+        let mut usr = self.errors;
+        if self.rx_ready {
+            usr |= USR_RX_READY;
+        }
+        if self.tx_data.is_none() {
+            usr |= USR_TX_EMPTY;
+            if self.tx_shifting.is_none() {
+                usr |= USR_TX_COMPLETE;
+            }
+        }
+        usr
+    }
+
+    pub fn write_usr(&mut self, value: u8) {
+        // Writing 1 to an error bit clears it
+        self.errors &= !(value & USR_ERRORS);
+    }
+
+    pub fn read_irctr(&self) -> u8 {
+        self.irctr.get()
+    }
+
+    pub fn write_irctr(&mut self, value: u8) {
+        self.irctr.set(value);
+    }
+
+    pub fn read_bctr(&self) -> u8 {
+        self.bctr.get()
+    }
+
+    pub fn write_bctr(&mut self, value: u8) {
+        self.bctr.set(value);
+    }
+
+    pub fn read_udata(&mut self) -> u8 {
+        self.rx_ready = false;
+        self.rx_data
+    }
+
+    pub fn write_udata(&mut self, value: u8) {
+        // A byte written while the register is still full replaces the old one
+        self.tx_data = Some(value);
+    }
+
+    pub fn read_brs(&self) -> u8 {
+        self.brs.get()
+    }
+
+    pub fn write_brs(&mut self, value: u8) {
+        self.brs.set(value);
+    }
+
+    pub fn read_bdiv(&self) -> u8 {
+        self.bdiv.get()
+    }
+
+    pub fn write_bdiv(&mut self, value: u8) {
+        self.bdiv.set(value);
+    }
+}
+
+fn write_transfer(state: &mut StateWriter, transfer: Option<(u8, u64)>) {
+    state.write_bool(transfer.is_some());
+    let (byte, done) = transfer.unwrap_or_default();
+    state.write_u8(byte);
+    state.write_u64(done);
+}
+
+fn read_transfer(state: &mut StateReader) -> Result<Option<(u8, u64)>, StateError> {
+    let active = state.read_bool()?;
+    let byte = state.read_u8()?;
+    let done = state.read_u64()?;
+    Ok(active.then_some((byte, done)))
+}
+
+impl SaveState for State {
+    fn save_state(&self, state: &mut StateWriter) {
+        self.uctr.save_state(state);
+        self.irctr.save_state(state);
+        self.bctr.save_state(state);
+        self.brs.save_state(state);
+        self.bdiv.save_state(state);
+        state.write_u8(self.errors);
+        state.write_bool(self.tx_data.is_some());
+        state.write_u8(self.tx_data.unwrap_or_default());
+        write_transfer(state, self.tx_shifting);
+        state.write_u8(self.rx_data);
+        state.write_bool(self.rx_ready);
+        write_transfer(state, self.rx_shifting);
+        state.write_u64(self.next_rx_poll);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.uctr.load_state(state)?;
+        self.irctr.load_state(state)?;
+        self.bctr.load_state(state)?;
+        self.brs.load_state(state)?;
+        self.bdiv.load_state(state)?;
+        self.errors = state.read_u8()? & USR_ERRORS;
+        let tx_full = state.read_bool()?;
+        let tx_data = state.read_u8()?;
+        self.tx_data = tx_full.then_some(tx_data);
+        self.tx_shifting = read_transfer(state)?;
+        self.rx_data = state.read_u8()?;
+        self.rx_ready = state.read_bool()?;
+        self.rx_shifting = read_transfer(state)?;
+        self.next_rx_poll = state.read_u64()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+    use std::rc::Rc;
+
+    /// Host end of the line, which tests can feed and inspect
+    #[derive(Clone, Default)]
+    struct TestSerial {
+        sent: Rc<RefCell<Vec<u8>>>,
+        incoming: Rc<RefCell<VecDeque<u8>>>,
+    }
+
+    impl SerialInterface for TestSerial {
+        fn send(&mut self, byte: u8) {
+            self.sent.borrow_mut().push(byte);
+        }
+
+        fn receive(&mut self) -> Option<u8> {
+            self.incoming.borrow_mut().pop_front()
+        }
+    }
+
+    /// A UART with the baud rate generator running at `divisor` and `uctr` set
+    fn connected_uart(uctr: u8, divisor: u16) -> (State, TestSerial) {
+        let serial = TestSerial::default();
+        let mut uart = State::new();
+        uart.connect(Box::new(serial.clone()));
+        uart.write_brs((divisor >> 8) as u8);
+        uart.write_bdiv(divisor as u8);
+        uart.write_uctr(uctr);
+        uart.write_bctr(BCTR_ENABLE);
+        (uart, serial)
+    }
+
+    fn update_at(uart: &mut State, cycle: u64) -> UartRequests {
+        uart.set_current_cycle(cycle);
+        uart.update()
+    }
+
+    #[test]
+    fn sends_a_byte_one_frame_after_it_starts() {
+        // 8 MHz / (16 × 52) is about 9600 baud, and 10 bits make a frame
+        let (mut uart, serial) = connected_uart(UCTR_TX_ENABLE, 51);
+        let frame = 16 * 52 * 10;
+
+        uart.write_udata(0x41);
+        assert_eq!(uart.next_event_cycle(), Some(0));
+        update_at(&mut uart, 0);
+        assert_eq!(uart.next_event_cycle(), Some(frame));
+
+        update_at(&mut uart, frame - 1);
+        assert!(serial.sent.borrow().is_empty());
+        assert_eq!(uart.read_usr() & USR_TX_COMPLETE, 0);
+
+        update_at(&mut uart, frame);
+        assert_eq!(*serial.sent.borrow(), [0x41]);
+        assert_ne!(uart.read_usr() & USR_TX_COMPLETE, 0);
+        assert_eq!(uart.next_event_cycle(), None);
+    }
+
+    #[test]
+    fn frames_include_parity_and_stop_bits() {
+        // BRS holds the high bits of the divisor
+        let uctr = UCTR_TX_ENABLE | UCTR_PARITY_ENABLE | UCTR_TWO_STOP_BITS;
+        let (mut uart, _) = connected_uart(uctr, 0x100);
+
+        uart.write_udata(0);
+        update_at(&mut uart, 100);
+        assert_eq!(uart.next_event_cycle(), Some(100 + 16 * 0x101 * 12));
+    }
+
+    #[test]
+    fn requests_tx_each_time_the_data_register_empties() {
+        let (mut uart, serial) = connected_uart(UCTR_TX_ENABLE, 0);
+        let frame = 16 * 10;
+
+        uart.write_udata(1);
+        assert_eq!(uart.read_usr() & USR_TX_EMPTY, 0);
+        assert!(update_at(&mut uart, 0).tx);
+        assert_ne!(uart.read_usr() & USR_TX_EMPTY, 0);
+
+        // The second byte waits in the data register until the first is sent
+        uart.write_udata(2);
+        assert!(!update_at(&mut uart, frame - 1).tx);
+        assert_eq!(uart.read_usr() & USR_TX_EMPTY, 0);
+
+        assert!(update_at(&mut uart, frame).tx);
+        assert!(!update_at(&mut uart, frame * 2).tx);
+        assert_eq!(*serial.sent.borrow(), [1, 2]);
+    }
+
+    #[test]
+    fn requests_rx_once_a_byte_is_received() {
+        let (mut uart, serial) = connected_uart(UCTR_RX_ENABLE, 0);
+        let frame = 16 * 10;
+        serial.incoming.borrow_mut().push_back(0x55);
+
+        assert!(!update_at(&mut uart, 0).rx);
+        assert_eq!(uart.next_event_cycle(), Some(frame));
+        assert!(!update_at(&mut uart, frame - 1).rx);
+        assert_eq!(uart.read_usr() & USR_RX_READY, 0);
+
+        assert!(update_at(&mut uart, frame).rx);
+        assert_ne!(uart.read_usr() & USR_RX_READY, 0);
+        assert_eq!(uart.read_udata(), 0x55);
+        assert_eq!(uart.read_usr() & USR_RX_READY, 0);
+    }
+
+    #[test]
+    fn an_unread_byte_causes_an_overrun() {
+        let (mut uart, serial) = connected_uart(UCTR_RX_ENABLE, 0);
+        let frame = 16 * 10;
+        serial.incoming.borrow_mut().extend([1, 2]);
+
+        update_at(&mut uart, 0);
+        assert!(update_at(&mut uart, frame).rx);
+        assert!(!update_at(&mut uart, frame * 2).rx);
+        assert_ne!(uart.read_usr() & USR_OVERRUN, 0);
+        assert_eq!(uart.read_udata(), 1);
+
+        uart.write_usr(USR_OVERRUN);
+        assert_eq!(uart.read_usr() & USR_OVERRUN, 0);
+    }
+
+    #[test]
+    fn idle_lines_are_polled_at_least_every_frame() {
+        // Slow enough that a frame takes longer than the poll interval
+        let (mut uart, serial) = connected_uart(UCTR_RX_ENABLE, 0xFFF);
+        let frame = 16 * 0x1000 * 10;
+
+        update_at(&mut uart, 0);
+        assert_eq!(uart.next_event_cycle(), Some(frame));
+
+        serial.incoming.borrow_mut().push_back(3);
+        assert!(!update_at(&mut uart, frame).rx);
+        assert!(update_at(&mut uart, frame * 2).rx);
+    }
+
+    #[test]
+    fn does_nothing_without_the_baud_rate_generator() {
+        let (mut uart, serial) = connected_uart(UCTR_TX_ENABLE | UCTR_RX_ENABLE, 0);
+        uart.write_bctr(0);
+        serial.incoming.borrow_mut().push_back(1);
+
+        uart.write_udata(1);
+        assert_eq!(uart.next_event_cycle(), None);
+        let requests = update_at(&mut uart, 1_000_000);
+        assert!(!requests.tx && !requests.rx);
+        assert!(serial.sent.borrow().is_empty());
+    }
+}
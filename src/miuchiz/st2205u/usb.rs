@@ -0,0 +1,540 @@
+use super::reg::U8Register;
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
+use crate::usb::{UsbHost, UsbResponse, UsbTransaction};
+
+/// Bytes each endpoint's FIFO can hold, which is also its largest packet
+const FIFO_SIZE: usize = 64;
+
+/// Endpoint 0 is for control transfers, 1 is bulk IN and 2 is bulk OUT
+const ENDPOINT_COUNT: usize = 3;
+const CONTROL_ENDPOINT: u8 = 0;
+const IN_ENDPOINT: u8 = 1;
+const OUT_ENDPOINT: u8 = 2;
+
+/// USBCON bit which connects the device to the bus
+const USBCON_ENABLE: u8 = 1 << 7;
+
+// USBIREQ bits
+const IREQ_SETUP: u8 = 1 << 0;
+const IREQ_EP0_IN: u8 = 1 << 1;
+const IREQ_EP0_OUT: u8 = 1 << 2;
+const IREQ_EP1_IN: u8 = 1 << 3;
+const IREQ_EP2_OUT: u8 = 1 << 4;
+const IREQ_RESET: u8 = 1 << 5;
+
+// EPCTL bits, for the endpoint selected by EPINDEX
+/// Set by firmware once the FIFO holds a packet to send, and cleared once it is sent
+const EPCTL_IN_READY: u8 = 1 << 0;
+/// Set by firmware when the FIFO can take a packet, and cleared once one arrives
+const EPCTL_OUT_READY: u8 = 1 << 1;
+/// Answers every transaction on the endpoint with a stall
+const EPCTL_STALL: u8 = 1 << 2;
+/// The packet in endpoint 0's FIFO came from a setup transaction
+const EPCTL_SETUP: u8 = 1 << 3;
+
+/// Instruction cycles in one 1 ms USB frame, at which the host is checked for transactions
+const FRAME_CYCLES: u64 = 8000;
+
+/// Most transactions to handle in one frame
+const TRANSACTIONS_PER_FRAME: usize = 16;
+
+struct Endpoint {
+    control: u8,
+    fifo: Vec<u8>,
+    /// How far firmware has read through the FIFO
+    read_position: usize,
+}
+
+impl Endpoint {
+    fn new() -> Self {
+        Self {
+            control: 0,
+            fifo: Vec::with_capacity(FIFO_SIZE),
+            read_position: 0,
+        }
+    }
+
+    fn fill(&mut self, data: &[u8]) {
+        self.fifo.clear();
+        self.fifo
+            .extend_from_slice(&data[..data.len().min(FIFO_SIZE)]);
+        self.read_position = 0;
+    }
+}
+
+/// USB function controller, connected to a host through a `UsbHost`.
+///
+/// Transactions are handled at the start of each frame. One which an endpoint
+/// is not ready for is answered with NAK, and the rest of the frame is left
+/// for the host to try it again in the next one.
+pub struct State {
+    host: Option<Box<dyn UsbHost>>,
+
+    usbcon: U8Register,
+    usbien: U8Register,
+    usbireq: U8Register,
+    usbaddr: U8Register,
+    epindex: U8Register,
+    endpoints: [Endpoint; ENDPOINT_COUNT],
+
+    next_frame: u64,
+    current_cycle: u64,
+}
+
+impl State {
+    pub fn new() -> Self {
+        Self {
+            host: None,
+            usbcon: U8Register::new(0b0000_0000, 0b1000_0000),
+            usbien: U8Register::new(0b0000_0000, 0b0011_1111),
+            usbireq: U8Register::new(0b0000_0000, 0b0011_1111),
+            usbaddr: U8Register::new(0b0000_0000, 0b0111_1111),
+            epindex: U8Register::new(0b0000_0000, 0b0000_0011),
+            endpoints: [Endpoint::new(), Endpoint::new(), Endpoint::new()],
+            next_frame: 0,
+            current_cycle: 0,
+        }
+    }
+
+    /// Plugs the device into a host
+    pub fn connect(&mut self, host: Box<dyn UsbHost>) {
+        self.host = Some(host);
+    }
+
+    pub fn set_current_cycle(&mut self, cycle: u64) {
+        self.current_cycle = cycle;
+    }
+
+    fn enabled(&self) -> bool {
+        self.usbcon.get() & USBCON_ENABLE != 0
+    }
+
+    fn selected_endpoint(&mut self) -> Option<&mut Endpoint> {
+        self.endpoints.get_mut(self.epindex.get() as usize)
+    }
+
+    /// Handles transactions from the host if a frame has started. Returns whether
+    /// to request the USB interrupt.
+    pub fn update(&mut self) -> bool {
+        if !self.enabled() || self.host.is_none() {
+            return false;
+        }
+
+        let requested = self.usbireq.get();
+        if self.current_cycle >= self.next_frame {
+            self.next_frame = (self.current_cycle / FRAME_CYCLES + 1) * FRAME_CYCLES;
+        }
+
+        for _ in 0..TRANSACTIONS_PER_FRAME {
+            let Some(transaction) = self.host.as_mut().and_then(|host| host.next_transaction())
+            else {
+                break;
+            };
+
+            let response = self.handle(&transaction);
+            let nak = response == UsbResponse::Nak;
+            if let Some(host) = &mut self.host {
+                host.respond(response);
+            }
+            if nak {
+                break;
+            }
+        }
+
+        let newly_requested = self.usbireq.get() & !requested;
+        newly_requested & self.usbien.get() != 0
+    }
+
+    /// The instruction cycle at which `update` next has something to do
+    pub fn next_event_cycle(&self) -> Option<u64> {
+        (self.enabled() && self.host.is_some()).then_some(self.next_frame)
+    }
+
+    /// Carries out a transaction, returning the answer for the host
+    fn handle(&mut self, transaction: &UsbTransaction) -> UsbResponse {
+        match transaction {
+            UsbTransaction::Reset => {
+                self.usbaddr.set(0);
+                self.endpoints = [Endpoint::new(), Endpoint::new(), Endpoint::new()];
+                self.request(IREQ_RESET);
+                UsbResponse::Ack
+            }
+            UsbTransaction::Setup(packet) => {
+                // Setup packets cannot be refused, and end any transfer in progress
+                let endpoint = &mut self.endpoints[CONTROL_ENDPOINT as usize];
+                endpoint.fill(packet);
+                endpoint.control = EPCTL_SETUP;
+                self.request(IREQ_SETUP);
+                UsbResponse::Ack
+            }
+            UsbTransaction::Out { endpoint, data } => {
+                if !matches!(*endpoint, CONTROL_ENDPOINT | OUT_ENDPOINT) {
+                    return UsbResponse::Stall;
+                }
+                let index = *endpoint as usize;
+                let state = &mut self.endpoints[index];
+                if state.control & EPCTL_STALL != 0 {
+                    return UsbResponse::Stall;
+                }
+                if state.control & EPCTL_OUT_READY == 0 {
+                    return UsbResponse::Nak;
+                }
+
+                state.fill(data);
+                state.control &= !(EPCTL_OUT_READY | EPCTL_SETUP);
+                self.request(if *endpoint == CONTROL_ENDPOINT {
+                    IREQ_EP0_OUT
+                } else {
+                    IREQ_EP2_OUT
+                });
+                UsbResponse::Ack
+            }
+            UsbTransaction::In {
+                endpoint,
+                max_length,
+            } => {
+                if !matches!(*endpoint, CONTROL_ENDPOINT | IN_ENDPOINT) {
+                    return UsbResponse::Stall;
+                }
+                let index = *endpoint as usize;
+                let state = &mut self.endpoints[index];
+                if state.control & EPCTL_STALL != 0 {
+                    return UsbResponse::Stall;
+                }
+                if state.control & EPCTL_IN_READY == 0 {
+                    return UsbResponse::Nak;
+                }
+
+                let length = state.fifo.len().min(*max_length as usize);
+                let data = state.fifo[..length].to_vec();
+                state.fifo.clear();
+                state.read_position = 0;
+                state.control &= !EPCTL_IN_READY;
+                self.request(if *endpoint == CONTROL_ENDPOINT {
+                    IREQ_EP0_IN
+                } else {
+                    IREQ_EP1_IN
+                });
+                UsbResponse::Data(data)
+            }
+        }
+    }
+
+    fn request(&mut self, bits: u8) {
+        self.usbireq.set(self.usbireq.get() | bits);
+    }
+
+    pub fn read_usbcon(&self) -> u8 {
+        self.usbcon.get()
+    }
+
+    pub fn write_usbcon(&mut self, value: u8) {
+        let was_enabled = self.enabled();
+        self.usbcon.set(value);
+        if self.enabled() && !was_enabled {
+            self.next_frame = self.current_cycle;
+        }
+    }
+
+    pub fn read_usbien(&self) -> u8 {
+        self.usbien.get()
+    }
+
+    pub fn write_usbien(&mut self, value: u8) {
+        self.usbien.set(value);
+    }
+
+    pub fn read_usbireq(&self) -> u8 {
+        self.usbireq.get()
+    }
+
+    pub fn write_usbireq(&mut self, value: u8) {
+        // Writing 1 to a bit clears it
+        self.usbireq.set(self.usbireq.get() & !value);
+    }
+
+    pub fn read_usbaddr(&self) -> u8 {
+        self.usbaddr.get()
+    }
+
+    pub fn write_usbaddr(&mut self, value: u8) {
+        self.usbaddr.set(value);
+    }
+
+    pub fn read_epindex(&self) -> u8 {
+        self.epindex.get()
+    }
+
+    pub fn write_epindex(&mut self, value: u8) {
+        self.epindex.set(value);
+    }
+
+    pub fn read_epctl(&mut self) -> u8 {
+        self.selected_endpoint()
+            .map_or(0, |endpoint| endpoint.control)
+    }
+
+    pub fn write_epctl(&mut self, value: u8) {
+        if let Some(endpoint) = self.selected_endpoint() {
+            // The setup flag is only cleared by firmware, never set
+            let setup = endpoint.control & value & EPCTL_SETUP;
+            endpoint.control = (value & (EPCTL_IN_READY | EPCTL_OUT_READY | EPCTL_STALL)) | setup;
+        }
+    }
+
+    pub fn read_epcnt(&mut self) -> u8 {
+        self.selected_endpoint()
+            .map_or(0, |endpoint| endpoint.fifo.len() as u8)
+    }
+
+    pub fn write_epcnt(&mut self, _value: u8) {
+        // Any write empties the FIFO, ready for a new packet
+        if let Some(endpoint) = self.selected_endpoint() {
+            endpoint.fifo.clear();
+            endpoint.read_position = 0;
+        }
+    }
+
+    pub fn read_epfifo(&mut self) -> u8 {
+        let Some(endpoint) = self.selected_endpoint() else {
+            return 0;
+        };
+        let value = endpoint
+            .fifo
+            .get(endpoint.read_position)
+            .copied()
+            .unwrap_or_default();
+        endpoint.read_position = (endpoint.read_position + 1).min(endpoint.fifo.len());
+        value
+    }
+
+    pub fn write_epfifo(&mut self, value: u8) {
+        if let Some(endpoint) = self.selected_endpoint() {
+            if endpoint.fifo.len() < FIFO_SIZE {
+                endpoint.fifo.push(value);
+            }
+        }
+    }
+}
+
+impl SaveState for State {
+    fn save_state(&self, state: &mut StateWriter) {
+        self.usbcon.save_state(state);
+        self.usbien.save_state(state);
+        self.usbireq.save_state(state);
+        self.usbaddr.save_state(state);
+        self.epindex.save_state(state);
+        for endpoint in &self.endpoints {
+            state.write_u8(endpoint.control);
+            state.write_u8(endpoint.fifo.len() as u8);
+            state.write_bytes(&endpoint.fifo);
+            state.write_u8(endpoint.read_position as u8);
+        }
+        state.write_u64(self.next_frame);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.usbcon.load_state(state)?;
+        self.usbien.load_state(state)?;
+        self.usbireq.load_state(state)?;
+        self.usbaddr.load_state(state)?;
+        self.epindex.load_state(state)?;
+        for endpoint in &mut self.endpoints {
+            endpoint.control = state.read_u8()?;
+            let length = state.read_u8()? as usize;
+            if length > FIFO_SIZE {
+                return Err(StateError::InvalidValue("USB FIFO length"));
+            }
+            endpoint.fifo.resize(length, 0);
+            state.read_bytes(&mut endpoint.fifo)?;
+            endpoint.read_position = (state.read_u8()? as usize).min(length);
+        }
+        self.next_frame = state.read_u64()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+    use std::rc::Rc;
+
+    /// Host which sends queued transactions and keeps the answers
+    #[derive(Clone, Default)]
+    struct TestHost {
+        transactions: Rc<RefCell<VecDeque<UsbTransaction>>>,
+        responses: Rc<RefCell<Vec<UsbResponse>>>,
+    }
+
+    impl TestHost {
+        fn send(&self, transaction: UsbTransaction) {
+            self.transactions.borrow_mut().push_back(transaction);
+        }
+
+        fn take_responses(&self) -> Vec<UsbResponse> {
+            std::mem::take(&mut self.responses.borrow_mut())
+        }
+    }
+
+    impl UsbHost for TestHost {
+        fn next_transaction(&mut self) -> Option<UsbTransaction> {
+            self.transactions.borrow_mut().pop_front()
+        }
+
+        fn respond(&mut self, response: UsbResponse) {
+            self.responses.borrow_mut().push(response);
+        }
+    }
+
+    fn connected_device() -> (State, TestHost) {
+        let host = TestHost::default();
+        let mut usb = State::new();
+        usb.connect(Box::new(host.clone()));
+        usb.write_usbien(0b0011_1111);
+        usb.write_usbcon(USBCON_ENABLE);
+        (usb, host)
+    }
+
+    /// Does what firmware would to send `data` from `endpoint`
+    fn ready_in(usb: &mut State, endpoint: u8, data: &[u8]) {
+        usb.write_epindex(endpoint);
+        usb.write_epcnt(0);
+        for &byte in data {
+            usb.write_epfifo(byte);
+        }
+        usb.write_epctl(EPCTL_IN_READY);
+    }
+
+    #[test]
+    fn setup_then_in_data() {
+        let (mut usb, host) = connected_device();
+        let setup = [0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 0x12, 0x00];
+        host.send(UsbTransaction::Setup(setup));
+
+        assert!(usb.update());
+        assert_eq!(host.take_responses(), [UsbResponse::Ack]);
+        assert_eq!(usb.read_usbireq(), IREQ_SETUP);
+
+        usb.write_epindex(CONTROL_ENDPOINT);
+        assert_eq!(usb.read_epctl(), EPCTL_SETUP);
+        assert_eq!(usb.read_epcnt(), 8);
+        let received: Vec<u8> = (0..8).map(|_| usb.read_epfifo()).collect();
+        assert_eq!(received, setup);
+        usb.write_usbireq(IREQ_SETUP);
+
+        ready_in(&mut usb, CONTROL_ENDPOINT, &[1, 2, 3, 4]);
+        host.send(UsbTransaction::In {
+            endpoint: CONTROL_ENDPOINT,
+            max_length: 3,
+        });
+        assert!(usb.update());
+        assert_eq!(host.take_responses(), [UsbResponse::Data(vec![1, 2, 3])]);
+        assert_eq!(usb.read_usbireq(), IREQ_EP0_IN);
+        assert_eq!(usb.read_epctl() & EPCTL_IN_READY, 0);
+    }
+
+    #[test]
+    fn in_to_a_not_ready_endpoint_is_naked() {
+        let (mut usb, host) = connected_device();
+        let read = UsbTransaction::In {
+            endpoint: IN_ENDPOINT,
+            max_length: 64,
+        };
+
+        // The rest of the frame waits for the host to try again
+        host.send(read.clone());
+        host.send(UsbTransaction::Reset);
+        assert!(!usb.update());
+        assert_eq!(host.take_responses(), [UsbResponse::Nak]);
+
+        host.transactions.borrow_mut().push_front(read);
+        ready_in(&mut usb, IN_ENDPOINT, &[5, 6]);
+        assert!(usb.update());
+        assert_eq!(
+            host.take_responses(),
+            [UsbResponse::Data(vec![5, 6]), UsbResponse::Ack]
+        );
+    }
+
+    #[test]
+    fn out_to_a_not_ready_endpoint_is_naked() {
+        let (mut usb, host) = connected_device();
+        let write = UsbTransaction::Out {
+            endpoint: OUT_ENDPOINT,
+            data: vec![7, 8, 9],
+        };
+
+        host.send(write.clone());
+        assert!(!usb.update());
+        assert_eq!(host.take_responses(), [UsbResponse::Nak]);
+        usb.write_epindex(OUT_ENDPOINT);
+        assert_eq!(usb.read_epcnt(), 0);
+
+        usb.write_epctl(EPCTL_OUT_READY);
+        host.send(write);
+        assert!(usb.update());
+        assert_eq!(host.take_responses(), [UsbResponse::Ack]);
+        assert_eq!(usb.read_usbireq(), IREQ_EP2_OUT);
+        assert_eq!(usb.read_epctl() & EPCTL_OUT_READY, 0);
+        let received: Vec<u8> = (0..usb.read_epcnt()).map(|_| usb.read_epfifo()).collect();
+        assert_eq!(received, [7, 8, 9]);
+    }
+
+    #[test]
+    fn stalls_halted_and_unsupported_endpoints() {
+        let (mut usb, host) = connected_device();
+        usb.write_epindex(IN_ENDPOINT);
+        usb.write_epctl(EPCTL_IN_READY | EPCTL_STALL);
+
+        host.send(UsbTransaction::In {
+            endpoint: IN_ENDPOINT,
+            max_length: 64,
+        });
+        // Endpoint 2 only takes OUT data
+        host.send(UsbTransaction::In {
+            endpoint: OUT_ENDPOINT,
+            max_length: 64,
+        });
+        host.send(UsbTransaction::Out {
+            endpoint: 3,
+            data: vec![],
+        });
+        assert!(!usb.update());
+        assert_eq!(host.take_responses(), vec![UsbResponse::Stall; 3]);
+        assert_eq!(usb.read_usbireq(), 0);
+    }
+
+    #[test]
+    fn reset_returns_to_the_default_state() {
+        let (mut usb, host) = connected_device();
+        usb.write_usbaddr(5);
+        ready_in(&mut usb, IN_ENDPOINT, &[1]);
+
+        host.send(UsbTransaction::Reset);
+        assert!(usb.update());
+        assert_eq!(host.take_responses(), [UsbResponse::Ack]);
+        assert_eq!(usb.read_usbireq(), IREQ_RESET);
+        assert_eq!(usb.read_usbaddr(), 0);
+        assert_eq!((usb.read_epctl(), usb.read_epcnt()), (0, 0));
+
+        host.send(UsbTransaction::In {
+            endpoint: IN_ENDPOINT,
+            max_length: 64,
+        });
+        usb.update();
+        assert_eq!(host.take_responses(), [UsbResponse::Nak]);
+    }
+
+    #[test]
+    fn does_nothing_until_enabled() {
+        let (mut usb, host) = connected_device();
+        usb.write_usbcon(0);
+        host.send(UsbTransaction::Reset);
+
+        assert!(!usb.update());
+        assert_eq!(usb.next_event_cycle(), None);
+        assert!(host.take_responses().is_empty());
+    }
+}
@@ -66,28 +66,36 @@ impl AddressingMode {
                 let value = core.address_space.read_u8(read_address as usize);
                 (value, crosses_page(*addr, read_address))
             }
-            AddressingMode::AbsoluteXIndexedIndirect(_) => todo!(),
+            AddressingMode::AbsoluteXIndexedIndirect(addr) => {
+                let ptr_address = addr.wrapping_add(core.registers.x.into());
+                let read_address = core.address_space.read_u16_le(ptr_address as usize);
+                let value = core.address_space.read_u8(read_address as usize);
+                (value, crosses_page(*addr, ptr_address))
+            }
             AddressingMode::Immediate(imm) => (*imm, false),
-            AddressingMode::Indirect(_) => todo!(),
+            AddressingMode::Indirect(addr) => {
+                let read_address = core.address_space.read_u16_le(*addr as usize);
+                (core.address_space.read_u8(read_address as usize), false)
+            }
             AddressingMode::XIndexedIndirect(addr) => {
                 // (0,X) should only access ZP, meaning page boundaries can never be crossed
                 let offset_addr = addr.wrapping_add(core.registers.x);
-                let read_addr = core.address_space.read_u16_le(offset_addr as usize);
+                let read_addr = read_zp_u16(&mut core.address_space, offset_addr);
                 let value = core.address_space.read_u8(read_addr as usize);
                 (value, false)
             }
             AddressingMode::IndirectYIndexed(addr) => {
-                let ptr = core.address_space.read_u16_le(*addr as usize);
+                let ptr = read_zp_u16(&mut core.address_space, *addr);
                 let ptr_offset = ptr.wrapping_add(core.registers.y.into());
                 let value = core.address_space.read_u8(ptr_offset as usize);
                 (value, crosses_page(ptr, ptr_offset))
             }
-            AddressingMode::Relative(_) => todo!(),
+            AddressingMode::Relative(offset) => (*offset as u8, false),
             AddressingMode::ZeroPage(zp_addr) => {
                 (core.address_space.read_u8(*zp_addr as usize), false)
             }
             AddressingMode::IndirectZeroPage(zp_addr) => {
-                let read_address = core.address_space.read_u16_le(*zp_addr as usize);
+                let read_address = read_zp_u16(&mut core.address_space, *zp_addr);
                 (core.address_space.read_u8(read_address as usize), false)
             }
             AddressingMode::ZeroPageXIndexed(zp_addr) => {
@@ -96,8 +104,15 @@ impl AddressingMode {
                     .read_u8(zp_addr.wrapping_add(core.registers.x) as usize);
                 (value, false)
             }
-            AddressingMode::ZeroPageYIndexed(_) => todo!(),
-            AddressingMode::ZeroPageRelative(_, _) => todo!(),
+            AddressingMode::ZeroPageYIndexed(zp_addr) => {
+                let value = core
+                    .address_space
+                    .read_u8(zp_addr.wrapping_add(core.registers.y) as usize);
+                (value, false)
+            }
+            AddressingMode::ZeroPageRelative(zp_addr, _) => {
+                (core.address_space.read_u8(*zp_addr as usize), false)
+            }
             AddressingMode::Implied => (core.registers.a, false),
             AddressingMode::AbsoluteAddress(_)
             | AddressingMode::IndirectAddress(_)
@@ -133,11 +148,12 @@ impl AddressingMode {
             }
             AddressingMode::AbsoluteXIndexedIndirectAddress(addr) => {
                 let address_address = addr.wrapping_add(core.registers.x.into());
-                println!("{address_address:X}");
                 let jmp_addr = core.address_space.read_u16_le(address_address as usize);
                 (jmp_addr, crosses_page(*addr, address_address))
             }
-            _ => todo!(),
+            _ => {
+                panic!("It doesn't make sense to read a u16 with addressing mode {self:?}");
+            }
         }
     }
 
@@ -151,7 +167,11 @@ impl AddressingMode {
                 let value = core.address_space.read_u8(*zp_addr as usize);
                 ((value, *offset), false)
             }
-            _ => todo!(),
+            _ => {
+                panic!(
+                    "It doesn't make sense to read a u8 and an i8 with addressing mode {self:?}"
+                );
+            }
         }
     }
 
@@ -176,23 +196,35 @@ impl AddressingMode {
                 core.address_space.write_u8(write_address as usize, value);
                 crosses_page(*addr, write_address)
             }
-            AddressingMode::AbsoluteXIndexedIndirect(_) => todo!(),
-            AddressingMode::Immediate(_) => todo!(),
-            AddressingMode::Indirect(_) => todo!(),
-            AddressingMode::XIndexedIndirect(_) => todo!(),
+            AddressingMode::AbsoluteXIndexedIndirect(addr) => {
+                let ptr_address = addr.wrapping_add(core.registers.x.into());
+                let write_address = core.address_space.read_u16_le(ptr_address as usize);
+                core.address_space.write_u8(write_address as usize, value);
+                crosses_page(*addr, ptr_address)
+            }
+            AddressingMode::Indirect(addr) => {
+                let write_address = core.address_space.read_u16_le(*addr as usize);
+                core.address_space.write_u8(write_address as usize, value);
+                false
+            }
+            AddressingMode::XIndexedIndirect(addr) => {
+                let offset_addr = addr.wrapping_add(core.registers.x);
+                let write_address = read_zp_u16(&mut core.address_space, offset_addr);
+                core.address_space.write_u8(write_address as usize, value);
+                false
+            }
             AddressingMode::IndirectYIndexed(addr) => {
-                let address1 = core.address_space.read_u16_le(*addr as usize);
+                let address1 = read_zp_u16(&mut core.address_space, *addr);
                 let address2 = address1.wrapping_add(core.registers.y.into());
                 core.address_space.write_u8(address2 as usize, value);
                 crosses_page(address1, address2)
             }
-            AddressingMode::Relative(_) => todo!(),
             AddressingMode::ZeroPage(zp_addr) => {
                 core.address_space.write_u8(*zp_addr as usize, value);
                 false
             }
             AddressingMode::IndirectZeroPage(zp_addr) => {
-                let write_address = core.address_space.read_u16_le(*zp_addr as usize);
+                let write_address = read_zp_u16(&mut core.address_space, *zp_addr);
                 core.address_space.write_u8(write_address as usize, value);
                 false
             }
@@ -201,16 +233,25 @@ impl AddressingMode {
                     .write_u8(zp_addr.wrapping_add(core.registers.x) as usize, value);
                 false
             }
-            AddressingMode::ZeroPageYIndexed(_) => todo!(),
-            AddressingMode::ZeroPageRelative(_, _) => todo!(),
+            AddressingMode::ZeroPageYIndexed(zp_addr) => {
+                core.address_space
+                    .write_u8(zp_addr.wrapping_add(core.registers.y) as usize, value);
+                false
+            }
+            AddressingMode::ZeroPageRelative(zp_addr, _) => {
+                core.address_space.write_u8(*zp_addr as usize, value);
+                false
+            }
             AddressingMode::Implied => {
                 core.registers.a = value;
                 false
             }
-            AddressingMode::AbsoluteAddress(_)
+            AddressingMode::Immediate(_)
+            | AddressingMode::Relative(_)
+            | AddressingMode::AbsoluteAddress(_)
             | AddressingMode::IndirectAddress(_)
             | AddressingMode::AbsoluteXIndexedIndirectAddress(_) => {
-                panic!("Addressing mode doesn't return u8")
+                panic!("It doesn't make sense to write a u8 with addressing mode {self:?}");
             }
         }
     }
@@ -256,3 +297,10 @@ impl ToString for AddressingMode {
 fn crosses_page(addr1: u16, addr2: u16) -> bool {
     addr1 & 0xFF00 != addr2 & 0xFF00
 }
+
+// Pointers in the zero page wrap around within it
+fn read_zp_u16(address_space: &mut impl AddressSpace, zp_addr: u8) -> u16 {
+    let low = address_space.read_u8(zp_addr as usize);
+    let high = address_space.read_u8(zp_addr.wrapping_add(1) as usize);
+    u16::from_le_bytes([low, high])
+}
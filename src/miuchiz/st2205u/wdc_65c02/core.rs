@@ -1,8 +1,9 @@
 use super::{instr, opcode::Opcode, DecodedInstruction, HandlesInterrupt};
 use crate::memory::AddressSpace;
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
 
 // This core should tick every 2 oscillations
-const CYCLE_FREQUENCY_DIVISOR: u64 = 2;
+pub const CYCLE_FREQUENCY_DIVISOR: u64 = 2;
 
 /// A WDC 65C02 CPU core
 pub struct Core<A>
@@ -18,6 +19,18 @@ where
     pub registers: Registers,
 
     pub flags: Flags,
+
+    pub run_state: RunState,
+}
+
+/// Whether the core is executing instructions
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RunState {
+    Running,
+    /// Halted by WAI until an interrupt is requested
+    Waiting,
+    /// Halted by STP; only a reset resumes execution
+    Stopped,
 }
 
 #[derive(Default)]
@@ -108,6 +121,10 @@ impl<A: AddressSpace + HandlesInterrupt> HandlesInterrupt for Core<A> {
     fn interrupted(&self) -> bool {
         self.address_space.interrupted()
     }
+
+    fn brk_vector(&self) -> u16 {
+        self.address_space.brk_vector()
+    }
 }
 
 impl<A: AddressSpace + HandlesInterrupt> Core<A> {
@@ -116,6 +133,7 @@ impl<A: AddressSpace + HandlesInterrupt> Core<A> {
             frequency,
             cycles: 0,
             flags: Flags::default(),
+            run_state: RunState::Running,
             address_space,
             registers: Registers {
                 sp: 0,
@@ -127,6 +145,11 @@ impl<A: AddressSpace + HandlesInterrupt> Core<A> {
         }
     }
 
+    /// Oscillator cycles per second
+    pub fn frequency(&self) -> u64 {
+        self.frequency
+    }
+
     pub fn cycles_per_second(&self) -> u64 {
         self.frequency / CYCLE_FREQUENCY_DIVISOR
     }
@@ -139,11 +162,22 @@ impl<A: AddressSpace + HandlesInterrupt> Core<A> {
         self.cycles * CYCLE_FREQUENCY_DIVISOR
     }
 
+    /// The first instruction cycle at or after the given oscillator cycle
+    pub fn instruction_cycle_from_oscillator(&self, oscillator_cycles: u64) -> u64 {
+        oscillator_cycles.div_ceil(CYCLE_FREQUENCY_DIVISOR)
+    }
+
     pub fn decode_next_instruction(&mut self) -> DecodedInstruction {
         DecodedInstruction::decode(&mut self.address_space, self.registers.pc.into())
     }
 
     pub fn step(&mut self) {
+        if self.run_state != RunState::Running {
+            // No instruction is executed, but time still passes for everything else
+            self.cycles += 1;
+            return;
+        }
+
         let dins = self.decode_next_instruction();
         let ins = &dins.instruction;
 
@@ -210,14 +244,14 @@ impl<A: AddressSpace + HandlesInterrupt> Core<A> {
             Opcode::Bcc => instr::bcc,
             Opcode::Bcs => instr::bcs,
             Opcode::Beq => instr::beq,
-            Opcode::Bit => todo!(),
+            Opcode::Bit => instr::bit,
             Opcode::Bmi => instr::bmi,
             Opcode::Bne => instr::bne,
             Opcode::Bpl => instr::bpl,
             Opcode::Bra => instr::bra,
-            Opcode::Brk => todo!(),
-            Opcode::Bvc => todo!(),
-            Opcode::Bvs => todo!(),
+            Opcode::Brk => instr::brk,
+            Opcode::Bvc => instr::bvc,
+            Opcode::Bvs => instr::bvs,
             Opcode::Clc => instr::clc,
             Opcode::Cld => instr::cld,
             Opcode::Cli => instr::cli,
@@ -273,14 +307,14 @@ impl<A: AddressSpace + HandlesInterrupt> Core<A> {
             Opcode::Smb6 => instr::smb6,
             Opcode::Smb7 => instr::smb7,
             Opcode::Sta => instr::sta,
-            Opcode::Stp => todo!(),
+            Opcode::Stp => instr::stp,
             Opcode::Stx => instr::stx,
             Opcode::Sty => instr::sty,
             Opcode::Stz => instr::stz,
             Opcode::Tax => instr::tax,
             Opcode::Tay => instr::tay,
-            Opcode::Trb => todo!(),
-            Opcode::Tsb => todo!(),
+            Opcode::Trb => instr::trb,
+            Opcode::Tsb => instr::tsb,
             Opcode::Tsx => instr::tsx,
             Opcode::Txa => instr::txa,
             Opcode::Txs => instr::txs,
@@ -295,3 +329,46 @@ impl<A: AddressSpace + HandlesInterrupt> Core<A> {
         }
     }
 }
+
+impl<A: AddressSpace + HandlesInterrupt + SaveState> SaveState for Core<A> {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u64(self.cycles);
+
+        state.write_u8(self.registers.sp);
+        state.write_u16(self.registers.pc);
+        state.write_u8(self.registers.a);
+        state.write_u8(self.registers.x);
+        state.write_u8(self.registers.y);
+
+        state.write_u8(self.flags.to_u8());
+
+        state.write_u8(match self.run_state {
+            RunState::Running => 0,
+            RunState::Waiting => 1,
+            RunState::Stopped => 2,
+        });
+
+        self.address_space.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.cycles = state.read_u64()?;
+
+        self.registers.sp = state.read_u8()?;
+        self.registers.pc = state.read_u16()?;
+        self.registers.a = state.read_u8()?;
+        self.registers.x = state.read_u8()?;
+        self.registers.y = state.read_u8()?;
+
+        self.flags = Flags::from_u8(state.read_u8()?);
+
+        self.run_state = match state.read_u8()? {
+            0 => RunState::Running,
+            1 => RunState::Waiting,
+            2 => RunState::Stopped,
+            _ => return Err(StateError::InvalidValue("CPU run state")),
+        };
+
+        self.address_space.load_state(state)
+    }
+}
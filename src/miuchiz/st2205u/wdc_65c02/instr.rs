@@ -1,6 +1,6 @@
 use crate::memory::AddressSpace;
 
-use super::{AddressingMode, Core, Flags, HandlesInterrupt, Opcode};
+use super::{AddressingMode, Core, Flags, HandlesInterrupt, Opcode, RunState};
 
 #[derive(Debug)]
 pub struct Instruction {
@@ -112,7 +112,6 @@ pub fn smbx<A: AddressSpace + HandlesInterrupt>(
     let (mut operand, _) = inst.addressing_mode.read_operand_u8(core);
 
     operand |= 1 << n;
-    core.flags.zero = operand == 0;
 
     let _ = inst.addressing_mode.write_operand_u8(core, operand);
 
@@ -159,7 +158,6 @@ pub fn rmbx<A: AddressSpace + HandlesInterrupt>(
     let (mut operand, _) = inst.addressing_mode.read_operand_u8(core);
 
     operand &= !(1 << n);
-    core.flags.zero = operand == 0;
 
     let _ = inst.addressing_mode.write_operand_u8(core, operand);
 
@@ -198,10 +196,14 @@ pub fn rmb7<A: AddressSpace + HandlesInterrupt>(core: &mut Core<A>, inst: &Instr
     rmbx(core, inst, 7)
 }
 
-pub fn wai<A: AddressSpace + HandlesInterrupt>(_core: &mut Core<A>, _inst: &Instruction) -> bool {
-    // TODO: IMPLEMENT WHEN THERE ARE INTERRUPTS
-    // core.registers.pc = core.registers.pc.wrapping_sub(inst.encoded_length() as u16);
+pub fn stp<A: AddressSpace + HandlesInterrupt>(core: &mut Core<A>, _inst: &Instruction) -> bool {
+    core.run_state = RunState::Stopped;
+    false
+}
 
+pub fn wai<A: AddressSpace + HandlesInterrupt>(core: &mut Core<A>, _inst: &Instruction) -> bool {
+    // Execution resumes after the WAI once the interrupt controller requests an interrupt
+    core.run_state = RunState::Waiting;
     false
 }
 
@@ -351,6 +353,44 @@ pub fn and<A: AddressSpace + HandlesInterrupt>(core: &mut Core<A>, inst: &Instru
     bound_crossed
 }
 
+pub fn bit<A: AddressSpace + HandlesInterrupt>(core: &mut Core<A>, inst: &Instruction) -> bool {
+    let (operand, bound_crossed) = inst.addressing_mode.read_operand_u8(core);
+
+    core.flags.zero = core.registers.a & operand == 0;
+
+    // Immediate mode only affects the zero flag
+    if !matches!(inst.addressing_mode, AddressingMode::Immediate(_)) {
+        core.flags.negative = is_negative(operand);
+        core.flags.overflow = operand & (1 << 6) != 0;
+    }
+
+    bound_crossed
+}
+
+pub fn trb<A: AddressSpace + HandlesInterrupt>(core: &mut Core<A>, inst: &Instruction) -> bool {
+    let (operand, _) = inst.addressing_mode.read_operand_u8(core);
+
+    core.flags.zero = core.registers.a & operand == 0;
+
+    let _ = inst
+        .addressing_mode
+        .write_operand_u8(core, operand & !core.registers.a);
+
+    false
+}
+
+pub fn tsb<A: AddressSpace + HandlesInterrupt>(core: &mut Core<A>, inst: &Instruction) -> bool {
+    let (operand, _) = inst.addressing_mode.read_operand_u8(core);
+
+    core.flags.zero = core.registers.a & operand == 0;
+
+    let _ = inst
+        .addressing_mode
+        .write_operand_u8(core, operand | core.registers.a);
+
+    false
+}
+
 pub fn asl<A: AddressSpace + HandlesInterrupt>(core: &mut Core<A>, inst: &Instruction) -> bool {
     let (mut operand, bound_crossed) = inst.addressing_mode.read_operand_u8(core);
 
@@ -413,6 +453,23 @@ pub fn rti<A: AddressSpace + HandlesInterrupt>(core: &mut Core<A>, _inst: &Instr
     false
 }
 
+pub fn brk<A: AddressSpace + HandlesInterrupt>(core: &mut Core<A>, _inst: &Instruction) -> bool {
+    // BRK is encoded as a single byte, but the return address skips a signature byte
+    core.push_u16(core.registers.pc.wrapping_add(1));
+    // Bit 4 (B) distinguishes BRK from a hardware interrupt, bit 5 always reads as 1
+    core.push_u8(core.flags.to_u8() | 0b0011_0000);
+
+    core.flags.interrupt_disable = true;
+    core.flags.decimal = false;
+
+    core.address_space.set_interrupted(true);
+    core.registers.pc = core
+        .address_space
+        .read_u16_le(core.address_space.brk_vector().into());
+
+    false
+}
+
 pub fn rts<A: AddressSpace + HandlesInterrupt>(core: &mut Core<A>, _inst: &Instruction) -> bool {
     core.registers.pc = core.pop_u16();
 
@@ -469,6 +526,30 @@ pub fn bcs<A: AddressSpace + HandlesInterrupt>(core: &mut Core<A>, inst: &Instru
     bound_crossed
 }
 
+pub fn bvc<A: AddressSpace + HandlesInterrupt>(core: &mut Core<A>, inst: &Instruction) -> bool {
+    let (operand, bound_crossed) = inst.addressing_mode.read_operand_i8(core);
+
+    if !core.flags.overflow {
+        branch(core, operand);
+        // Extra cycle taken if branch succeeds
+        core.cycles += 1;
+    }
+
+    bound_crossed
+}
+
+pub fn bvs<A: AddressSpace + HandlesInterrupt>(core: &mut Core<A>, inst: &Instruction) -> bool {
+    let (operand, bound_crossed) = inst.addressing_mode.read_operand_i8(core);
+
+    if core.flags.overflow {
+        branch(core, operand);
+        // Extra cycle taken if branch succeeds
+        core.cycles += 1;
+    }
+
+    bound_crossed
+}
+
 pub fn bbr<A: AddressSpace + HandlesInterrupt>(
     core: &mut Core<A>,
     inst: &Instruction,
@@ -653,7 +734,8 @@ pub fn beq<A: AddressSpace + HandlesInterrupt>(core: &mut Core<A>, inst: &Instru
 }
 
 pub fn php<A: AddressSpace + HandlesInterrupt>(core: &mut Core<A>, _inst: &Instruction) -> bool {
-    core.push_u8(core.flags.to_u8());
+    // B and bit 5 always read as 1 in the pushed status
+    core.push_u8(core.flags.to_u8() | 0b0011_0000);
     false
 }
 
@@ -665,17 +747,28 @@ pub fn plp<A: AddressSpace + HandlesInterrupt>(core: &mut Core<A>, _inst: &Instr
 pub fn adc<A: AddressSpace + HandlesInterrupt>(core: &mut Core<A>, inst: &Instruction) -> bool {
     let (operand, bound_crossed) = inst.addressing_mode.read_operand_u8(core);
 
-    let mut sum = operand as u16 + core.registers.a as u16 + core.flags.carry as u16;
+    let a = core.registers.a;
+    let carry = core.flags.carry as u16;
+
+    let mut sum = a as u16 + operand as u16 + carry;
+    // Overflow when both operands have the same sign and the result does not
+    core.flags.overflow = !(a ^ operand) & (a ^ sum as u8) & 0x80 != 0;
 
     if core.flags.decimal {
-        let mut low_result =
-            (core.registers.a as u16 & 0x0F) + (operand as u16 & 0x0F) + (core.flags.carry as u16);
-        if low_result > 9 {
-            low_result = ((low_result + 6) & 0x0F) + 16;
+        // http://www.6502.org/tutorials/decimal_mode.html#A
+        let mut low_result = (a as u16 & 0x0F) + (operand as u16 & 0x0F) + carry;
+        if low_result >= 0x0A {
+            low_result = ((low_result + 0x06) & 0x0F) + 0x10;
         }
-        sum = (core.registers.a as u16 & 0xF0) + (operand as u16 & 0xF0) + low_result;
-        if sum > 0x90 {
-            sum = sum + 0x60;
+
+        // Overflow comes from the signed sum before the high digit is adjusted
+        let signed_sum =
+            (a & 0xF0) as i8 as i16 + (operand & 0xF0) as i8 as i16 + low_result as i16;
+        core.flags.overflow = !(-128..=127).contains(&signed_sum);
+
+        sum = (a as u16 & 0xF0) + (operand as u16 & 0xF0) + low_result;
+        if sum >= 0xA0 {
+            sum += 0x60;
         }
         core.cycles += 1;
     }
@@ -685,42 +778,39 @@ pub fn adc<A: AddressSpace + HandlesInterrupt>(core: &mut Core<A>, inst: &Instru
     core.flags.zero = core.registers.a == 0;
     core.flags.negative = is_negative(core.registers.a);
 
-    let c_6 = (((core.registers.a & 0x7F) + (operand & 0x7F) + (core.flags.carry as u8))
-        & 0b10000000)
-        != 0;
-    core.flags.overflow = c_6 ^ core.flags.carry;
-
     bound_crossed
 }
 
 pub fn sbc<A: AddressSpace + HandlesInterrupt>(core: &mut Core<A>, inst: &Instruction) -> bool {
     let (operand, bound_crossed) = inst.addressing_mode.read_operand_u8(core);
 
+    let a = core.registers.a;
     let old_carry = core.flags.carry;
 
-    let mut result = u16::from(core.registers.a) + u16::from(!operand) + old_carry as u16;
-
-    core.flags.carry = result > u8::MAX as u16;
-    core.flags.zero = result & 0xFF == 0;
-    core.flags.overflow =
-        ((core.registers.a ^ operand) & (core.registers.a ^ result as u8) & 0b10000000 as u8) > 0;
+    // Carry and overflow come from the binary subtraction in both modes
+    let difference = a as u16 + !operand as u16 + old_carry as u16;
+    core.flags.carry = difference > u8::MAX as u16;
+    core.flags.overflow = (a ^ operand) & (a ^ difference as u8) & 0x80 != 0;
 
-    core.flags.negative = is_negative((result & 0xFF) as u8);
+    let mut result = (difference & 0xFF) as u8;
 
     if core.flags.decimal {
-        let value = operand as i16;
-
-        let mut sum = (core.registers.a & 0xf) as i16 - (value & 0xf) + old_carry as i16 - 1;
-        if sum < 0 {
-            sum = ((sum - 0x6) & 0xf) - 0x10;
+        // http://www.6502.org/tutorials/decimal_mode.html#A, sequence for the 65C02
+        let low_result = (a & 0x0F) as i16 - (operand & 0x0F) as i16 + old_carry as i16 - 1;
+        let mut decimal_result = a as i16 - operand as i16 + old_carry as i16 - 1;
+        if decimal_result < 0 {
+            decimal_result -= 0x60;
         }
-        let mut sum = (core.registers.a & 0xf0) as i16 - (value & 0xf0) + sum;
-        if sum < 0 {
-            sum -= 0x60;
+        if low_result < 0 {
+            decimal_result -= 0x06;
         }
-        result = (sum & 0xff) as u16;
+        result = (decimal_result & 0xFF) as u8;
+        core.cycles += 1;
     }
-    core.registers.a = (result & 0xFF) as u8;
+
+    core.registers.a = result;
+    core.flags.zero = result == 0;
+    core.flags.negative = is_negative(result);
 
     bound_crossed
 }
@@ -1,4 +1,9 @@
 pub trait HandlesInterrupt {
     fn set_interrupted(&mut self, interrupted: bool);
     fn interrupted(&self) -> bool;
+
+    /// Address of the vector BRK jumps through
+    fn brk_vector(&self) -> u16 {
+        0xFFFE
+    }
 }
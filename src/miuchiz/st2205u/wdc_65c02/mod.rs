@@ -4,8 +4,10 @@ mod decoder;
 pub(self) mod instr;
 mod interrupt;
 mod opcode;
+#[cfg(test)]
+mod tests;
 
-pub use self::core::{Core, Flags, Registers};
+pub use self::core::{Core, Flags, Registers, RunState, CYCLE_FREQUENCY_DIVISOR};
 pub use addr_mode::AddressingMode;
 pub use decoder::DecodedInstruction;
 pub use instr::Instruction;
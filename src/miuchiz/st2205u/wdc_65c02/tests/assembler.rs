@@ -0,0 +1,662 @@
+//! A small two-pass 65C02 assembler for the functional test programs.
+//!
+//! It understands the usual operand syntax for every addressing mode, labels
+//! (`name:`), constants (`name = expression`) and the `.org`, `.byte` and
+//! `.word` directives. Expressions are sums and differences of numbers (`$FF`,
+//! `%1010`, `255`, `'a'`), symbols and `*` (the address of the current line),
+//! optionally prefixed with `<` or `>` for the low or high byte.
+//!
+//! The opcode table is written out independently of the core's decoder so that
+//! a mistake in the decoder can't hide itself.
+
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    Implied,
+    Immediate,
+    ZeroPage,
+    ZeroPageX,
+    ZeroPageY,
+    Absolute,
+    AbsoluteX,
+    AbsoluteY,
+    Indirect,
+    IndirectX,
+    IndirectY,
+    IndirectZeroPage,
+    AbsoluteIndirectX,
+    Relative,
+    ZeroPageRelative,
+}
+
+impl Mode {
+    fn operand_length(self) -> u16 {
+        match self {
+            Mode::Implied => 0,
+            Mode::Immediate
+            | Mode::ZeroPage
+            | Mode::ZeroPageX
+            | Mode::ZeroPageY
+            | Mode::IndirectX
+            | Mode::IndirectY
+            | Mode::IndirectZeroPage
+            | Mode::Relative => 1,
+            Mode::Absolute
+            | Mode::AbsoluteX
+            | Mode::AbsoluteY
+            | Mode::Indirect
+            | Mode::AbsoluteIndirectX
+            | Mode::ZeroPageRelative => 2,
+        }
+    }
+}
+
+// Operands shared by the eight main ALU instructions, in the order
+// #imm, zp, zp,X, abs, abs,X, abs,Y, (zp,X), (zp),Y, (zp)
+const ALU_MODES: [Mode; 9] = [
+    Mode::Immediate,
+    Mode::ZeroPage,
+    Mode::ZeroPageX,
+    Mode::Absolute,
+    Mode::AbsoluteX,
+    Mode::AbsoluteY,
+    Mode::IndirectX,
+    Mode::IndirectY,
+    Mode::IndirectZeroPage,
+];
+
+// The (zp,X) opcode of each ALU instruction, which the others are offsets from
+const ALU_OPCODES: [(&str, u8); 8] = [
+    ("ORA", 0x01),
+    ("AND", 0x21),
+    ("EOR", 0x41),
+    ("ADC", 0x61),
+    ("STA", 0x81),
+    ("LDA", 0xA1),
+    ("CMP", 0xC1),
+    ("SBC", 0xE1),
+];
+
+const ALU_OFFSETS: [u8; 9] = [0x08, 0x04, 0x14, 0x0C, 0x1C, 0x18, 0x00, 0x10, 0x11];
+
+const OPCODES: &[(&str, Mode, u8)] = &[
+    // Shifts, rotates, increments and decrements
+    ("ASL", Mode::Implied, 0x0A),
+    ("ASL", Mode::ZeroPage, 0x06),
+    ("ASL", Mode::ZeroPageX, 0x16),
+    ("ASL", Mode::Absolute, 0x0E),
+    ("ASL", Mode::AbsoluteX, 0x1E),
+    ("ROL", Mode::Implied, 0x2A),
+    ("ROL", Mode::ZeroPage, 0x26),
+    ("ROL", Mode::ZeroPageX, 0x36),
+    ("ROL", Mode::Absolute, 0x2E),
+    ("ROL", Mode::AbsoluteX, 0x3E),
+    ("LSR", Mode::Implied, 0x4A),
+    ("LSR", Mode::ZeroPage, 0x46),
+    ("LSR", Mode::ZeroPageX, 0x56),
+    ("LSR", Mode::Absolute, 0x4E),
+    ("LSR", Mode::AbsoluteX, 0x5E),
+    ("ROR", Mode::Implied, 0x6A),
+    ("ROR", Mode::ZeroPage, 0x66),
+    ("ROR", Mode::ZeroPageX, 0x76),
+    ("ROR", Mode::Absolute, 0x6E),
+    ("ROR", Mode::AbsoluteX, 0x7E),
+    ("INC", Mode::Implied, 0x1A),
+    ("INC", Mode::ZeroPage, 0xE6),
+    ("INC", Mode::ZeroPageX, 0xF6),
+    ("INC", Mode::Absolute, 0xEE),
+    ("INC", Mode::AbsoluteX, 0xFE),
+    ("DEC", Mode::Implied, 0x3A),
+    ("DEC", Mode::ZeroPage, 0xC6),
+    ("DEC", Mode::ZeroPageX, 0xD6),
+    ("DEC", Mode::Absolute, 0xCE),
+    ("DEC", Mode::AbsoluteX, 0xDE),
+    // Bit tests
+    ("BIT", Mode::Immediate, 0x89),
+    ("BIT", Mode::ZeroPage, 0x24),
+    ("BIT", Mode::ZeroPageX, 0x34),
+    ("BIT", Mode::Absolute, 0x2C),
+    ("BIT", Mode::AbsoluteX, 0x3C),
+    ("TSB", Mode::ZeroPage, 0x04),
+    ("TSB", Mode::Absolute, 0x0C),
+    ("TRB", Mode::ZeroPage, 0x14),
+    ("TRB", Mode::Absolute, 0x1C),
+    // Index register loads, stores and compares
+    ("LDX", Mode::Immediate, 0xA2),
+    ("LDX", Mode::ZeroPage, 0xA6),
+    ("LDX", Mode::ZeroPageY, 0xB6),
+    ("LDX", Mode::Absolute, 0xAE),
+    ("LDX", Mode::AbsoluteY, 0xBE),
+    ("LDY", Mode::Immediate, 0xA0),
+    ("LDY", Mode::ZeroPage, 0xA4),
+    ("LDY", Mode::ZeroPageX, 0xB4),
+    ("LDY", Mode::Absolute, 0xAC),
+    ("LDY", Mode::AbsoluteX, 0xBC),
+    ("STX", Mode::ZeroPage, 0x86),
+    ("STX", Mode::ZeroPageY, 0x96),
+    ("STX", Mode::Absolute, 0x8E),
+    ("STY", Mode::ZeroPage, 0x84),
+    ("STY", Mode::ZeroPageX, 0x94),
+    ("STY", Mode::Absolute, 0x8C),
+    ("STZ", Mode::ZeroPage, 0x64),
+    ("STZ", Mode::ZeroPageX, 0x74),
+    ("STZ", Mode::Absolute, 0x9C),
+    ("STZ", Mode::AbsoluteX, 0x9E),
+    ("CPX", Mode::Immediate, 0xE0),
+    ("CPX", Mode::ZeroPage, 0xE4),
+    ("CPX", Mode::Absolute, 0xEC),
+    ("CPY", Mode::Immediate, 0xC0),
+    ("CPY", Mode::ZeroPage, 0xC4),
+    ("CPY", Mode::Absolute, 0xCC),
+    // Jumps and branches
+    ("JMP", Mode::Absolute, 0x4C),
+    ("JMP", Mode::Indirect, 0x6C),
+    ("JMP", Mode::AbsoluteIndirectX, 0x7C),
+    ("JSR", Mode::Absolute, 0x20),
+    ("BPL", Mode::Relative, 0x10),
+    ("BMI", Mode::Relative, 0x30),
+    ("BVC", Mode::Relative, 0x50),
+    ("BVS", Mode::Relative, 0x70),
+    ("BRA", Mode::Relative, 0x80),
+    ("BCC", Mode::Relative, 0x90),
+    ("BCS", Mode::Relative, 0xB0),
+    ("BNE", Mode::Relative, 0xD0),
+    ("BEQ", Mode::Relative, 0xF0),
+    // Everything without an operand
+    ("BRK", Mode::Implied, 0x00),
+    ("PHP", Mode::Implied, 0x08),
+    ("CLC", Mode::Implied, 0x18),
+    ("PLP", Mode::Implied, 0x28),
+    ("SEC", Mode::Implied, 0x38),
+    ("RTI", Mode::Implied, 0x40),
+    ("PHA", Mode::Implied, 0x48),
+    ("CLI", Mode::Implied, 0x58),
+    ("PHY", Mode::Implied, 0x5A),
+    ("RTS", Mode::Implied, 0x60),
+    ("PLA", Mode::Implied, 0x68),
+    ("SEI", Mode::Implied, 0x78),
+    ("PLY", Mode::Implied, 0x7A),
+    ("DEY", Mode::Implied, 0x88),
+    ("TXA", Mode::Implied, 0x8A),
+    ("TYA", Mode::Implied, 0x98),
+    ("TXS", Mode::Implied, 0x9A),
+    ("TAY", Mode::Implied, 0xA8),
+    ("TAX", Mode::Implied, 0xAA),
+    ("CLV", Mode::Implied, 0xB8),
+    ("TSX", Mode::Implied, 0xBA),
+    ("INY", Mode::Implied, 0xC8),
+    ("DEX", Mode::Implied, 0xCA),
+    ("WAI", Mode::Implied, 0xCB),
+    ("CLD", Mode::Implied, 0xD8),
+    ("PHX", Mode::Implied, 0xDA),
+    ("STP", Mode::Implied, 0xDB),
+    ("INX", Mode::Implied, 0xE8),
+    ("NOP", Mode::Implied, 0xEA),
+    ("SED", Mode::Implied, 0xF8),
+    ("PLX", Mode::Implied, 0xFA),
+];
+
+fn opcode(mnemonic: &str, mode: Mode) -> Option<u8> {
+    // RMBn, SMBn, BBRn and BBSn carry the bit number in the high nibble
+    let bit = mnemonic.get(3..).and_then(|digit| digit.parse::<u8>().ok());
+    if let Some(bit) = bit.filter(|bit| *bit < 8) {
+        let (base, bit_mode) = match &mnemonic[..3] {
+            "RMB" => (0x07, Mode::ZeroPage),
+            "SMB" => (0x87, Mode::ZeroPage),
+            "BBR" => (0x0F, Mode::ZeroPageRelative),
+            "BBS" => (0x8F, Mode::ZeroPageRelative),
+            _ => return None,
+        };
+        return (mode == bit_mode).then_some(base + (bit << 4));
+    }
+
+    if let Some((_, base)) = ALU_OPCODES.iter().find(|(name, _)| *name == mnemonic) {
+        // There is no STA #imm; its slot holds BIT #imm
+        if mnemonic == "STA" && mode == Mode::Immediate {
+            return None;
+        }
+        let index = ALU_MODES.iter().position(|m| *m == mode)?;
+        return Some(base + ALU_OFFSETS[index]);
+    }
+
+    OPCODES
+        .iter()
+        .find(|(name, m, _)| *name == mnemonic && *m == mode)
+        .map(|(_, _, opcode)| *opcode)
+}
+
+/// The operand of an instruction as written, before its size is known
+enum Operand<'a> {
+    None,
+    Immediate(&'a str),
+    IndirectX(&'a str),
+    IndirectY(&'a str),
+    Indirect(&'a str),
+    IndexedX(&'a str),
+    IndexedY(&'a str),
+    Pair(&'a str, &'a str),
+    Direct(&'a str),
+}
+
+impl<'a> Operand<'a> {
+    fn parse(text: &'a str) -> Self {
+        let upper = text.to_ascii_uppercase();
+        let inner = |start: usize, end: usize| text[start..text.len() - end].trim();
+
+        if text.is_empty() || upper == "A" {
+            Operand::None
+        } else if let Some(rest) = text.strip_prefix('#') {
+            Operand::Immediate(rest.trim())
+        } else if text.starts_with('(') && upper.ends_with(",X)") {
+            Operand::IndirectX(inner(1, 3))
+        } else if text.starts_with('(') && upper.ends_with("),Y") {
+            Operand::IndirectY(inner(1, 3))
+        } else if text.starts_with('(') && text.ends_with(')') {
+            Operand::Indirect(inner(1, 1))
+        } else if upper.ends_with(",X") {
+            Operand::IndexedX(inner(0, 2))
+        } else if upper.ends_with(",Y") {
+            Operand::IndexedY(inner(0, 2))
+        } else if let Some((first, second)) = text.split_once(',') {
+            Operand::Pair(first.trim(), second.trim())
+        } else {
+            Operand::Direct(text)
+        }
+    }
+
+    fn expression(&self) -> &'a str {
+        match self {
+            Operand::None => "",
+            Operand::Immediate(e)
+            | Operand::IndirectX(e)
+            | Operand::IndirectY(e)
+            | Operand::Indirect(e)
+            | Operand::IndexedX(e)
+            | Operand::IndexedY(e)
+            | Operand::Pair(e, _)
+            | Operand::Direct(e) => e,
+        }
+    }
+}
+
+pub struct Assembly {
+    pub symbols: HashMap<String, u16>,
+    /// Each run of bytes with the address it starts at
+    pub segments: Vec<(u16, Vec<u8>)>,
+    /// The address of each statement with its line number and text
+    listing: Vec<(u16, usize, String)>,
+}
+
+impl Assembly {
+    /// Finds the statement which assembled to `address`
+    pub fn line_at(&self, address: u16) -> String {
+        self.listing
+            .iter()
+            .filter(|(start, _, _)| *start <= address)
+            .max_by_key(|(start, line, _)| (*start, *line))
+            .map(|(_, line, text)| format!("line {line}: {}", text.trim()))
+            .unwrap_or_else(|| "no source line".to_owned())
+    }
+}
+
+struct Pass {
+    symbols: HashMap<String, u16>,
+    /// The addressing mode picked for each line in the first pass, so that
+    /// forward references can't change the layout in the second
+    modes: HashMap<usize, Mode>,
+    final_pass: bool,
+    /// Address of the current statement
+    here: u16,
+    pc: u16,
+    segments: Vec<(u16, Vec<u8>)>,
+    listing: Vec<(u16, usize, String)>,
+}
+
+/// Assembles `source`, with error messages naming the line at fault
+pub fn assemble(source: &str) -> Result<Assembly, String> {
+    let mut pass = Pass {
+        symbols: HashMap::new(),
+        modes: HashMap::new(),
+        final_pass: false,
+        here: 0,
+        pc: 0,
+        segments: Vec::new(),
+        listing: Vec::new(),
+    };
+    pass.run(source)?;
+
+    let mut pass = Pass {
+        final_pass: true,
+        here: 0,
+        pc: 0,
+        segments: Vec::new(),
+        listing: Vec::new(),
+        ..pass
+    };
+    pass.run(source)?;
+
+    Ok(Assembly {
+        symbols: pass.symbols,
+        segments: pass.segments,
+        listing: pass.listing,
+    })
+}
+
+impl Pass {
+    fn run(&mut self, source: &str) -> Result<(), String> {
+        for (index, text) in source.lines().enumerate() {
+            let line = index + 1;
+            self.statement(line, text)
+                .map_err(|why| format!("line {line}: {why}: {}", text.trim()))?;
+        }
+        Ok(())
+    }
+
+    fn statement(&mut self, line: usize, text: &str) -> Result<(), String> {
+        let mut text = text.split(';').next().unwrap_or("").trim();
+        self.here = self.pc;
+
+        if let Some((label, rest)) = text.split_once(':') {
+            self.define(label.trim(), self.pc)?;
+            text = rest.trim();
+        }
+
+        if text.is_empty() {
+            return Ok(());
+        }
+
+        if let Some((name, expression)) = text.split_once('=') {
+            if let Some(value) = self.evaluate(expression)? {
+                self.define(name.trim(), value)?;
+            }
+            return Ok(());
+        }
+
+        self.listing.push((self.pc, line, text.to_owned()));
+
+        let (word, rest) = text.split_once(char::is_whitespace).unwrap_or((text, ""));
+        let rest = rest.trim();
+
+        match word.to_ascii_lowercase().as_str() {
+            ".org" => {
+                self.pc = self
+                    .evaluate(rest)?
+                    .ok_or("the origin must be known in the first pass")?;
+            }
+            ".byte" => {
+                for expression in rest.split(',') {
+                    let value = self.evaluate(expression)?.unwrap_or(0);
+                    self.emit(self.byte(value)?);
+                }
+            }
+            ".word" => {
+                for expression in rest.split(',') {
+                    let value = self.evaluate(expression)?.unwrap_or(0);
+                    self.emit_word(value);
+                }
+            }
+            _ => self.instruction(line, &word.to_ascii_uppercase(), rest)?,
+        }
+
+        Ok(())
+    }
+
+    fn instruction(&mut self, line: usize, mnemonic: &str, operand: &str) -> Result<(), String> {
+        let operand = Operand::parse(operand);
+        let value = self.evaluate(operand.expression())?;
+
+        let mode = match self.modes.get(&line) {
+            Some(mode) => *mode,
+            None => {
+                let mode = Self::pick_mode(mnemonic, &operand, value)
+                    .ok_or("no such instruction and addressing mode")?;
+                self.modes.insert(line, mode);
+                mode
+            }
+        };
+        let opcode = opcode(mnemonic, mode).ok_or("no such instruction and addressing mode")?;
+
+        let value = value.unwrap_or(0);
+        let next = self.pc.wrapping_add(1 + mode.operand_length());
+        self.emit(opcode);
+
+        match mode {
+            Mode::Implied => {}
+            Mode::Relative => {
+                let offset = self.branch_offset(value, next)?;
+                self.emit(offset);
+            }
+            Mode::ZeroPageRelative => {
+                let Operand::Pair(_, target) = operand else {
+                    unreachable!();
+                };
+                let target = self.evaluate(target)?.unwrap_or(0);
+                self.emit(self.byte(value)?);
+                let offset = self.branch_offset(target, next)?;
+                self.emit(offset);
+            }
+            _ if mode.operand_length() == 1 => self.emit(self.byte(value)?),
+            _ => self.emit_word(value),
+        }
+
+        Ok(())
+    }
+
+    /// Picks the shortest addressing mode that suits the operand
+    fn pick_mode(mnemonic: &str, operand: &Operand, value: Option<u16>) -> Option<Mode> {
+        let fits = value.is_some_and(|value| value <= 0xFF);
+        let either = |short: Mode, long: Mode| {
+            if fits && opcode(mnemonic, short).is_some() {
+                short
+            } else {
+                long
+            }
+        };
+
+        let mode = match operand {
+            Operand::None => Mode::Implied,
+            Operand::Immediate(_) => Mode::Immediate,
+            Operand::IndirectX(_) => either(Mode::IndirectX, Mode::AbsoluteIndirectX),
+            Operand::IndirectY(_) => Mode::IndirectY,
+            Operand::Indirect(_) => either(Mode::IndirectZeroPage, Mode::Indirect),
+            Operand::IndexedX(_) => either(Mode::ZeroPageX, Mode::AbsoluteX),
+            Operand::IndexedY(_) => either(Mode::ZeroPageY, Mode::AbsoluteY),
+            Operand::Pair(_, _) => Mode::ZeroPageRelative,
+            Operand::Direct(_) if opcode(mnemonic, Mode::Relative).is_some() => Mode::Relative,
+            Operand::Direct(_) => either(Mode::ZeroPage, Mode::Absolute),
+        };
+
+        opcode(mnemonic, mode).map(|_| mode)
+    }
+
+    fn branch_offset(&self, target: u16, next: u16) -> Result<u8, String> {
+        let offset = target.wrapping_sub(next) as i16;
+        if self.final_pass && !(-128..=127).contains(&offset) {
+            return Err(format!("branch to ${target:04X} is out of range"));
+        }
+        Ok(offset as u8)
+    }
+
+    fn byte(&self, value: u16) -> Result<u8, String> {
+        if self.final_pass && value > 0xFF {
+            return Err(format!("${value:04X} does not fit in a byte"));
+        }
+        Ok(value as u8)
+    }
+
+    fn emit(&mut self, byte: u8) {
+        match self.segments.last_mut() {
+            Some((start, bytes)) if start.wrapping_add(bytes.len() as u16) == self.pc => {
+                bytes.push(byte)
+            }
+            _ => self.segments.push((self.pc, vec![byte])),
+        }
+        self.pc = self.pc.wrapping_add(1);
+    }
+
+    fn emit_word(&mut self, word: u16) {
+        for byte in word.to_le_bytes() {
+            self.emit(byte);
+        }
+    }
+
+    fn define(&mut self, name: &str, value: u16) -> Result<(), String> {
+        let valid = name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if !valid {
+            return Err(format!("{name:?} is not a valid symbol name"));
+        }
+
+        match self.symbols.insert(name.to_owned(), value) {
+            Some(old) if !self.final_pass && old != value => {
+                Err(format!("{name} is defined twice"))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Evaluates an expression, or returns `None` in the first pass if it
+    /// refers to a symbol that is not defined yet
+    fn evaluate(&self, expression: &str) -> Result<Option<u16>, String> {
+        let expression = expression.trim();
+        if let Some(rest) = expression.strip_prefix('<') {
+            return Ok(self.evaluate(rest)?.map(|value| value & 0xFF));
+        }
+        if let Some(rest) = expression.strip_prefix('>') {
+            return Ok(self.evaluate(rest)?.map(|value| value >> 8));
+        }
+
+        let mut total = Some(0u16);
+        let mut negate = false;
+        let mut term_start = 0;
+        let terms = expression
+            .char_indices()
+            .filter(|(_, c)| *c == '+' || *c == '-');
+
+        for (end, sign) in terms.chain([(expression.len(), '+')]) {
+            let term = expression[term_start..end].trim();
+            // A sign at the very start applies to the first term
+            if !term.is_empty() || end != 0 {
+                let value = self.term(term)?;
+                total = total.zip(value).map(|(total, value)| {
+                    if negate {
+                        total.wrapping_sub(value)
+                    } else {
+                        total.wrapping_add(value)
+                    }
+                });
+            }
+            negate = sign == '-';
+            term_start = end + 1;
+        }
+
+        Ok(total)
+    }
+
+    fn term(&self, term: &str) -> Result<Option<u16>, String> {
+        let number = |digits: &str, radix| {
+            u16::from_str_radix(digits, radix)
+                .map(Some)
+                .map_err(|_| format!("{term:?} is not a number"))
+        };
+
+        if let Some(hex) = term.strip_prefix('$') {
+            number(hex, 16)
+        } else if let Some(binary) = term.strip_prefix('%') {
+            number(binary, 2)
+        } else if term.starts_with(|c: char| c.is_ascii_digit()) {
+            number(term, 10)
+        } else if term.len() == 3 && term.starts_with('\'') && term.ends_with('\'') {
+            Ok(Some(term.as_bytes()[1] as u16))
+        } else if term == "*" {
+            Ok(Some(self.here))
+        } else if let Some(value) = self.symbols.get(term) {
+            Ok(Some(*value))
+        } else if self.final_pass {
+            Err(format!("{term:?} is not defined"))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+#[test]
+fn assembles_each_addressing_mode() {
+    let source = "
+        zp = $12
+        .org $0300
+    start:
+        nop
+        asl a
+        lda #$34
+        lda zp
+        lda zp,x
+        ldx zp,y
+        lda $1234
+        lda $1234,x
+        lda $1234,y
+        lda (zp,x)
+        lda (zp),y
+        lda (zp)
+        jmp ($1234)
+        jmp ($1234,x)
+        bbr3 zp,start
+        bne start
+        rmb7 zp
+    ";
+    let assembly = assemble(source).unwrap();
+
+    assert_eq!(assembly.symbols["start"], 0x0300);
+    assert_eq!(
+        assembly.segments,
+        vec![(
+            0x0300,
+            vec![
+                0xEA, 0x0A, 0xA9, 0x34, 0xA5, 0x12, 0xB5, 0x12, 0xB6, 0x12, 0xAD, 0x34, 0x12, 0xBD,
+                0x34, 0x12, 0xB9, 0x34, 0x12, 0xA1, 0x12, 0xB1, 0x12, 0xB2, 0x12, 0x6C, 0x34, 0x12,
+                0x7C, 0x34, 0x12, 0x3F, 0x12, 0xDE, 0xD0, 0xDC, 0x77, 0x12,
+            ]
+        )]
+    );
+}
+
+#[test]
+fn resolves_forward_references_and_expressions() {
+    let source = "
+        .org $02FE
+        jmp later+1
+        .byte <later, >later, 'A', %101, 10-3
+        .word *
+    later = $0400
+    ";
+    let assembly = assemble(source).unwrap();
+
+    assert_eq!(
+        assembly.segments,
+        vec![(
+            0x02FE,
+            vec![0x4C, 0x01, 0x04, 0x00, 0x04, 0x41, 0x05, 0x07, 0x06, 0x03]
+        )]
+    );
+    assert_eq!(
+        assembly.line_at(0x0302),
+        "line 4: .byte <later, >later, 'A', %101, 10-3"
+    );
+}
+
+#[test]
+fn reports_errors_by_line() {
+    assert_eq!(
+        assemble("  nop\n  stx $1234,x\n").err().unwrap(),
+        "line 2: no such instruction and addressing mode: stx $1234,x"
+    );
+    assert_eq!(
+        assemble("  bne far\n  .org $1000\nfar:\n").err().unwrap(),
+        "line 1: branch to $1000 is out of range: bne far"
+    );
+    assert_eq!(
+        assemble("  lda missing\n").err().unwrap(),
+        "line 1: \"missing\" is not defined: lda missing"
+    );
+}
@@ -0,0 +1,427 @@
+//! Tests for the 65C02 core.
+//!
+//! Besides tests of single instructions, this runs two functional test
+//! programs which are assembled in-tree by [`assembler`]:
+//!
+//! - `extended_opcodes.a65` checks the instructions and addressing modes the
+//!   65C02 added to the 6502, in the manner of Klaus Dormann's 65C02 extended
+//!   opcodes test
+//! - `decimal.a65` is Bruce Clark's decimal mode test with the 65C02
+//!   predictions, over every combination of operands and carry
+//!
+//! Both end in a `success` loop, and get stuck branching to themselves at the
+//! check that failed otherwise.
+
+mod assembler;
+
+use super::{AddressingMode, Core, HandlesInterrupt, RunState};
+use crate::memory::AddressSpace;
+use assembler::Assembly;
+
+/// A flat 64 KiB RAM with no peripherals
+struct FlatAddressSpace {
+    memory: Box<[u8; 0x10000]>,
+    interrupted: bool,
+}
+
+impl FlatAddressSpace {
+    fn new() -> Self {
+        Self {
+            memory: Box::new([0u8; 0x10000]),
+            interrupted: false,
+        }
+    }
+
+    fn load(&mut self, start: u16, data: &[u8]) {
+        let start = start as usize;
+        self.memory[start..start + data.len()].copy_from_slice(data);
+    }
+}
+
+impl AddressSpace for FlatAddressSpace {
+    fn read_u8(&mut self, address: usize) -> u8 {
+        self.memory[address & 0xFFFF]
+    }
+
+    fn write_u8(&mut self, address: usize, value: u8) {
+        self.memory[address & 0xFFFF] = value;
+    }
+}
+
+impl HandlesInterrupt for FlatAddressSpace {
+    fn set_interrupted(&mut self, interrupted: bool) {
+        self.interrupted = interrupted;
+    }
+
+    fn interrupted(&self) -> bool {
+        self.interrupted
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Outcome {
+    /// An instruction jumped or branched to itself
+    Trapped(u16),
+    /// STP was executed at the given address
+    Stopped(u16),
+    /// The instruction limit was reached
+    Timeout(u16),
+}
+
+fn run(core: &mut Core<FlatAddressSpace>, max_instructions: u64) -> Outcome {
+    for _ in 0..max_instructions {
+        let pc = core.registers.pc;
+        core.step();
+
+        if core.run_state == RunState::Stopped {
+            return Outcome::Stopped(pc);
+        }
+
+        if core.registers.pc == pc {
+            return Outcome::Trapped(pc);
+        }
+    }
+
+    Outcome::Timeout(core.registers.pc)
+}
+
+fn make_core(start: u16, load_address: u16, program: &[u8]) -> Core<FlatAddressSpace> {
+    let mut address_space = FlatAddressSpace::new();
+    address_space.load(load_address, program);
+
+    let mut core = Core::new(2, address_space);
+    core.registers.pc = start;
+    core.registers.sp = 0xFF;
+    core
+}
+
+/// Runs a single instruction placed at $0400
+fn execute(core: &mut Core<FlatAddressSpace>, instruction: &[u8]) {
+    core.address_space.load(0x0400, instruction);
+    core.registers.pc = 0x0400;
+    core.step();
+}
+
+fn to_bcd(value: u8) -> u8 {
+    ((value / 10) << 4) | (value % 10)
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0x0F)
+}
+
+/// Assembles a functional test program and runs it from its `start` label
+/// until it gets stuck, which must be in its `success` loop
+fn run_program(source: &str, max_instructions: u64) {
+    let assembly = assembler::assemble(source).unwrap_or_else(|why| panic!("{why}"));
+    let mut core = load_program(&assembly);
+
+    let success = assembly.symbols["success"];
+    let outcome = run(&mut core, max_instructions);
+    let pc = match outcome {
+        Outcome::Trapped(pc) if pc == success => return,
+        Outcome::Trapped(pc) | Outcome::Stopped(pc) | Outcome::Timeout(pc) => pc,
+    };
+
+    panic!(
+        "Failed with {outcome:?} at {} ({}; P: {:02X}; zero page: {:02X?})",
+        assembly.line_at(pc),
+        core.registers.to_string(),
+        core.flags.to_u8(),
+        &core.address_space.memory[..0x40]
+    );
+}
+
+fn load_program(assembly: &Assembly) -> Core<FlatAddressSpace> {
+    let mut core = make_core(assembly.symbols["start"], 0, &[]);
+    for (start, bytes) in &assembly.segments {
+        core.address_space.load(*start, bytes);
+    }
+    core
+}
+
+#[test]
+fn extended_opcodes() {
+    run_program(include_str!("extended_opcodes.a65"), 10_000);
+}
+
+#[test]
+fn decimal_mode() {
+    run_program(include_str!("decimal.a65"), 100_000_000);
+}
+
+#[test]
+fn detects_jump_trap() {
+    // JMP $0400
+    let mut core = make_core(0x0400, 0x0400, &[0x4C, 0x00, 0x04]);
+    assert_eq!(run(&mut core, 10), Outcome::Trapped(0x0400));
+}
+
+#[test]
+fn detects_branch_trap() {
+    // LDA #$01; BNE *
+    let mut core = make_core(0x0400, 0x0400, &[0xA9, 0x01, 0xD0, 0xFE]);
+    assert_eq!(run(&mut core, 10), Outcome::Trapped(0x0402));
+}
+
+#[test]
+fn detects_stop() {
+    // NOP; STP
+    let mut core = make_core(0x0400, 0x0400, &[0xEA, 0xDB]);
+    assert_eq!(run(&mut core, 10), Outcome::Stopped(0x0401));
+}
+
+#[test]
+fn times_out() {
+    // INX; BRA -3
+    let mut core = make_core(0x0400, 0x0400, &[0xE8, 0x80, 0xFD]);
+    assert_eq!(run(&mut core, 100), Outcome::Timeout(0x0400));
+}
+
+#[test]
+fn brk_vectors_through_standard_location() {
+    // BRK; signature byte; STP
+    let mut core = make_core(0x0400, 0x0400, &[0x00, 0xFF, 0xDB]);
+    // Handler at $0500: STP
+    core.address_space.load(0x0500, &[0xDB]);
+    core.address_space.load(0xFFFE, &[0x00, 0x05]);
+
+    assert_eq!(run(&mut core, 10), Outcome::Stopped(0x0500));
+
+    // Return address skips the signature byte, and B is set in the pushed status
+    assert_eq!(core.address_space.read_u8(0x01FF), 0x04);
+    assert_eq!(core.address_space.read_u8(0x01FE), 0x02);
+    assert_ne!(core.address_space.read_u8(0x01FD) & 0b0001_0000, 0);
+    assert!(core.flags.interrupt_disable);
+}
+
+#[test]
+fn binary_adc_and_sbc() {
+    let mut core = make_core(0x0400, 0x0400, &[]);
+
+    for a in 0..=0xFFu8 {
+        for operand in 0..=0xFFu8 {
+            for carry in [false, true] {
+                // ADC #operand
+                core.registers.a = a;
+                core.flags.carry = carry;
+                execute(&mut core, &[0x69, operand]);
+
+                let sum = a as u16 + operand as u16 + carry as u16;
+                let signed_sum = a as i8 as i16 + operand as i8 as i16 + carry as i16;
+                assert_eq!(core.registers.a, sum as u8, "{a:02X} + {operand:02X}");
+                assert_eq!(core.flags.carry, sum > 0xFF, "{a:02X} + {operand:02X}");
+                assert_eq!(
+                    core.flags.overflow,
+                    !(-128..=127).contains(&signed_sum),
+                    "{a:02X} + {operand:02X}"
+                );
+                assert_eq!(core.flags.zero, sum as u8 == 0);
+                assert_eq!(core.flags.negative, sum & 0x80 != 0);
+
+                // SBC #operand
+                core.registers.a = a;
+                core.flags.carry = carry;
+                execute(&mut core, &[0xE9, operand]);
+
+                let borrow = !carry as i16;
+                let difference = a as i16 - operand as i16 - borrow;
+                let signed_difference = a as i8 as i16 - operand as i8 as i16 - borrow;
+                assert_eq!(
+                    core.registers.a, difference as u8,
+                    "{a:02X} - {operand:02X}"
+                );
+                assert_eq!(core.flags.carry, difference >= 0, "{a:02X} - {operand:02X}");
+                assert_eq!(
+                    core.flags.overflow,
+                    !(-128..=127).contains(&signed_difference),
+                    "{a:02X} - {operand:02X}"
+                );
+                assert_eq!(core.flags.zero, difference as u8 == 0);
+                assert_eq!(core.flags.negative, difference & 0x80 != 0);
+            }
+        }
+    }
+}
+
+#[test]
+fn decimal_adc_and_sbc() {
+    let mut core = make_core(0x0400, 0x0400, &[]);
+    core.flags.decimal = true;
+
+    for a in (0..100).map(to_bcd) {
+        for operand in (0..100).map(to_bcd) {
+            for carry in [false, true] {
+                // ADC #operand
+                core.registers.a = a;
+                core.flags.carry = carry;
+                execute(&mut core, &[0x69, operand]);
+
+                let sum = from_bcd(a) + from_bcd(operand) + carry as u8;
+                let expected = to_bcd(sum % 100);
+                assert_eq!(core.registers.a, expected, "{a:02X} + {operand:02X}");
+                assert_eq!(core.flags.carry, sum >= 100, "{a:02X} + {operand:02X}");
+                // Unlike the NMOS 6502, N and Z reflect the decimal result
+                assert_eq!(core.flags.zero, expected == 0);
+                assert_eq!(core.flags.negative, expected & 0x80 != 0);
+
+                // SBC #operand
+                core.registers.a = a;
+                core.flags.carry = carry;
+                execute(&mut core, &[0xE9, operand]);
+
+                let difference = from_bcd(a) as i16 - from_bcd(operand) as i16 - !carry as i16;
+                let expected = to_bcd(difference.rem_euclid(100) as u8);
+                assert_eq!(core.registers.a, expected, "{a:02X} - {operand:02X}");
+                assert_eq!(core.flags.carry, difference >= 0, "{a:02X} - {operand:02X}");
+                assert_eq!(core.flags.zero, expected == 0);
+                assert_eq!(core.flags.negative, expected & 0x80 != 0);
+            }
+        }
+    }
+}
+
+#[test]
+fn decimal_arithmetic_takes_an_extra_cycle() {
+    let mut core = make_core(0x0400, 0x0400, &[]);
+
+    // ADC #$01, then SBC #$01
+    for opcode in [0x69, 0xE9] {
+        core.flags.decimal = false;
+        let start = core.cycles;
+        execute(&mut core, &[opcode, 0x01]);
+        let binary_cycles = core.cycles - start;
+
+        core.flags.decimal = true;
+        let start = core.cycles;
+        execute(&mut core, &[opcode, 0x01]);
+        assert_eq!(core.cycles - start, binary_cycles + 1);
+    }
+}
+
+#[test]
+fn bit_set_and_reset_leave_flags() {
+    let mut core = make_core(0x0400, 0x0400, &[]);
+    core.flags.zero = false;
+
+    // RMB0 $10 clears the only set bit
+    core.address_space.load(0x0010, &[0x01]);
+    execute(&mut core, &[0x07, 0x10]);
+    assert_eq!(core.address_space.read_u8(0x0010), 0x00);
+    assert!(!core.flags.zero);
+
+    // SMB7 $10
+    core.flags.zero = true;
+    execute(&mut core, &[0xF7, 0x10]);
+    assert_eq!(core.address_space.read_u8(0x0010), 0x80);
+    assert!(core.flags.zero);
+}
+
+#[test]
+fn php_pushes_break_and_unused_bits() {
+    let mut core = make_core(0x0400, 0x0400, &[]);
+    core.flags.carry = true;
+
+    // PHP
+    execute(&mut core, &[0x08]);
+    assert_eq!(core.address_space.read_u8(0x01FF), 0b0011_0001);
+}
+
+#[test]
+fn x_indexed_indirect_store() {
+    let mut core = make_core(0x0400, 0x0400, &[]);
+    core.address_space.load(0x0014, &[0x00, 0x03]);
+    core.registers.a = 0x42;
+    core.registers.x = 0x04;
+
+    // STA ($10,X)
+    execute(&mut core, &[0x81, 0x10]);
+    assert_eq!(core.address_space.read_u8(0x0300), 0x42);
+}
+
+#[test]
+fn zero_page_y_indexed() {
+    let mut core = make_core(0x0400, 0x0400, &[]);
+    core.address_space.load(0x00F5, &[0x99]);
+    core.registers.y = 0x05;
+
+    // LDX $F0,Y
+    execute(&mut core, &[0xB6, 0xF0]);
+    assert_eq!(core.registers.x, 0x99);
+    assert!(core.flags.negative);
+
+    // STX $FE,Y wraps within the zero page
+    execute(&mut core, &[0x96, 0xFE]);
+    assert_eq!(core.address_space.read_u8(0x0003), 0x99);
+    assert_eq!(core.address_space.read_u8(0x0103), 0x00);
+}
+
+#[test]
+fn zero_page_pointers_wrap() {
+    let mut core = make_core(0x0400, 0x0400, &[]);
+    core.address_space.load(0x00FF, &[0x34]);
+    core.address_space.load(0x0000, &[0x12]);
+    core.address_space.load(0x1234, &[0x77]);
+
+    // LDA ($FF)
+    execute(&mut core, &[0xB2, 0xFF]);
+    assert_eq!(core.registers.a, 0x77);
+
+    // LDA ($FF),Y
+    core.registers.y = 0x01;
+    core.address_space.load(0x1235, &[0x88]);
+    execute(&mut core, &[0xB1, 0xFF]);
+    assert_eq!(core.registers.a, 0x88);
+
+    // LDA ($FE,X)
+    core.registers.x = 0x01;
+    execute(&mut core, &[0xA1, 0xFE]);
+    assert_eq!(core.registers.a, 0x77);
+}
+
+#[test]
+fn every_opcode_executes() {
+    for opcode in 0..=0xFFu8 {
+        let mut core = make_core(0x0400, 0x0400, &[opcode, 0x10, 0x02]);
+        core.address_space.load(0x0010, &[0x00, 0x03]);
+
+        core.step();
+        assert!(core.cycles > 0, "Opcode {opcode:02X} took no cycles");
+    }
+}
+
+#[test]
+fn indirect_data_operands() {
+    let mut core = make_core(0x0400, 0x0400, &[]);
+    core.address_space.load(0x1000, &[0x00, 0x03, 0x10, 0x03]);
+    core.registers.x = 0x02;
+
+    // ($1000)
+    let mode = AddressingMode::Indirect(0x1000);
+    assert!(!mode.write_operand_u8(&mut core, 0x42));
+    assert_eq!(core.address_space.read_u8(0x0300), 0x42);
+    assert_eq!(mode.read_operand_u8(&mut core), (0x42, false));
+
+    // ($1000,X)
+    let mode = AddressingMode::AbsoluteXIndexedIndirect(0x1000);
+    assert!(!mode.write_operand_u8(&mut core, 0x43));
+    assert_eq!(core.address_space.read_u8(0x0310), 0x43);
+    assert_eq!(mode.read_operand_u8(&mut core), (0x43, false));
+
+    // ($10FF,X) crosses into the next page to find the pointer
+    core.address_space.load(0x1101, &[0x20, 0x03]);
+    let mode = AddressingMode::AbsoluteXIndexedIndirect(0x10FF);
+    assert!(mode.write_operand_u8(&mut core, 0x44));
+    assert_eq!(core.address_space.read_u8(0x0320), 0x44);
+}
+
+#[test]
+fn zero_page_relative_operand() {
+    let mut core = make_core(0x0400, 0x0400, &[]);
+
+    let mode = AddressingMode::ZeroPageRelative(0x20, -4);
+    mode.write_operand_u8(&mut core, 0x81);
+    assert_eq!(core.address_space.read_u8(0x0020), 0x81);
+    assert_eq!(mode.read_operand_u8(&mut core), (0x81, false));
+    assert_eq!(mode.read_operand_u8_i8(&mut core), ((0x81, -4), false));
+}
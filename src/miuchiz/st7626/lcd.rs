@@ -1,5 +1,6 @@
 use crate::{
     memory::AddressSpace,
+    savestate::{SaveState, StateError, StateReader, StateWriter},
     screen::{Pixel, Screen},
 };
 
@@ -7,8 +8,8 @@ const COMMAND_REG: usize = 0;
 const DATA_REG: usize = 1;
 const REG_COUNT: usize = 2;
 
-const LCD_WIDTH: usize = 98;
-const LCD_HEIGHT: usize = 67;
+pub const LCD_WIDTH: usize = 98;
+pub const LCD_HEIGHT: usize = 67;
 
 const DDRAM_PAGE: usize = 68;
 const DDRAM_COLUMN: usize = 98;
@@ -35,6 +36,9 @@ pub struct Lcd {
     screen: Box<dyn Screen>,
 
     voltage: Voltage,
+
+    /// CPU cycle count, to timestamp frames
+    current_cycle: u64,
 }
 
 /// Voltage is a weird 9 bit register
@@ -193,6 +197,57 @@ impl Command {
 
         Some(command)
     }
+
+    /// The extension setting and byte which select this command
+    pub fn to_val(&self) -> (bool, u8) {
+        match self {
+            Self::DisplayOn => (false, 0xAF),
+            Self::DisplayOff => (false, 0xAE),
+            Self::NormalDisplay => (false, 0xA6),
+            Self::InverseDisplay => (false, 0xA7),
+            Self::ComScanDirection => (false, 0xBB),
+            Self::DisplayControl => (false, 0xCA),
+            Self::SleepInOutPreparation => (false, 0x04),
+            Self::SleepIn => (false, 0x95),
+            Self::SleepOut => (false, 0x94),
+            Self::PageAddressSet => (false, 0x75),
+            Self::ColumnAddressSet => (false, 0x15),
+            Self::DataScanDirection => (false, 0xBC),
+            Self::WritingToMemory => (false, 0x5C),
+            Self::ReadingFromMemory => (false, 0x5D),
+            Self::PartialDisplayIn => (false, 0xA8),
+            Self::PartialDisplayOut => (false, 0xA9),
+            Self::ReadModifyWriteIn => (false, 0xE0),
+            Self::ReadModifyWriteOut => (false, 0xEE),
+            Self::AreaScrollSet => (false, 0xAA),
+            Self::ScrollStartSet => (false, 0xAB),
+            Self::InternalOscOn => (false, 0xD1),
+            Self::InternalOscOff => (false, 0xD2),
+            Self::PowerControl => (false, 0x20),
+            Self::EcControl => (false, 0x81),
+            Self::EcIncrease1 => (false, 0xD6),
+            Self::EcDecrease1 => (false, 0xD7),
+            Self::ReadRegister1 => (false, 0x7C),
+            Self::ReadRegister2 => (false, 0x7D),
+            Self::NoOperation => (false, 0x25),
+            Self::EepromFunctionStart => (false, 0x07),
+
+            Self::Frame1PwmSet => (true, 0x20),
+            Self::Frame2PwmSet => (true, 0x21),
+            Self::Frame3PwmSet => (true, 0x22),
+            Self::Frame4PwmSet => (true, 0x23),
+            Self::AnalogSet => (true, 0x32),
+            Self::ControlEeprom => (true, 0xCD),
+            Self::CancelEeprom => (true, 0xCC),
+            Self::WriteToEeprom => (true, 0xFC),
+            Self::ReadFromEeprom => (true, 0xFD),
+            Self::DisplayPerformanceAdjustment => (true, 0xFA),
+            Self::InternalInitializePreparation => (true, 0xF4),
+
+            Self::ExtOff => (false, 0x30),
+            Self::ExtOn => (false, 0x31),
+        }
+    }
 }
 
 enum Register {
@@ -226,8 +281,13 @@ impl Lcd {
             display_on: false,
             screen,
             voltage: Voltage::new(Voltage::max()),
+            current_cycle: 0,
         }
     }
+
+    pub fn set_current_cycle(&mut self, cycle: u64) {
+        self.current_cycle = cycle;
+    }
 }
 
 impl Lcd {
@@ -243,7 +303,7 @@ impl Lcd {
             Command::DisplayOn => self.display_on = true,
             Command::EcControl => {}
             _ => {
-                println!("Unimplemented LCD command {command:?}")
+                eprintln!("Unimplemented LCD command {command:?}")
             }
         }
         self.active_command = Some(command);
@@ -252,7 +312,7 @@ impl Lcd {
 
     fn handle_data(&mut self, value: u8) {
         let Some(command) = &self.active_command else {
-            println!("LCD received data with no active command.");
+            eprintln!("LCD received data with no active command.");
             return;
         };
         // println!("LCD data {value:02X} to command {:?} (byte {})", command, self.byte_since_command);
@@ -303,7 +363,7 @@ impl Lcd {
                 }
             }
             _ => {
-                println!("Received unhandled data for command {command:?}");
+                eprintln!("Received unhandled data for command {command:?}");
             }
         }
 
@@ -360,7 +420,7 @@ impl Lcd {
 
         // println!("Pixel len {}, pages {} col {}", pixels.len(), self.end_page, self.end_column);
 
-        self.screen.set_pixels(&pixels);
+        self.screen.set_pixels(&pixels, self.current_cycle);
     }
 
     fn get_voltage_percent(&self) -> f32 {
@@ -382,9 +442,63 @@ impl Lcd {
     }
 }
 
+impl SaveState for Lcd {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.ext);
+        match &self.active_command {
+            Some(command) => {
+                let (ext, val) = command.to_val();
+                state.write_bool(true);
+                state.write_bool(ext);
+                state.write_u8(val);
+            }
+            None => state.write_bool(false),
+        }
+        state.write_u64(self.byte_since_command as u64);
+        state.write_bytes(&self.ddram);
+        state.write_u64(self.ddram_ptr as u64);
+        state.write_u8(self.start_page);
+        state.write_u8(self.end_page);
+        state.write_u8(self.start_column);
+        state.write_u8(self.end_column);
+        state.write_bool(self.display_on);
+        state.write_u16(self.voltage.get());
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.ext = state.read_bool()?;
+        self.active_command = if state.read_bool()? {
+            let ext = state.read_bool()?;
+            let val = state.read_u8()?;
+            Some(Command::from_val(ext, val).ok_or(StateError::InvalidValue("LCD command"))?)
+        } else {
+            None
+        };
+        self.byte_since_command = state.read_u64()? as usize;
+        state.read_bytes(&mut self.ddram)?;
+
+        let ddram_ptr = state.read_u64()? as usize;
+        if ddram_ptr >= self.ddram.len() {
+            return Err(StateError::InvalidValue("LCD DDRAM pointer"));
+        }
+        self.ddram_ptr = ddram_ptr;
+
+        self.start_page = state.read_u8()?;
+        self.end_page = state.read_u8()?;
+        self.start_column = state.read_u8()?;
+        self.end_column = state.read_u8()?;
+        self.display_on = state.read_bool()?;
+        self.voltage.set(state.read_u16()?);
+
+        // Show the restored frame right away rather than waiting for the next one
+        self.update_display();
+        Ok(())
+    }
+}
+
 impl AddressSpace for Lcd {
     fn read_u8(&mut self, address: usize) -> u8 {
-        println!("Unimplemented read u8 LCD address {address}");
+        eprintln!("Unimplemented read u8 LCD address {address}");
         0xff
     }
 
@@ -392,7 +506,7 @@ impl AddressSpace for Lcd {
         match Register::from_address(address) {
             Register::Command => {
                 let Some(command) = Command::from_val(self.ext, value) else {
-                    println!("Write invalid video command {value:02X} ext: {}", self.ext);
+                    eprintln!("Write invalid video command {value:02X} ext: {}", self.ext);
                     return;
                 };
                 self.handle_command(command);
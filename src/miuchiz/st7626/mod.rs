@@ -1,2 +1,2 @@
 mod lcd;
-pub use lcd::Lcd;
+pub use lcd::{Lcd, LCD_HEIGHT, LCD_WIDTH};
@@ -0,0 +1,44 @@
+use std::cell::RefCell;
+use std::io::Write;
+
+use crate::gpio::{GpioButtonState, GpioInterface, GpioPin};
+
+/// Passes input through while writing every change of an output pin's level
+/// to a text file, one per line, such as `8000000 PC4 high`
+pub struct PinRecorder<W: Write> {
+    io: Box<dyn GpioInterface>,
+    output: RefCell<W>,
+}
+
+impl<W: Write> PinRecorder<W> {
+    pub fn new(io: Box<dyn GpioInterface>, mut output: W) -> std::io::Result<Self> {
+        writeln!(output, "# emiu2 pin recording")?;
+        Ok(Self {
+            io,
+            output: RefCell::new(output),
+        })
+    }
+}
+
+impl<W: Write> GpioInterface for PinRecorder<W> {
+    fn get_updates(&self, current_cycle: u64) -> Option<GpioButtonState> {
+        self.io.get_updates(current_cycle)
+    }
+
+    fn next_external_clock_edge(&self, current_cycle: u64) -> Option<u64> {
+        self.io.next_external_clock_edge(current_cycle)
+    }
+
+    fn external_clock_level(&self, current_cycle: u64) -> bool {
+        self.io.external_clock_level(current_cycle)
+    }
+
+    fn output_changed(&self, pin: GpioPin, high: bool, current_cycle: u64) {
+        let level = if high { "high" } else { "low" };
+        if let Err(why) = writeln!(self.output.borrow_mut(), "{current_cycle} {pin} {level}") {
+            eprintln!("Failed to record pins: {why}");
+        }
+
+        self.io.output_changed(pin, high, current_cycle);
+    }
+}
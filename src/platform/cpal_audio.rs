@@ -1,7 +1,8 @@
-use std::collections::VecDeque;
 use std::error::Error;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::mpsc::{channel, Receiver, Sender};
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
 
 use cpal::StreamConfig;
 use cpal::{
@@ -9,93 +10,314 @@ use cpal::{
     FromSample, Sample, SizedSample,
 };
 
-use crate::audio::AudioInterface;
+use crate::audio::{AudioInterface, SampleClock};
+use crate::resampler::Resampler;
+
+/// The emulator is sampled at this multiple of the output rate, and filtered down
+const OVERSAMPLING: u32 = 4;
+
+/// Amount of audio to keep queued for the output, in seconds
+const TARGET_LATENCY: f64 = 0.05;
+
+/// Queued audio beyond this multiple of the target is dropped
+const MAX_LATENCY_FACTOR: usize = 4;
+
+/// Largest change in playback speed used to keep the queue at its target
+const MAX_ADJUSTMENT: f64 = 0.005;
+
+/// How quickly the measured queue length follows the real one, per callback
+const FILL_SMOOTHING: f64 = 0.05;
+
+/// How far the output has got through the emulator's audio, shared between the two threads
+struct Playback {
+    /// Samples sent by the emulator
+    produced: AtomicU64,
+    /// Samples played or dropped by the output
+    consumed: Mutex<u64>,
+    played: Condvar,
+}
+
+impl Playback {
+    fn new() -> Self {
+        Self {
+            produced: AtomicU64::new(0),
+            consumed: Mutex::new(0),
+            played: Condvar::new(),
+        }
+    }
+}
 
 struct AudioReceiver {
     audio_rx: Receiver<Vec<f32>>,
-    buffer: VecDeque<f32>,
+    playback: Arc<Playback>,
+    /// Samples taken from `audio_rx` so far
+    received: u64,
+    resampler: Resampler,
+    target_fill: usize,
+    average_fill: f64,
+    /// Set while the queue is being built back up after running dry
+    buffering: bool,
     last_sample: f32,
 }
 
 impl AudioReceiver {
-    fn new(audio_rx: Receiver<Vec<f32>>) -> Self {
+    fn new(audio_rx: Receiver<Vec<f32>>, playback: Arc<Playback>, output_rate: u32) -> Self {
+        let input_rate = output_rate * OVERSAMPLING;
+
         Self {
             audio_rx,
-            buffer: VecDeque::new(),
+            playback,
+            received: 0,
+            resampler: Resampler::new(input_rate, output_rate),
+            target_fill: target_fill(input_rate),
+            average_fill: target_fill(input_rate) as f64,
+            buffering: true,
             last_sample: 0.0,
         }
     }
 
     fn update(&mut self) {
-        if let Ok(values) = self.audio_rx.try_recv() {
-            self.buffer.extend(values);
+        for values in self.audio_rx.try_iter() {
+            self.received += values.len() as u64;
+            self.resampler.push(values);
+        }
+
+        let fill = self.resampler.buffered();
+        if fill > self.target_fill * MAX_LATENCY_FACTOR {
+            // Far too much is queued, such as after the output stalled, so skip ahead
+            self.resampler.discard(fill - self.target_fill);
+            self.average_fill = self.target_fill as f64;
+        } else {
+            self.average_fill += (fill as f64 - self.average_fill) * FILL_SMOOTHING;
         }
+
+        if self.buffering && fill >= self.target_fill {
+            self.buffering = false;
+        }
+
+        // Play slightly faster when the queue is long and slower when it is short,
+        // so that the emulator's clock and the output's clock can drift apart
+        let error = (self.average_fill - self.target_fill as f64) / self.target_fill as f64;
+        self.resampler
+            .set_adjustment((error * MAX_ADJUSTMENT).clamp(-MAX_ADJUSTMENT, MAX_ADJUSTMENT));
     }
 
     fn pop_value(&mut self) -> f32 {
-        self.last_sample = self.buffer.pop_front().unwrap_or(self.last_sample);
+        if !self.buffering {
+            match self.resampler.next_sample() {
+                Some(value) => self.last_sample = value,
+                None => self.buffering = true,
+            }
+        }
         self.last_sample
     }
+
+    /// Lets the emulator know how much has been played
+    fn publish_playback(&self) {
+        let consumed = self.received - self.resampler.buffered() as u64;
+        *self
+            .playback
+            .consumed
+            .lock()
+            .expect("Failed to lock playback") = consumed;
+        self.playback.played.notify_all();
+    }
+}
+
+/// Number of samples, at the emulator's side of the resampler, to keep queued
+fn target_fill(input_rate: u32) -> usize {
+    (input_rate as f64 * TARGET_LATENCY) as usize
+}
+
+/// Paces emulation by the audio output, so that the emulator runs exactly as fast as
+/// its audio is played
+pub struct AudioPacer {
+    playback: Arc<Playback>,
+    input_rate: u32,
+    target: u64,
+}
+
+impl AudioPacer {
+    /// Waits for the output to play some of the queued audio, for up to `timeout`,
+    /// and returns how much emulated time will fill the queue back up
+    pub fn wait_for_demand(&self, timeout: Duration) -> Duration {
+        let outstanding = |consumed: &u64| {
+            self.playback
+                .produced
+                .load(Ordering::Acquire)
+                .saturating_sub(*consumed)
+        };
+
+        let consumed = self
+            .playback
+            .consumed
+            .lock()
+            .expect("Failed to lock playback");
+        let (consumed, _) = self
+            .playback
+            .played
+            .wait_timeout_while(consumed, timeout, |consumed| {
+                outstanding(consumed) >= self.target
+            })
+            .expect("Failed to lock playback");
+
+        let samples = self.target.saturating_sub(outstanding(&consumed));
+        Duration::from_secs_f64(samples as f64 / self.input_rate as f64)
+    }
 }
 
 pub struct AudioSender {
     tx: Sender<Vec<f32>>,
-    emulated_clock_rate: u64,
-    host_sample_rate: u32,
-    clock_of_last_sample: f64,
-    clocks_between_samples: f64,
+    playback: Arc<Playback>,
+    clock: SampleClock,
+    sample_rate: u32,
     frame_size: usize,
     buffer: Vec<f32>,
 }
 
+impl AudioSender {
+    /// Sample rate of the output device
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    pub fn pacer(&self) -> AudioPacer {
+        let input_rate = self.sample_rate * OVERSAMPLING;
+        AudioPacer {
+            playback: self.playback.clone(),
+            input_rate,
+            // Also leave room for the samples held here until a frame is full
+            target: (target_fill(input_rate) + self.frame_size) as u64,
+        }
+    }
+}
+
 impl AudioInterface for AudioSender {
     fn set_clock_rate(&mut self, emulated_clock_rate: u64) {
-        self.emulated_clock_rate = emulated_clock_rate;
-        self.clocks_between_samples =
-            self.emulated_clock_rate as f64 / self.host_sample_rate as f64;
+        self.clock.set_clock_rate(emulated_clock_rate);
     }
 
     fn needs_sample(&self, current_cycle: u64) -> bool {
-        let next_sample_cycle = self.clock_of_last_sample + self.clocks_between_samples;
-        next_sample_cycle <= current_cycle as f64
+        self.clock.needs_sample(current_cycle)
     }
 
-    fn add_sample(&mut self, value: f32) {
+    fn next_sample_cycle(&self) -> u64 {
+        self.clock.next_sample_cycle()
+    }
+
+    fn add_sample(&mut self, value: f32, _current_cycle: u64) {
         self.buffer.push(value);
-        self.clock_of_last_sample += self.clocks_between_samples;
+        self.clock.advance();
         if self.buffer.len() >= self.frame_size {
             let values = std::mem::take(&mut self.buffer);
+            let count = values.len() as u64;
             self.tx.send(values).expect("Failed to send audio data");
+            self.playback.produced.fetch_add(count, Ordering::Release);
         }
     }
+
+    fn reset_clock(&mut self, current_cycle: u64) {
+        self.clock.reset(current_cycle);
+    }
 }
 
-pub fn stream_setup_for() -> Result<(cpal::Stream, AudioSender), Box<dyn Error>> {
-    let (_host, device, config) = host_device_setup()?;
+/// Opens the output device with the given name, or the default one
+pub fn stream_setup_for(
+    device_name: Option<&str>,
+) -> Result<(cpal::Stream, AudioSender), Box<dyn Error>> {
+    let (_host, device, config, sample_format) = host_device_setup(device_name)?;
     let (tx, rx) = channel();
+    let playback = Arc::new(Playback::new());
+
+    let sample_rate = config.sample_rate.0;
+    let frame_size = match config.buffer_size {
+        cpal::BufferSize::Fixed(size) => size as usize,
+        cpal::BufferSize::Default => 64,
+    };
 
     let audio_sender = AudioSender {
         tx,
-        emulated_clock_rate: 1,
-        host_sample_rate: config.sample_rate.0,
-        clock_of_last_sample: 0.0,
-        clocks_between_samples: 0.0,
-        frame_size: match config.buffer_size {
-            cpal::BufferSize::Fixed(size) => size as usize,
-            cpal::BufferSize::Default => 64,
-        },
+        playback: playback.clone(),
+        clock: SampleClock::new(sample_rate * OVERSAMPLING),
+        sample_rate,
+        frame_size: frame_size * OVERSAMPLING as usize,
         buffer: Vec::new(),
     };
 
-    let stream = make_stream::<f32>(&device, &config.into(), rx)?;
+    let stream = match sample_format {
+        cpal::SampleFormat::I8 => make_stream::<i8>(&device, &config, rx, playback)?,
+        cpal::SampleFormat::I16 => make_stream::<i16>(&device, &config, rx, playback)?,
+        cpal::SampleFormat::I32 => make_stream::<i32>(&device, &config, rx, playback)?,
+        cpal::SampleFormat::I64 => make_stream::<i64>(&device, &config, rx, playback)?,
+        cpal::SampleFormat::U8 => make_stream::<u8>(&device, &config, rx, playback)?,
+        cpal::SampleFormat::U16 => make_stream::<u16>(&device, &config, rx, playback)?,
+        cpal::SampleFormat::U32 => make_stream::<u32>(&device, &config, rx, playback)?,
+        cpal::SampleFormat::U64 => make_stream::<u64>(&device, &config, rx, playback)?,
+        cpal::SampleFormat::F32 => make_stream::<f32>(&device, &config, rx, playback)?,
+        cpal::SampleFormat::F64 => make_stream::<f64>(&device, &config, rx, playback)?,
+        format => return Err(format!("Unsupported sample format {format}").into()),
+    };
     Ok((stream, audio_sender))
 }
 
-fn host_device_setup() -> Result<(cpal::Host, cpal::Device, cpal::StreamConfig), Box<dyn Error>> {
+/// Prints each output device along with the configurations it supports
+pub fn print_output_devices() -> Result<(), Box<dyn Error>> {
     let host = cpal::default_host();
-    let device = host
+    let default_name = host
         .default_output_device()
-        .ok_or("Default output device is not available")?;
+        .and_then(|device| device.name().ok());
+
+    for device in host.output_devices()? {
+        let name = device.name()?;
+        if Some(&name) == default_name.as_ref() {
+            println!("{name} (default)");
+        } else {
+            println!("{name}");
+        }
+
+        for config in device.supported_output_configs()? {
+            println!(
+                "    {} channels, {}-{} Hz, {}",
+                config.channels(),
+                config.min_sample_rate().0,
+                config.max_sample_rate().0,
+                config.sample_format()
+            );
+        }
+    }
+
+    Ok(())
+}
+
+fn is_supported_format(format: cpal::SampleFormat) -> bool {
+    use cpal::SampleFormat::*;
+    matches!(
+        format,
+        I8 | I16 | I32 | I64 | U8 | U16 | U32 | U64 | F32 | F64
+    )
+}
+
+fn host_device_setup(
+    device_name: Option<&str>,
+) -> Result<
+    (
+        cpal::Host,
+        cpal::Device,
+        cpal::StreamConfig,
+        cpal::SampleFormat,
+    ),
+    Box<dyn Error>,
+> {
+    let host = cpal::default_host();
+    let device = match device_name {
+        Some(name) => host
+            .output_devices()?
+            .find(|device| device.name().is_ok_and(|device_name| device_name == name))
+            .ok_or_else(|| format!("There is no audio output device named \"{name}\""))?,
+        None => host
+            .default_output_device()
+            .ok_or("Default output device is not available")?,
+    };
 
     // println!("Output device: {}", device.name()?);
 
@@ -106,9 +328,17 @@ fn host_device_setup() -> Result<(cpal::Host, cpal::Device, cpal::StreamConfig),
     //     println!("  {:?}", config);
     // }
 
-    let supported_config = device
-        .supported_output_configs()?
+    // Prefer F32, which is what the emulator produces, but take any format that can be converted to
+    let supported_configs: Vec<_> = device.supported_output_configs()?.collect();
+    let supported_config = supported_configs
+        .iter()
         .find(|config| config.sample_format() == cpal::SampleFormat::F32)
+        .or_else(|| {
+            supported_configs
+                .iter()
+                .find(|config| is_supported_format(config.sample_format()))
+        })
+        .cloned()
         .ok_or("No supported audio configuration found")?;
 
     // Choose sample rate closest to 44100
@@ -148,19 +378,24 @@ fn host_device_setup() -> Result<(cpal::Host, cpal::Device, cpal::StreamConfig),
     };
 
     // println!("Selected output config: {:?}", output_config);
-    Ok((host, device, output_config))
+    Ok((host, device, output_config, config.sample_format()))
 }
 
 fn make_stream<T>(
     device: &cpal::Device,
     config: &cpal::StreamConfig,
     audio_rx: Receiver<Vec<f32>>,
+    playback: Arc<Playback>,
 ) -> Result<cpal::Stream, Box<dyn Error>>
 where
     T: SizedSample + FromSample<f32>,
 {
     let num_channels = config.channels as usize;
-    let player = Arc::new(Mutex::new(AudioReceiver::new(audio_rx)));
+    let player = Arc::new(Mutex::new(AudioReceiver::new(
+        audio_rx,
+        playback,
+        config.sample_rate.0,
+    )));
 
     let err_fn = |err| eprintln!("Error building output sound stream: {}", err);
 
@@ -188,4 +423,6 @@ fn process_frame<SampleType>(
         let value = SampleType::from_sample(player.pop_value());
         frame.fill(value);
     }
+
+    player.publish_playback();
 }
@@ -0,0 +1,50 @@
+//! Host interfaces for running without a display, sound card or input devices
+
+use std::sync::mpsc::Receiver;
+
+use crate::audio::AudioInterface;
+use crate::gpio::{GpioButtonState, GpioInterface};
+use crate::screen::{Pixel, Screen};
+
+/// A screen which discards everything drawn to it
+pub struct NullScreen;
+
+impl Screen for NullScreen {
+    fn set_pixels(&self, _pixels: &[Pixel], _current_cycle: u64) {}
+}
+
+/// Audio output which never asks for samples
+pub struct NullAudio;
+
+impl AudioInterface for NullAudio {
+    fn set_clock_rate(&mut self, _emulated_clock_rate: u64) {}
+
+    fn needs_sample(&self, _current_cycle: u64) -> bool {
+        false
+    }
+
+    fn next_sample_cycle(&self) -> u64 {
+        u64::MAX
+    }
+
+    fn add_sample(&mut self, _value: f32, _current_cycle: u64) {}
+
+    fn reset_clock(&mut self, _current_cycle: u64) {}
+}
+
+/// Input sent by the thread running the emulator, so it arrives on a known cycle
+pub struct ChannelGpio {
+    receiver: Receiver<GpioButtonState>,
+}
+
+impl ChannelGpio {
+    pub fn new(receiver: Receiver<GpioButtonState>) -> Self {
+        Self { receiver }
+    }
+}
+
+impl GpioInterface for ChannelGpio {
+    fn get_updates(&self, _current_cycle: u64) -> Option<GpioButtonState> {
+        self.receiver.try_recv().ok()
+    }
+}
@@ -1,7 +1,8 @@
+use std::collections::VecDeque;
 use std::sync::mpsc::Sender;
 use std::sync::mpsc::{channel, Receiver};
 
-use minifb::{Key, MouseButton, MouseMode, Scale, ScaleMode, Window, WindowOptions};
+use minifb::{Key, KeyRepeat, MouseButton, MouseMode, Scale, ScaleMode, Window, WindowOptions};
 
 use crate::gpio::{GpioButton, GpioButtonState, GpioInterface};
 use crate::screen::{Pixel, Screen};
@@ -10,12 +11,16 @@ pub struct MiniFbScreen {
     tx: Sender<MiniFBMessage>,
     rx: Receiver<MiniFBMessage>,
     closed: bool,
+    hotkeys: VecDeque<Hotkey>,
 }
 
 impl MiniFbScreen {
+    /// Opens a window showing a screen of `width` by `height` pixels
     pub fn open(
         title: &str,
         scale: usize,
+        width: usize,
+        height: usize,
     ) -> (Self, Receiver<GpioButtonState>, Sender<Vec<Pixel>>) {
         let (host_tx, worker_rx) = channel::<MiniFBMessage>();
         let (worker_tx, host_rx) = channel::<MiniFBMessage>();
@@ -24,7 +29,15 @@ impl MiniFbScreen {
 
         let owned_title = title.to_owned();
         std::thread::spawn(move || {
-            run_minifb_worker(owned_title, scale, gpio_tx, screen_rx, worker_tx, worker_rx)
+            run_minifb_worker(
+                owned_title,
+                scale,
+                (width, height),
+                gpio_tx,
+                screen_rx,
+                worker_tx,
+                worker_rx,
+            )
         });
 
         (
@@ -32,6 +45,7 @@ impl MiniFbScreen {
                 tx: host_tx,
                 rx: host_rx,
                 closed: false,
+                hotkeys: VecDeque::new(),
             },
             gpio_rx,
             screen_tx,
@@ -43,16 +57,23 @@ impl MiniFbScreen {
     }
 
     pub fn update_state(&mut self) {
-        match self.rx.try_recv() {
-            Ok(message) => match message {
+        while let Ok(message) = self.rx.try_recv() {
+            match message {
                 MiniFBMessage::Close => {
                     self.closed = true;
                 }
-            },
-            Err(_) => return,
+                MiniFBMessage::Hotkey(hotkey) => {
+                    self.hotkeys.push_back(hotkey);
+                }
+            }
         }
     }
 
+    /// Returns the next hotkey pressed since the last call, if any
+    pub fn next_hotkey(&mut self) -> Option<Hotkey> {
+        self.hotkeys.pop_front()
+    }
+
     pub fn is_open(&self) -> bool {
         !self.closed
     }
@@ -66,8 +87,27 @@ impl Drop for MiniFbScreen {
 
 enum MiniFBMessage {
     Close,
+    Hotkey(Hotkey),
+}
+
+/// Emulator actions bound to keys in the window
+#[derive(Debug, Clone, Copy)]
+pub enum Hotkey {
+    SaveState,
+    LoadState,
+    Screenshot,
+    ToggleRecording,
+    Warp,
 }
 
+const HOTKEYS: [(Key, Hotkey); 5] = [
+    (Key::F5, Hotkey::SaveState),
+    (Key::F7, Hotkey::Warp),
+    (Key::F9, Hotkey::LoadState),
+    (Key::F10, Hotkey::ToggleRecording),
+    (Key::F12, Hotkey::Screenshot),
+];
+
 struct MiniFbWindowButton {
     pub position: (usize, usize),
     pub button: GpioButton,
@@ -77,14 +117,12 @@ struct MiniFbWindowButton {
 fn run_minifb_worker(
     title: String,
     scale: usize,
+    (width, height): (usize, usize),
     gpio_tx: Sender<GpioButtonState>,
     screen_rx: Receiver<Vec<Pixel>>,
     worker_tx: Sender<MiniFBMessage>,
     worker_rx: Receiver<MiniFBMessage>,
 ) {
-    let width = 98;
-    let height = 67;
-
     let extra_player_width = width * scale;
     let extra_player_height = height / 2 * scale;
     let player_width = width * scale + extra_player_width;
@@ -279,6 +317,14 @@ fn run_minifb_worker(
         };
         let pressed_keys = window.get_keys();
 
+        for key in window.get_keys_pressed(KeyRepeat::No) {
+            if let Some((_, hotkey)) = HOTKEYS.iter().find(|(hotkey_key, _)| *hotkey_key == key) {
+                if let Err(err) = worker_tx.send(MiniFBMessage::Hotkey(*hotkey)) {
+                    eprintln!("Failed to send hotkey: {err:?}");
+                }
+            }
+        }
+
         // Put the buttons on the player
         let clicked_pixel = Pixel {
             red: 255,
@@ -382,7 +428,7 @@ impl MiniFbGpioInterface {
 }
 
 impl GpioInterface for MiniFbGpioInterface {
-    fn get_updates(&self) -> Option<GpioButtonState> {
+    fn get_updates(&self, _current_cycle: u64) -> Option<GpioButtonState> {
         self.receiver.try_recv().ok()
     }
 }
@@ -398,7 +444,7 @@ impl MiniFbScreenInterface {
 }
 
 impl Screen for MiniFbScreenInterface {
-    fn set_pixels(&self, pixels: &[Pixel]) {
+    fn set_pixels(&self, pixels: &[Pixel], _current_cycle: u64) {
         if let Err(err) = self.tx.send(pixels.to_vec()) {
             eprintln!("Failed to send pixels: {err:?}");
         }
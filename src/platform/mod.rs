@@ -1,2 +1,6 @@
 pub mod cpal_audio;
+pub mod headless;
 pub mod minifb_screen_gpio;
+pub mod rtc_source;
+pub mod serial;
+pub mod usb_socket;
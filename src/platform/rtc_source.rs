@@ -0,0 +1,146 @@
+//! Where the real-time clock's time comes from when the emulator starts
+
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+
+/// Days from 1970-01-01 to 2000-01-01, where the RTC's day count starts
+const DAYS_BEFORE_2000: i64 = 10957;
+
+/// How the real-time clock is set at startup. It then counts in emulated time.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RtcSource {
+    /// The host's local time
+    Host,
+    /// A set time, in seconds since 2000-01-01, so that runs can be repeated
+    Fixed(u64),
+    /// The host's local time, moved by an offset kept next to the flash image.
+    /// This keeps a time which was set on the device.
+    Flash,
+}
+
+impl FromStr for RtcSource {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        match text.split_once(':') {
+            None if text == "host" => Ok(RtcSource::Host),
+            None if text == "flash" => Ok(RtcSource::Flash),
+            Some(("fixed", time)) => parse_time(time).map(RtcSource::Fixed),
+            _ => Err(format!(
+                "\"{text}\" is not a clock source; use host, fixed:<YYYY-MM-DDTHH:MM[:SS]> or flash"
+            )),
+        }
+    }
+}
+
+/// Parses a time such as `2024-05-01T12:30` or `2024-05-01 12:30:15` into
+/// seconds since 2000-01-01
+fn parse_time(text: &str) -> Result<u64, String> {
+    let invalid = || format!("\"{text}\" is not a time like 2024-05-01T12:30[:00]");
+
+    let (date, time) = text.split_once(['T', ' ']).ok_or_else(invalid)?;
+
+    let date: Vec<&str> = date.split('-').collect();
+    let time: Vec<&str> = time.split(':').collect();
+    let [year, month, day] = date[..] else {
+        return Err(invalid());
+    };
+    let (hour, minute, second) = match time[..] {
+        [hour, minute] => (hour, minute, "0"),
+        [hour, minute, second] => (hour, minute, second),
+        _ => return Err(invalid()),
+    };
+
+    let number = |field: &str, range: std::ops::RangeInclusive<i64>| {
+        field
+            .parse::<i64>()
+            .ok()
+            .filter(|value| range.contains(value))
+            .ok_or_else(invalid)
+    };
+    let days = days_from_civil(
+        number(year, 2000..=2178)?,
+        number(month, 1..=12)?,
+        number(day, 1..=31)?,
+    ) - DAYS_BEFORE_2000;
+    let seconds =
+        number(hour, 0..=23)? * 3600 + number(minute, 0..=59)? * 60 + number(second, 0..=59)?;
+
+    Ok((days * SECONDS_PER_DAY + seconds) as u64)
+}
+
+/// Days since 1970-01-01 of a date in the proleptic Gregorian calendar, from
+/// Howard Hinnant's `days_from_civil`
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+/// The host's local time, in seconds since 2000-01-01
+pub fn host_time() -> u64 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs() as i64);
+    let local = now + utc_offset(now);
+    (local - DAYS_BEFORE_2000 * SECONDS_PER_DAY).max(0) as u64
+}
+
+/// Seconds the local time zone is ahead of UTC at `time`, in seconds since 1970-01-01
+#[cfg(unix)]
+fn utc_offset(time: i64) -> i64 {
+    let time = time as libc::time_t;
+    // SAFETY: `tm` is plain data, and localtime_r only writes to it
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    if unsafe { libc::localtime_r(&time, &mut tm) }.is_null() {
+        return 0;
+    }
+    tm.tm_gmtoff as i64
+}
+
+/// Local time is taken to be UTC where the time zone cannot be looked up
+#[cfg(not(unix))]
+fn utc_offset(_time: i64) -> i64 {
+    0
+}
+
+/// The file which keeps the clock offset for the flash image at `flash_file`
+pub fn offset_file(flash_file: &Path) -> PathBuf {
+    let mut path = flash_file.as_os_str().to_owned();
+    path.push(".rtc");
+    PathBuf::from(path)
+}
+
+/// The time to start the clock at. A missing offset file counts as no offset.
+pub fn start_time(source: RtcSource, flash_file: &Path) -> Result<u64, String> {
+    match source {
+        RtcSource::Host => Ok(host_time()),
+        RtcSource::Fixed(time) => Ok(time),
+        RtcSource::Flash => {
+            let path = offset_file(flash_file);
+            let offset = match std::fs::read_to_string(&path) {
+                Ok(text) => text
+                    .trim()
+                    .parse::<i64>()
+                    .map_err(|_| format!("{path:?} does not hold a number of seconds"))?,
+                Err(why) if why.kind() == std::io::ErrorKind::NotFound => 0,
+                Err(why) => return Err(format!("Could not read {path:?}: {why}")),
+            };
+            Ok(host_time().saturating_add_signed(offset))
+        }
+    }
+}
+
+/// Keeps how far `time` is from the host's time next to the flash image at `flash_file`
+pub fn save_offset(time: u64, flash_file: &Path) -> std::io::Result<PathBuf> {
+    let path = offset_file(flash_file);
+    let offset = time as i64 - host_time() as i64;
+    std::fs::write(&path, format!("{offset}\n"))?;
+    Ok(path)
+}
@@ -0,0 +1,303 @@
+//! Host connections for the UART's serial line
+
+use std::collections::VecDeque;
+use std::fmt::Display;
+use std::fs::File;
+use std::io::{ErrorKind, LineWriter, Read, Write};
+use std::net::{Ipv4Addr, TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::mpsc::{channel, Receiver};
+
+use crate::serial::SerialInterface;
+
+/// Most bytes to take from the host at once
+const READ_CHUNK: usize = 256;
+
+/// Where the serial line is connected to
+#[derive(Clone, Debug)]
+pub enum SerialBackend {
+    /// A new pseudoterminal, which a terminal program can open
+    Pty,
+    /// A TCP socket on localhost, which one client at a time can connect to
+    Tcp(u16),
+    /// A file which transmitted bytes are written to
+    File(PathBuf),
+    /// The emulator's own standard input and output
+    Stdio,
+}
+
+impl FromStr for SerialBackend {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        match text.split_once(':') {
+            None if text == "pty" => Ok(SerialBackend::Pty),
+            None if text == "stdio" => Ok(SerialBackend::Stdio),
+            Some(("tcp", port)) => port
+                .parse()
+                .map(SerialBackend::Tcp)
+                .map_err(|_| format!("\"{port}\" is not a port number")),
+            Some(("file", path)) => Ok(SerialBackend::File(PathBuf::from(path))),
+            _ => Err(format!(
+                "\"{text}\" is not a serial backend; use pty, tcp:<PORT>, file:<PATH> or stdio"
+            )),
+        }
+    }
+}
+
+/// Connects to `backend`, describing where to find the other end of the line
+pub fn open(backend: &SerialBackend) -> Result<Box<dyn SerialInterface>, SerialError> {
+    match backend {
+        SerialBackend::Pty => {
+            let pty = PtySerial::open().map_err(SerialError::Pty)?;
+            eprintln!("Serial port is on {}", pty.path);
+            Ok(Box::new(pty))
+        }
+        SerialBackend::Tcp(port) => {
+            let tcp = TcpSerial::listen(*port).map_err(SerialError::Tcp)?;
+            eprintln!("Serial port is listening on {}:{port}", Ipv4Addr::LOCALHOST);
+            Ok(Box::new(tcp))
+        }
+        SerialBackend::File(path) => {
+            let file = File::create(path).map_err(SerialError::File)?;
+            eprintln!("Writing serial output to {path:?}");
+            Ok(Box::new(FileSerial {
+                writer: LineWriter::new(file),
+            }))
+        }
+        SerialBackend::Stdio => Ok(Box::new(StdioSerial::new())),
+    }
+}
+
+/// Moves whatever `reader` has ready into `pending` without waiting for more.
+/// Returns false if the other end has closed.
+fn read_available(reader: &mut impl Read, pending: &mut VecDeque<u8>) -> std::io::Result<bool> {
+    let mut buffer = [0u8; READ_CHUNK];
+    match reader.read(&mut buffer) {
+        Ok(count) => {
+            pending.extend(&buffer[..count]);
+            Ok(count > 0)
+        }
+        Err(why) if why.kind() == ErrorKind::WouldBlock => Ok(true),
+        Err(why) => Err(why),
+    }
+}
+
+/// The controlling side of a pseudoterminal, whose other side is at `path`
+pub struct PtySerial {
+    master: File,
+    path: String,
+    pending: VecDeque<u8>,
+}
+
+impl PtySerial {
+    #[cfg(unix)]
+    fn open() -> std::io::Result<Self> {
+        use std::ffi::CStr;
+        use std::os::fd::FromRawFd;
+
+        let last_error = std::io::Error::last_os_error;
+
+        // SAFETY: The descriptor is owned by `master` as soon as it is opened, so
+        // it is closed on every path, and the name is copied before any other
+        // call could replace it.
+        unsafe {
+            let fd = libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY);
+            if fd < 0 {
+                return Err(last_error());
+            }
+            let master = File::from_raw_fd(fd);
+
+            if libc::grantpt(fd) != 0 || libc::unlockpt(fd) != 0 {
+                return Err(last_error());
+            }
+
+            let name = libc::ptsname(fd);
+            if name.is_null() {
+                return Err(last_error());
+            }
+            let path = CStr::from_ptr(name).to_string_lossy().into_owned();
+
+            // Pass bytes through untouched, without echoing or translating line endings
+            let mut termios = std::mem::zeroed();
+            if libc::tcgetattr(fd, &mut termios) != 0 {
+                return Err(last_error());
+            }
+            libc::cfmakeraw(&mut termios);
+            if libc::tcsetattr(fd, libc::TCSANOW, &termios) != 0 {
+                return Err(last_error());
+            }
+
+            let flags = libc::fcntl(fd, libc::F_GETFL);
+            if flags < 0 || libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) != 0 {
+                return Err(last_error());
+            }
+
+            Ok(Self {
+                master,
+                path,
+                pending: VecDeque::new(),
+            })
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn open() -> std::io::Result<Self> {
+        Err(std::io::Error::new(
+            ErrorKind::Unsupported,
+            "pseudoterminals are only available on Unix",
+        ))
+    }
+}
+
+impl SerialInterface for PtySerial {
+    fn send(&mut self, byte: u8) {
+        // Bytes are lost if nothing is reading the other side, as on a real line
+        self.master.write_all(&[byte]).ok();
+    }
+
+    fn receive(&mut self) -> Option<u8> {
+        if self.pending.is_empty() {
+            // Reading fails until the other side has been opened
+            read_available(&mut self.master, &mut self.pending).ok();
+        }
+        self.pending.pop_front()
+    }
+}
+
+/// A socket on localhost which accepts one client at a time
+pub struct TcpSerial {
+    listener: TcpListener,
+    client: Option<TcpStream>,
+    pending: VecDeque<u8>,
+}
+
+impl TcpSerial {
+    fn listen(port: u16) -> std::io::Result<Self> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port))?;
+        listener.set_nonblocking(true)?;
+
+        Ok(Self {
+            listener,
+            client: None,
+            pending: VecDeque::new(),
+        })
+    }
+
+    /// The connected client, accepting a new one if there is none
+    fn client(&mut self) -> Option<&mut TcpStream> {
+        if self.client.is_none() {
+            if let Ok((stream, address)) = self.listener.accept() {
+                if stream.set_nonblocking(true).is_ok() {
+                    stream.set_nodelay(true).ok();
+                    eprintln!("Serial client connected from {address}");
+                    self.client = Some(stream);
+                }
+            }
+        }
+        self.client.as_mut()
+    }
+
+    fn disconnect(&mut self) {
+        if self.client.take().is_some() {
+            eprintln!("Serial client disconnected");
+        }
+    }
+}
+
+impl SerialInterface for TcpSerial {
+    fn send(&mut self, byte: u8) {
+        let Some(client) = self.client() else {
+            return;
+        };
+
+        match client.write(&[byte]) {
+            Ok(_) => {}
+            // The client is not keeping up, so the byte is lost
+            Err(why) if why.kind() == ErrorKind::WouldBlock => {}
+            Err(_) => self.disconnect(),
+        }
+    }
+
+    fn receive(&mut self) -> Option<u8> {
+        if self.pending.is_empty() {
+            self.client()?;
+            let client = self.client.as_mut()?;
+            if !matches!(read_available(client, &mut self.pending), Ok(true)) {
+                self.disconnect();
+            }
+        }
+        self.pending.pop_front()
+    }
+}
+
+/// Writes transmitted bytes to a file. Nothing is ever received.
+pub struct FileSerial {
+    writer: LineWriter<File>,
+}
+
+impl SerialInterface for FileSerial {
+    fn send(&mut self, byte: u8) {
+        if let Err(why) = self.writer.write_all(&[byte]) {
+            eprintln!("Failed to write serial output: {why}");
+        }
+    }
+
+    fn receive(&mut self) -> Option<u8> {
+        None
+    }
+}
+
+/// Writes transmitted bytes to standard output and receives standard input
+pub struct StdioSerial {
+    input: Receiver<u8>,
+}
+
+impl StdioSerial {
+    fn new() -> Self {
+        // Standard input cannot be read without blocking, so a thread waits on it
+        let (sender, input) = channel();
+        std::thread::spawn(move || {
+            for byte in std::io::stdin().lock().bytes() {
+                let Ok(byte) = byte else {
+                    break;
+                };
+                if sender.send(byte).is_err() {
+                    break;
+                }
+            }
+        });
+
+        Self { input }
+    }
+}
+
+impl SerialInterface for StdioSerial {
+    fn send(&mut self, byte: u8) {
+        let mut stdout = std::io::stdout().lock();
+        stdout.write_all(&[byte]).ok();
+        stdout.flush().ok();
+    }
+
+    fn receive(&mut self) -> Option<u8> {
+        self.input.try_recv().ok()
+    }
+}
+
+#[derive(Debug)]
+pub enum SerialError {
+    Pty(std::io::Error),
+    Tcp(std::io::Error),
+    File(std::io::Error),
+}
+
+impl Display for SerialError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&match &self {
+            SerialError::Pty(why) => format!("Could not create a pseudoterminal: {why}"),
+            SerialError::Tcp(why) => format!("Could not listen for connections: {why}"),
+            SerialError::File(why) => format!("Could not create the file: {why}"),
+        })
+    }
+}
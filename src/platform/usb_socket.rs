@@ -0,0 +1,193 @@
+//! Exposes the emulated USB device on a Unix socket.
+//!
+//! Each message is a kind byte, an endpoint byte, a little endian 16 bit length
+//! and then that many bytes of data. The host sends one of:
+//!
+//! - `0x00` to reset the bus
+//! - `0x01` with the 8 byte setup packet of a control transfer
+//! - `0x02` with data for an OUT endpoint
+//! - `0x03` to read an IN endpoint, with the length set to the most bytes to take
+//!   and no data
+//!
+//! and waits for the device to answer with `0x80` for an acknowledgement, `0x81`
+//! with data from an IN endpoint, `0x82` for a stall, or `0x83` for a NAK when
+//! the firmware has not readied the endpoint yet. A NAKed transaction can be
+//! sent again, as a real host would in a later frame.
+
+use std::path::Path;
+
+use crate::usb::UsbHost;
+
+/// Bytes before each message's data
+const HEADER_LENGTH: usize = 4;
+
+const RESET: u8 = 0x00;
+const SETUP: u8 = 0x01;
+const OUT: u8 = 0x02;
+const IN: u8 = 0x03;
+
+const ACK: u8 = 0x80;
+const DATA: u8 = 0x81;
+const STALL: u8 = 0x82;
+const NAK: u8 = 0x83;
+
+/// Listens for a USB host program on the socket at `path`
+pub fn open(path: &Path) -> std::io::Result<Box<dyn UsbHost>> {
+    #[cfg(unix)]
+    {
+        Ok(Box::new(unix::SocketUsbHost::listen(path)?))
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = path;
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "Unix sockets are only available on Unix",
+        ))
+    }
+}
+
+#[cfg(unix)]
+mod unix {
+    use std::io::{ErrorKind, Read, Write};
+    use std::os::unix::fs::FileTypeExt;
+    use std::os::unix::net::{UnixListener, UnixStream};
+    use std::path::{Path, PathBuf};
+
+    use super::{ACK, DATA, HEADER_LENGTH, IN, NAK, OUT, RESET, SETUP, STALL};
+    use crate::usb::{UsbHost, UsbResponse, UsbTransaction};
+
+    pub struct SocketUsbHost {
+        path: PathBuf,
+        listener: UnixListener,
+        client: Option<UnixStream>,
+        /// Bytes received which do not yet make up a whole message
+        received: Vec<u8>,
+        /// Endpoint of the transaction being answered
+        endpoint: u8,
+    }
+
+    impl SocketUsbHost {
+        pub fn listen(path: &Path) -> std::io::Result<Self> {
+            // A socket left behind by an earlier run would stop the new one being made
+            if std::fs::metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+                std::fs::remove_file(path)?;
+            }
+
+            let listener = UnixListener::bind(path)?;
+            listener.set_nonblocking(true)?;
+            eprintln!("USB device is listening on {path:?}");
+
+            Ok(Self {
+                path: path.to_path_buf(),
+                listener,
+                client: None,
+                received: Vec::new(),
+                endpoint: 0,
+            })
+        }
+
+        /// The connected client, accepting a new one if there is none
+        fn client(&mut self) -> Option<&mut UnixStream> {
+            if self.client.is_none() {
+                if let Ok((stream, _)) = self.listener.accept() {
+                    if stream.set_nonblocking(true).is_ok() {
+                        eprintln!("USB host connected");
+                        self.received.clear();
+                        self.client = Some(stream);
+                    }
+                }
+            }
+            self.client.as_mut()
+        }
+
+        fn disconnect(&mut self) {
+            if self.client.take().is_some() {
+                eprintln!("USB host disconnected");
+            }
+        }
+
+        /// Takes the first whole message from `received`
+        fn take_message(&mut self) -> Option<(u8, u8, usize, Vec<u8>)> {
+            let header = self.received.get(..HEADER_LENGTH)?;
+            let (kind, endpoint) = (header[0], header[1]);
+            let length = u16::from_le_bytes([header[2], header[3]]) as usize;
+
+            // IN messages carry a length but no data
+            let data_length = if kind == IN { 0 } else { length };
+            if self.received.len() < HEADER_LENGTH + data_length {
+                return None;
+            }
+
+            let data = self.received[HEADER_LENGTH..HEADER_LENGTH + data_length].to_vec();
+            self.received.drain(..HEADER_LENGTH + data_length);
+            Some((kind, endpoint, length, data))
+        }
+    }
+
+    impl UsbHost for SocketUsbHost {
+        fn next_transaction(&mut self) -> Option<UsbTransaction> {
+            let client = self.client()?;
+
+            let mut buffer = [0u8; 1024];
+            match client.read(&mut buffer) {
+                Ok(0) => self.disconnect(),
+                Ok(count) => self.received.extend_from_slice(&buffer[..count]),
+                Err(why) if why.kind() == ErrorKind::WouldBlock => {}
+                Err(_) => self.disconnect(),
+            }
+
+            let (kind, endpoint, length, data) = self.take_message()?;
+            self.endpoint = endpoint;
+            let transaction = match kind {
+                RESET => UsbTransaction::Reset,
+                SETUP => match <[u8; 8]>::try_from(data) {
+                    Ok(packet) => UsbTransaction::Setup(packet),
+                    Err(_) => {
+                        eprintln!("USB host sent a setup packet which is not 8 bytes");
+                        self.disconnect();
+                        return None;
+                    }
+                },
+                OUT => UsbTransaction::Out { endpoint, data },
+                IN => UsbTransaction::In {
+                    endpoint,
+                    max_length: length as u16,
+                },
+                _ => {
+                    eprintln!("USB host sent an unknown message {kind:02X}");
+                    self.disconnect();
+                    return None;
+                }
+            };
+            Some(transaction)
+        }
+
+        fn respond(&mut self, response: UsbResponse) {
+            let (kind, data) = match response {
+                UsbResponse::Ack => (ACK, Vec::new()),
+                UsbResponse::Data(data) => (DATA, data),
+                UsbResponse::Stall => (STALL, Vec::new()),
+                UsbResponse::Nak => (NAK, Vec::new()),
+            };
+
+            let mut message = vec![kind, self.endpoint];
+            message.extend_from_slice(&(data.len() as u16).to_le_bytes());
+            message.extend_from_slice(&data);
+
+            let Some(client) = self.client.as_mut() else {
+                return;
+            };
+            if client.write_all(&message).is_err() {
+                self.disconnect();
+            }
+        }
+    }
+
+    impl Drop for SocketUsbHost {
+        fn drop(&mut self) {
+            std::fs::remove_file(&self.path).ok();
+        }
+    }
+}
@@ -0,0 +1,116 @@
+use std::collections::VecDeque;
+use std::f64::consts::PI;
+
+/// Number of input samples each output sample is filtered from
+const TAPS: usize = 64;
+
+/// Number of fractional positions between input samples the filter is precomputed for
+const PHASES: usize = 64;
+
+/// Passband edge, as a fraction of the lower of the two Nyquist frequencies
+const CUTOFF: f64 = 0.9;
+
+/// Converts a stream of samples to another sample rate through a windowed-sinc
+/// low-pass filter, so content above the output's Nyquist frequency is removed
+/// instead of aliasing.
+///
+/// The ratio between the rates can be nudged while running, to follow a clock
+/// which drifts against the one producing the samples.
+pub struct Resampler {
+    input: VecDeque<f32>,
+    /// One row of taps per phase, plus one for a phase of a whole sample
+    kernel: Vec<[f32; TAPS]>,
+    /// How far the next output sample is past the centre of the filter, in input samples
+    phase: f64,
+    nominal_ratio: f64,
+    ratio: f64,
+}
+
+impl Resampler {
+    pub fn new(input_rate: u32, output_rate: u32) -> Self {
+        let nominal_ratio = input_rate as f64 / output_rate as f64;
+
+        // Cycles per input sample
+        let cutoff = CUTOFF * 0.5 * (1.0 / nominal_ratio).min(1.0);
+
+        let kernel = (0..=PHASES)
+            .map(|phase| {
+                let offset = (TAPS / 2 - 1) as f64 + phase as f64 / PHASES as f64;
+
+                let mut row = [0.0; TAPS];
+                for (tap, value) in row.iter_mut().enumerate() {
+                    let x = tap as f64 - offset;
+                    *value = (sinc(2.0 * cutoff * x) * blackman(x, TAPS as f64)) as f32;
+                }
+
+                // Keep a constant input at the same level
+                let sum: f32 = row.iter().sum();
+                row.iter_mut().for_each(|value| *value /= sum);
+                row
+            })
+            .collect();
+
+        Self {
+            input: VecDeque::with_capacity(TAPS * 2),
+            kernel,
+            phase: 0.0,
+            nominal_ratio,
+            ratio: nominal_ratio,
+        }
+    }
+
+    pub fn push(&mut self, values: impl IntoIterator<Item = f32>) {
+        self.input.extend(values);
+    }
+
+    /// Number of input samples waiting to be resampled
+    pub fn buffered(&self) -> usize {
+        self.input.len()
+    }
+
+    /// Throws away the oldest `count` input samples
+    pub fn discard(&mut self, count: usize) {
+        self.input.drain(..count.min(self.input.len()));
+    }
+
+    /// Consumes input this much faster than the nominal ratio, or slower if negative
+    pub fn set_adjustment(&mut self, adjustment: f64) {
+        self.ratio = self.nominal_ratio * (1.0 + adjustment);
+    }
+
+    /// Produces the next output sample, if there is enough input for it
+    pub fn next_sample(&mut self) -> Option<f32> {
+        if self.input.len() < TAPS {
+            return None;
+        }
+
+        let row = &self.kernel[(self.phase * PHASES as f64).round() as usize];
+        let output = self
+            .input
+            .iter()
+            .zip(row)
+            .map(|(input, tap)| input * tap)
+            .sum();
+
+        self.phase += self.ratio;
+        let consumed = self.phase.floor();
+        self.phase -= consumed;
+        self.discard(consumed as usize);
+
+        Some(output)
+    }
+}
+
+fn sinc(x: f64) -> f64 {
+    if x == 0.0 {
+        1.0
+    } else {
+        (PI * x).sin() / (PI * x)
+    }
+}
+
+/// Blackman window of width `width`, centred on 0
+fn blackman(x: f64, width: f64) -> f64 {
+    let n = (x / width + 0.5).clamp(0.0, 1.0);
+    0.42 - 0.5 * (2.0 * PI * n).cos() + 0.08 * (4.0 * PI * n).cos()
+}
@@ -0,0 +1,167 @@
+use std::fmt::Display;
+
+const MAGIC: &[u8; 8] = b"EMIU2SAV";
+
+/// Bumped whenever the layout of a saved state changes
+const VERSION: u32 = 11;
+
+/// Something whose state can be written to and restored from a save state.
+///
+/// Only emulated state is saved. Host interfaces such as screens and audio
+/// outputs, as well as configuration fixed at construction, are left alone.
+pub trait SaveState {
+    fn save_state(&self, state: &mut StateWriter);
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError>;
+}
+
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> Self {
+        let mut writer = Self { data: Vec::new() };
+        writer.write_bytes(MAGIC);
+        writer.write_u32(VERSION);
+        writer
+    }
+
+    pub fn write_u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn write_bool(&mut self, value: bool) {
+        self.write_u8(value as u8);
+    }
+
+    pub fn write_u16(&mut self, value: u16) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_i16(&mut self, value: i16) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_u64(&mut self, value: u64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    /// Writes bytes with no length prefix, to be read back into a buffer of the same size
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.data.extend_from_slice(bytes);
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> Result<Self, StateError> {
+        let mut reader = Self { data, position: 0 };
+
+        let mut magic = [0u8; MAGIC.len()];
+        reader
+            .read_bytes(&mut magic)
+            .map_err(|_| StateError::InvalidMagic)?;
+        if &magic != MAGIC {
+            return Err(StateError::InvalidMagic);
+        }
+
+        let version = reader.read_u32()?;
+        if version != VERSION {
+            return Err(StateError::UnsupportedVersion(version));
+        }
+
+        Ok(reader)
+    }
+
+    fn take<const N: usize>(&mut self) -> Result<[u8; N], StateError> {
+        let mut bytes = [0u8; N];
+        self.read_bytes(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.take::<1>()?[0])
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool, StateError> {
+        match self.read_u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(StateError::InvalidValue("bool")),
+        }
+    }
+
+    pub fn read_u16(&mut self) -> Result<u16, StateError> {
+        Ok(u16::from_le_bytes(self.take()?))
+    }
+
+    pub fn read_i16(&mut self) -> Result<i16, StateError> {
+        Ok(i16::from_le_bytes(self.take()?))
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, StateError> {
+        Ok(u32::from_le_bytes(self.take()?))
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, StateError> {
+        Ok(u64::from_le_bytes(self.take()?))
+    }
+
+    /// Fills `buffer` with bytes written by `StateWriter::write_bytes`
+    pub fn read_bytes(&mut self, buffer: &mut [u8]) -> Result<(), StateError> {
+        let end = self.position + buffer.len();
+        let bytes = self
+            .data
+            .get(self.position..end)
+            .ok_or(StateError::UnexpectedEnd)?;
+        buffer.copy_from_slice(bytes);
+        self.position = end;
+        Ok(())
+    }
+
+    /// Checks that the whole state has been consumed
+    pub fn finish(&self) -> Result<(), StateError> {
+        let remaining = self.data.len() - self.position;
+        if remaining != 0 {
+            return Err(StateError::TrailingData(remaining));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub enum StateError {
+    InvalidMagic,
+    UnsupportedVersion(u32),
+    UnexpectedEnd,
+    InvalidValue(&'static str),
+    TrailingData(usize),
+}
+
+impl Display for StateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&match &self {
+            StateError::InvalidMagic => "The file is not an emiu2 save state".to_owned(),
+            StateError::UnsupportedVersion(version) => format!(
+                "The save state is version {version}, but only version {VERSION} is supported"
+            ),
+            StateError::UnexpectedEnd => "The save state is truncated".to_owned(),
+            StateError::InvalidValue(what) => format!("The save state has an invalid {what}"),
+            StateError::TrailingData(size) => {
+                format!("The save state has {size} unexpected bytes at the end")
+            }
+        })
+    }
+}
@@ -1,8 +1,9 @@
 pub trait Screen {
-    fn set_pixels(&self, pixels: &[Pixel]);
+    /// Displays a new frame, drawn when the CPU had run `current_cycle` cycles
+    fn set_pixels(&self, pixels: &[Pixel], current_cycle: u64);
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, PartialEq)]
 pub struct Pixel {
     pub red: u8,
     pub green: u8,
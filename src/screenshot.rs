@@ -0,0 +1,170 @@
+use std::fmt::Display;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use crate::screen::{Pixel, Screen};
+use crate::video::{Recording, VideoError};
+
+/// Passes frames on to another screen while keeping a copy of the latest one
+pub struct CapturingScreen {
+    screen: Box<dyn Screen>,
+    capture: FrameCapture,
+}
+
+impl CapturingScreen {
+    pub fn new(screen: Box<dyn Screen>, width: usize, height: usize) -> (Self, FrameCapture) {
+        let capture = FrameCapture {
+            state: Arc::new(Mutex::new(CaptureState {
+                frame: vec![
+                    Pixel {
+                        red: 0,
+                        green: 0,
+                        blue: 0,
+                    };
+                    width * height
+                ],
+                recording: None,
+            })),
+            width,
+            height,
+        };
+
+        (
+            Self {
+                screen,
+                capture: capture.clone(),
+            },
+            capture,
+        )
+    }
+}
+
+impl Screen for CapturingScreen {
+    fn set_pixels(&self, pixels: &[Pixel], current_cycle: u64) {
+        {
+            let mut state = self.capture.lock();
+            state.frame.copy_from_slice(pixels);
+            if let Some(recording) = &mut state.recording {
+                recording.add_frame(pixels, current_cycle);
+            }
+        }
+        self.screen.set_pixels(pixels, current_cycle);
+    }
+}
+
+struct CaptureState {
+    frame: Vec<Pixel>,
+    recording: Option<Recording>,
+}
+
+/// The frames drawn to a `CapturingScreen`
+#[derive(Clone)]
+pub struct FrameCapture {
+    state: Arc<Mutex<CaptureState>>,
+    width: usize,
+    height: usize,
+}
+
+impl FrameCapture {
+    fn lock(&self) -> MutexGuard<'_, CaptureState> {
+        self.state.lock().expect("Failed to lock captured frames")
+    }
+
+    pub fn frame(&self) -> Vec<Pixel> {
+        self.lock().frame.clone()
+    }
+
+    /// Writes the latest frame to a PNG file, with each pixel drawn as a `scale` by `scale` square
+    pub fn save_png(&self, path: &Path, scale: usize) -> Result<(), ScreenshotError> {
+        let data = scale_rgb(&self.frame(), self.width, scale);
+        let width = self.width * scale;
+        let height = self.height * scale;
+
+        let file = File::create(path).map_err(ScreenshotError::Io)?;
+        let mut encoder = png::Encoder::new(BufWriter::new(file), width as u32, height as u32);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+
+        let mut writer = encoder.write_header().map_err(ScreenshotError::Png)?;
+        writer
+            .write_image_data(&data)
+            .map_err(ScreenshotError::Png)?;
+        writer.finish().map_err(ScreenshotError::Png)
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.lock().recording.is_some()
+    }
+
+    /// Starts recording every frame drawn, beginning with the one currently shown
+    pub fn start_recording(
+        &self,
+        path: PathBuf,
+        scale: usize,
+        current_cycle: u64,
+        cycles_per_second: u64,
+    ) -> Result<(), VideoError> {
+        let mut recording = Recording::new(
+            path,
+            self.width,
+            self.height,
+            scale,
+            current_cycle,
+            cycles_per_second,
+        )?;
+
+        let mut state = self.lock();
+        recording.add_frame(&state.frame, current_cycle);
+        state.recording = Some(recording);
+        Ok(())
+    }
+
+    /// Stops recording and returns the recording, to be finished
+    pub fn stop_recording(&self) -> Option<Recording> {
+        self.lock().recording.take()
+    }
+}
+
+/// Converts a frame to 8-bit RGB, with each pixel drawn as a `scale` by `scale` square
+pub fn scale_rgb(frame: &[Pixel], width: usize, scale: usize) -> Vec<u8> {
+    let mut data = Vec::with_capacity(frame.len() * scale * scale * 3);
+    for row in frame.chunks(width) {
+        let mut line = Vec::with_capacity(width * scale * 3);
+        for pixel in row {
+            for _ in 0..scale {
+                line.extend_from_slice(&[pixel.red, pixel.green, pixel.blue]);
+            }
+        }
+        for _ in 0..scale {
+            data.extend_from_slice(&line);
+        }
+    }
+    data
+}
+
+/// Path for the scaled copy of a screenshot, such as `shot@3x.png` for `shot.png`
+pub fn scaled_path(path: &Path, scale: usize) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(extension) => format!("{stem}@{scale}x.{}", extension.to_string_lossy()),
+        None => format!("{stem}@{scale}x"),
+    };
+    path.with_file_name(name)
+}
+
+#[derive(Debug)]
+pub enum ScreenshotError {
+    Io(std::io::Error),
+    Png(png::EncodingError),
+}
+
+impl Display for ScreenshotError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&match &self {
+            ScreenshotError::Io(why) => format!("Could not create the file: {why}"),
+            ScreenshotError::Png(why) => format!("Could not encode the PNG: {why}"),
+        })
+    }
+}
@@ -0,0 +1,8 @@
+/// The host's end of the UART's serial line
+pub trait SerialInterface {
+    /// Called with each byte the device finishes transmitting
+    fn send(&mut self, byte: u8);
+
+    /// Returns the next byte for the device to receive, if one has arrived
+    fn receive(&mut self) -> Option<u8>;
+}
@@ -0,0 +1,32 @@
+/// Something the USB host asks of the device
+#[derive(Clone, Debug, PartialEq)]
+pub enum UsbTransaction {
+    /// Resets the device to its default, unaddressed state
+    Reset,
+    /// Starts a control transfer on endpoint 0
+    Setup([u8; 8]),
+    /// Sends data to an OUT endpoint
+    Out { endpoint: u8, data: Vec<u8> },
+    /// Asks an IN endpoint for up to `max_length` bytes
+    In { endpoint: u8, max_length: u16 },
+}
+
+/// How the device answered a transaction
+#[derive(Clone, Debug, PartialEq)]
+pub enum UsbResponse {
+    /// The reset, setup or OUT data was accepted
+    Ack,
+    /// Data from an IN endpoint
+    Data(Vec<u8>),
+    /// The endpoint is not ready yet, so the host should try again later
+    Nak,
+    /// The endpoint is halted, or does not support the transaction
+    Stall,
+}
+
+/// The host's end of the USB connection. Transactions are sent one at a time,
+/// and each gets a response before the next is taken.
+pub trait UsbHost {
+    fn next_transaction(&mut self) -> Option<UsbTransaction>;
+    fn respond(&mut self, response: UsbResponse);
+}
@@ -0,0 +1,360 @@
+use std::fmt::Display;
+use std::fs::File;
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::screen::Pixel;
+use crate::screenshot::scale_rgb;
+
+/// An encoder which frames are written to as soon as their length is known
+enum Encoder {
+    Gif(gif::Encoder<BufWriter<File>>),
+    /// The number of frames in an APNG comes before them, so a placeholder is
+    /// written and `file` is used to fill it in at the end
+    Apng {
+        writer: png::Writer<BufWriter<File>>,
+        file: File,
+    },
+}
+
+/// Frames drawn to the screen, encoded to a file as they arrive.
+///
+/// Each frame is stamped with the CPU cycle it was drawn on, and is shown until
+/// the next one. Only the latest frame is kept in memory, until the next one
+/// tells how long to show it for.
+pub struct Recording {
+    path: PathBuf,
+    encoder: Encoder,
+    width: usize,
+    height: usize,
+    scale: usize,
+    cycles_per_second: u64,
+    /// Units frame lengths are given in; GIFs use hundredths of a second
+    ticks_per_second: u64,
+    start_cycle: u64,
+    latest: Option<(u64, Vec<Pixel>)>,
+    frames_written: usize,
+    /// The first error from the encoder, after which nothing more is written
+    error: Option<VideoError>,
+}
+
+impl Recording {
+    /// Starts a recording to `path`, as a GIF or an APNG depending on its
+    /// extension, with each pixel drawn as a `scale` by `scale` square
+    pub fn new(
+        path: PathBuf,
+        width: usize,
+        height: usize,
+        scale: usize,
+        start_cycle: u64,
+        cycles_per_second: u64,
+    ) -> Result<Self, VideoError> {
+        let extension = path
+            .extension()
+            .map(|extension| extension.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+
+        let (encoder, ticks_per_second) = match extension.as_str() {
+            "gif" => (Self::gif_encoder(&path, width, height, scale)?, 100),
+            "png" | "apng" => (Self::apng_encoder(&path, width, height, scale)?, 1000),
+            _ => return Err(VideoError::UnknownFormat(extension)),
+        };
+
+        Ok(Self {
+            path,
+            encoder,
+            width,
+            height,
+            scale,
+            cycles_per_second,
+            ticks_per_second,
+            start_cycle,
+            latest: None,
+            frames_written: 0,
+            error: None,
+        })
+    }
+
+    fn gif_encoder(
+        path: &Path,
+        width: usize,
+        height: usize,
+        scale: usize,
+    ) -> Result<Encoder, VideoError> {
+        let file = File::create(path).map_err(VideoError::Io)?;
+        let width = (width * scale) as u16;
+        let height = (height * scale) as u16;
+
+        let mut encoder =
+            gif::Encoder::new(BufWriter::new(file), width, height, &[]).map_err(VideoError::Gif)?;
+        encoder
+            .set_repeat(gif::Repeat::Infinite)
+            .map_err(VideoError::Gif)?;
+        Ok(Encoder::Gif(encoder))
+    }
+
+    fn apng_encoder(
+        path: &Path,
+        width: usize,
+        height: usize,
+        scale: usize,
+    ) -> Result<Encoder, VideoError> {
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)
+            .map_err(VideoError::Io)?;
+        let patch_file = file.try_clone().map_err(VideoError::Io)?;
+
+        let mut encoder = png::Encoder::new(
+            BufWriter::new(file),
+            (width * scale) as u32,
+            (height * scale) as u32,
+        );
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        // The real number of frames is filled in by `finish`
+        encoder.validate_sequence(false);
+        encoder.set_animated(u32::MAX, 0).map_err(VideoError::Png)?;
+
+        let writer = encoder.write_header().map_err(VideoError::Png)?;
+        Ok(Encoder::Apng {
+            writer,
+            file: patch_file,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn add_frame(&mut self, pixels: &[Pixel], cycle: u64) {
+        match &self.latest {
+            // The LCD is often redrawn without changing
+            Some((_, latest)) if latest.as_slice() == pixels => return,
+            // Time can't go backwards within a recording
+            Some((latest_cycle, _)) if cycle < *latest_cycle => return,
+            _ => {}
+        }
+
+        if let Some((latest_cycle, latest)) = self.latest.take() {
+            // Frames replaced within a tick are skipped
+            let ticks = self.tick(cycle) - self.tick(latest_cycle);
+            if ticks > 0 {
+                self.write_frame(&latest, ticks);
+            }
+        }
+        self.latest = Some((cycle.max(self.start_cycle), pixels.to_vec()));
+    }
+
+    /// Writes the latest frame, shown until `end_cycle`, and completes the file.
+    /// Returns the number of frames written.
+    pub fn finish(mut self, end_cycle: u64) -> Result<usize, VideoError> {
+        if let Some((latest_cycle, latest)) = self.latest.take() {
+            let ticks = self.tick(end_cycle.max(latest_cycle)) - self.tick(latest_cycle);
+            // Always have something to show
+            if ticks > 0 || self.frames_written == 0 {
+                self.write_frame(&latest, ticks.max(1));
+            }
+        }
+
+        if let Some(why) = self.error {
+            return Err(why);
+        }
+
+        match self.encoder {
+            Encoder::Gif(encoder) => {
+                encoder
+                    .into_inner()
+                    .and_then(|mut file| file.flush())
+                    .map_err(VideoError::Io)?;
+            }
+            Encoder::Apng { writer, mut file } => {
+                writer.finish().map_err(VideoError::Png)?;
+                set_apng_frame_count(&mut file, self.frames_written as u32)
+                    .map_err(VideoError::Io)?;
+            }
+        }
+
+        Ok(self.frames_written)
+    }
+
+    /// Converts a cycle to the time since the start of the recording, in ticks
+    fn tick(&self, cycle: u64) -> u64 {
+        (cycle.saturating_sub(self.start_cycle) as u128 * self.ticks_per_second as u128
+            / self.cycles_per_second as u128) as u64
+    }
+
+    fn write_frame(&mut self, pixels: &[Pixel], ticks: u64) {
+        if self.error.is_some() {
+            return;
+        }
+
+        let delay = ticks.min(u16::MAX.into()) as u16;
+        let data = scale_rgb(pixels, self.width, self.scale);
+
+        let result = match &mut self.encoder {
+            Encoder::Gif(encoder) => {
+                let width = (self.width * self.scale) as u16;
+                let height = (self.height * self.scale) as u16;
+                let mut frame = gif::Frame::from_rgb_speed(width, height, &data, 10);
+                frame.delay = delay;
+                encoder.write_frame(&frame).map_err(VideoError::Gif)
+            }
+            Encoder::Apng { writer, .. } => writer
+                .set_frame_delay(delay, self.ticks_per_second as u16)
+                .and_then(|_| writer.write_image_data(&data))
+                .map_err(VideoError::Png),
+        };
+
+        match result {
+            Ok(_) => self.frames_written += 1,
+            Err(why) => self.error = Some(why),
+        }
+    }
+}
+
+/// Fills in the frame count of the `acTL` chunk of a finished APNG
+fn set_apng_frame_count(file: &mut File, frames: u32) -> std::io::Result<()> {
+    // Skip the signature, then look through the chunks before the image data
+    let mut position = 8;
+    loop {
+        let mut header = [0u8; 8];
+        file.seek(SeekFrom::Start(position))?;
+        file.read_exact(&mut header)?;
+        let length = u32::from_be_bytes(header[..4].try_into().unwrap()) as u64;
+
+        match &header[4..] {
+            b"acTL" => break,
+            b"IDAT" | b"fcTL" | b"IEND" => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "The APNG has no animation control chunk",
+                ));
+            }
+            _ => position += 12 + length,
+        }
+    }
+
+    // The frame count is followed by the play count and the chunk's CRC
+    let mut chunk = *b"acTL\0\0\0\0\0\0\0\0";
+    file.read_exact(&mut chunk[4..])?;
+    chunk[4..8].copy_from_slice(&frames.to_be_bytes());
+
+    file.seek(SeekFrom::Start(position + 4))?;
+    file.write_all(&chunk)?;
+    file.write_all(&crc32(&chunk).to_be_bytes())?;
+    file.flush()
+}
+
+/// The CRC-32 which PNG chunks end with
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in bytes {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+#[derive(Debug)]
+pub enum VideoError {
+    UnknownFormat(String),
+    Io(std::io::Error),
+    Gif(gif::EncodingError),
+    Png(png::EncodingError),
+}
+
+impl Display for VideoError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&match &self {
+            VideoError::UnknownFormat(extension) => {
+                format!("Cannot record to a \".{extension}\" file; use .gif, .png or .apng")
+            }
+            VideoError::Io(why) => format!("Could not write the file: {why}"),
+            VideoError::Gif(why) => format!("Could not encode the GIF: {why}"),
+            VideoError::Png(why) => format!("Could not encode the APNG: {why}"),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CYCLES_PER_SECOND: u64 = 8_000_000;
+
+    fn frame(shade: u8) -> Vec<Pixel> {
+        vec![
+            Pixel {
+                red: shade,
+                green: shade,
+                blue: shade,
+            };
+            4
+        ]
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("emiu2-{}-{name}", std::process::id()))
+    }
+
+    #[test]
+    fn gif_frames_are_shown_until_the_next_one() {
+        let path = temp_path("delays.gif");
+        let second = CYCLES_PER_SECOND;
+        let mut recording = Recording::new(path.clone(), 2, 2, 1, 5 * second, second).unwrap();
+
+        recording.add_frame(&frame(0), 5 * second);
+        recording.add_frame(&frame(0), 5 * second + second / 2);
+        recording.add_frame(&frame(1), 6 * second);
+        // From before a state was loaded
+        recording.add_frame(&frame(2), 3 * second);
+        recording.add_frame(&frame(3), 6 * second + second / 4);
+        assert_eq!(recording.finish(7 * second).unwrap(), 3);
+
+        let mut decoder = gif::DecodeOptions::new()
+            .read_info(File::open(&path).unwrap())
+            .unwrap();
+        let mut delays = Vec::new();
+        while let Some(frame) = decoder.read_next_frame().unwrap() {
+            delays.push(frame.delay);
+        }
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(delays, [100, 25, 75]);
+    }
+
+    #[test]
+    fn apng_frame_count_is_filled_in() {
+        let path = temp_path("count.png");
+        let second = CYCLES_PER_SECOND;
+        let mut recording = Recording::new(path.clone(), 2, 2, 2, 0, second).unwrap();
+
+        for shade in 0..5 {
+            recording.add_frame(&frame(shade), shade as u64 * second);
+        }
+        assert_eq!(recording.finish(5 * second).unwrap(), 5);
+
+        let decoder = png::Decoder::new(File::open(&path).unwrap());
+        let mut reader = decoder.read_info().unwrap();
+        let control = reader.info().animation_control.unwrap();
+        let mut buffer = vec![0; reader.output_buffer_size()];
+        let mut frames = 0;
+        while reader.next_frame(&mut buffer).is_ok() {
+            frames += 1;
+        }
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(control.num_frames, 5);
+        assert_eq!(frames, 5);
+    }
+}
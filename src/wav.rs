@@ -0,0 +1,56 @@
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+
+use crate::audio::{AudioInterface, SampleClock};
+
+/// Writes the mix to a WAV file.
+///
+/// The recorder is sampled on its own, apart from the audio output, so the
+/// file is the same whatever the output's rate. The file is finished when the
+/// recorder is dropped.
+pub struct WavRecorder {
+    clock: SampleClock,
+    writer: hound::WavWriter<BufWriter<File>>,
+}
+
+impl WavRecorder {
+    pub fn new(path: &Path, sample_rate: u32) -> Result<Self, hound::Error> {
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate,
+            bits_per_sample: 32,
+            sample_format: hound::SampleFormat::Float,
+        };
+
+        Ok(Self {
+            clock: SampleClock::new(sample_rate),
+            writer: hound::WavWriter::create(path, spec)?,
+        })
+    }
+}
+
+impl AudioInterface for WavRecorder {
+    fn set_clock_rate(&mut self, emulated_clock_rate: u64) {
+        self.clock.set_clock_rate(emulated_clock_rate);
+    }
+
+    fn needs_sample(&self, current_cycle: u64) -> bool {
+        self.clock.needs_sample(current_cycle)
+    }
+
+    fn next_sample_cycle(&self) -> u64 {
+        self.clock.next_sample_cycle()
+    }
+
+    fn add_sample(&mut self, value: f32, _current_cycle: u64) {
+        if let Err(why) = self.writer.write_sample(value) {
+            eprintln!("Failed to record audio: {why}");
+        }
+        self.clock.advance();
+    }
+
+    fn reset_clock(&mut self, current_cycle: u64) {
+        self.clock.reset(current_cycle);
+    }
+}